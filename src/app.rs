@@ -1,4 +1,6 @@
 use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Local};
 use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
 use futures::{
     stream::{FusedStream, Stream},
@@ -6,23 +8,43 @@ use futures::{
     StreamExt,
 };
 use futures_lite::StreamExt as LiteStreamExt;
+use std::borrow::Cow;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::task::Context as TaskContext;
-use tokio::select;
+use std::time::{Duration, Instant};
+use tokio::{
+    select,
+    signal::unix::{signal, Signal, SignalKind},
+    time::{interval, timeout, Interval, MissedTickBehavior},
+};
 use tor_client_lib::{control_connection::OnionServiceListener, TorServiceId};
 use voynich::{
+    chat::ChatMessage,
     engine::{ConnectionDirection, Engine, NetworkEvent},
-    logger::{Logger, StandardLogger},
+    logger::{Level, Logger, StandardLogger},
 };
+use zeroize::Zeroize;
 
 use crate::{
-    app_context::{AppContext, ConnectionContext},
+    app_context::{AppContext, ConnectionContext, FileOfferContext, RoomInviteContext},
+    chat::{format_duration, ChatId, MessageState},
+    client_auth::AuthorizedClients,
+    config::TermConfig,
+    contacts::Contacts,
     input::{
         allow_connection_input::AllowConnectionInput, chat_input::ChatInput,
-        command_input::CommandInput,
+        command_input::CommandInput, file_offer_input::FileOfferInput, lock_input::LockInput,
+        room_invite_input::RoomInviteInput,
     },
+    outbox::Outbox,
+    protocol::{self, Payload, ReceiptKind, PROTOCOL_VERSION},
+    room::Member,
     root::Root,
+    storage,
     term::Term,
+    tor_control::TorControl,
+    transfer::{format_size, TransferId, TransferStatus},
 };
 
 #[derive(Debug)]
@@ -52,6 +74,24 @@ impl FusedStream for TermInputStream {
     }
 }
 
+/// How long we wait for each peer to be disconnected on shutdown
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait for the control connection to remove our onion service on shutdown
+const DELETE_ONION_SERVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest panic mode waits on each step of cleaning up
+const PANIC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often we wake up to run timers, such as reconnecting to dropped chats
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many chunks of each file we're sending go out each round
+const CHUNKS_PER_ROUND: usize = 4;
+
+/// How often a round of file chunks goes out, while there are files to send
+const CHUNK_INTERVAL: Duration = Duration::from_millis(50);
+
 pub trait InputHandler {
     async fn handle_input_event(
         &mut self,
@@ -70,27 +110,56 @@ pub struct App {
     chat_input: ChatInput,
     command_input: CommandInput,
     allow_connection_input: AllowConnectionInput,
+    file_offer_input: FileOfferInput,
+    room_invite_input: RoomInviteInput,
+    lock_input: LockInput,
+    sigterm: Signal,
+    sighup: Signal,
+    tick: Interval,
+    chunk_tick: Interval,
+    /// Whether the outbox and contacts loaded, so saving them on the way out can't
+    /// overwrite files we couldn't read
+    files_loaded: bool,
 }
 
 impl App {
-    fn new(id: TorServiceId, onion_service_address: String) -> Result<Self> {
+    fn new(id: TorServiceId, onion_service_address: String, config: TermConfig) -> Result<Self> {
+        let mut tick = interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut chunk_tick = interval(CHUNK_INTERVAL);
+        chunk_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
         Ok(Self {
             term: Term::start()?,
             input_stream: TermInputStream::new(),
-            context: AppContext::new(id, onion_service_address),
+            context: AppContext::new(id, onion_service_address, config),
             chat_input: ChatInput::new(),
             command_input: CommandInput::new(),
             allow_connection_input: AllowConnectionInput::new(),
+            file_offer_input: FileOfferInput::new(),
+            room_invite_input: RoomInviteInput::new(),
+            lock_input: LockInput::new(),
+            sigterm: signal(SignalKind::terminate()).context("install SIGTERM handler")?,
+            sighup: signal(SignalKind::hangup()).context("install SIGHUP handler")?,
+            tick,
+            chunk_tick,
+            files_loaded: true,
         })
     }
 
+    /// Run the app until the user quits or we get a SIGTERM or SIGHUP.
+    ///
+    /// If `transient_service` is set, the onion service is removed from Tor on the
+    /// way out, using the control connection.
     pub async fn run(
         engine: &mut Engine,
         listener: &OnionServiceListener,
+        control_connection: &mut TorControl,
+        transient_service: bool,
+        config: TermConfig,
         logger: &mut StandardLogger,
     ) -> Result<()> {
         install_panic_hook();
-        let mut app = Self::new(engine.id(), engine.onion_service_address())?;
+        let mut app = Self::new(engine.id(), engine.onion_service_address(), config)?;
 
         logger.log_info(&format!(
             "Onion service {} in service",
@@ -99,19 +168,155 @@ impl App {
 
         logger.log_info("NOTE: To bring up the help screen, type ctrl-h");
 
-        while !app.context.should_quit {
-            app.draw(logger)?;
-            app.handle_events(engine, listener, logger).await?;
+        app.restore_outbox(logger);
+        app.load_contacts(logger);
+        app.load_authorized_clients(logger);
+
+        let mut result = Ok(());
+        while !app.context.should_quit && !app.context.panicking {
+            if let Err(error) = app.draw(logger) {
+                result = Err(error);
+                break;
+            }
+            app.add_client_auth_keys(control_connection, logger).await;
+            if let Some(address) = app.context.start_next_connect() {
+                app.connect(address, engine, logger).await;
+            }
+            if let Err(error) = app.handle_events(engine, listener, logger).await {
+                result = Err(error);
+                break;
+            }
+            app.say_hello(engine, logger).await;
+            app.announce_rooms(engine, logger).await;
+            app.deliver_queued_messages(engine, logger).await;
+            app.send_read_receipts(engine, logger).await;
+        }
+
+        if app.context.panicking {
+            app.panic(engine, control_connection, transient_service, logger)
+                .await;
+            return Ok(());
         }
+
+        let shutdown_started = Local::now();
+        app.shutdown(engine, control_connection, transient_service, logger)
+            .await;
+        app.wipe();
         Term::stop()?;
-        Ok(())
+        flush_log(logger, shutdown_started);
+        result
+    }
+
+    /// Wipe everything and get out as fast as we can. Messages, typed text, the system
+    /// messages and file transfers, with their partly downloaded files, go first, and the
+    /// terminal is cleared; then peers are disconnected without waiting long on any of
+    /// them. Depending on the config, our onion service and saved files go too.
+    async fn panic(
+        &mut self,
+        engine: &mut Engine,
+        control_connection: &mut TorControl,
+        transient_service: bool,
+        logger: &mut StandardLogger,
+    ) {
+        self.wipe();
+        *logger = StandardLogger::new(500);
+        self.context.transfers.discard();
+        let _ = Term::wipe();
+
+        for id in self.context.connected_peers() {
+            let _ = timeout(PANIC_TIMEOUT, engine.disconnect(&id, logger)).await;
+        }
+        if transient_service || self.context.config.panic_delete_service {
+            let _ = timeout(
+                PANIC_TIMEOUT,
+                control_connection.delete_onion(engine.id().as_str()),
+            )
+            .await;
+        }
+        if self.context.config.panic_delete_files {
+            let _ = storage::delete_files();
+        }
+    }
+
+    /// Zero the chats and everything that's been typed
+    fn wipe(&mut self) {
+        self.context.wipe();
+        self.chat_input.wipe();
+        self.command_input.wipe();
+        self.lock_input.wipe();
+    }
+
+    /// Shut down in an orderly fashion: save the outbox and contacts, disconnect from
+    /// every peer we're chatting with, giving the engine a chance to flush any messages
+    /// it has queued, and remove our onion service if it's transient
+    async fn shutdown(
+        &mut self,
+        engine: &mut Engine,
+        control_connection: &mut TorControl,
+        transient_service: bool,
+        logger: &mut StandardLogger,
+    ) {
+        logger.log_info("Shutting down");
+        let _ = self.draw(logger);
+
+        if self.files_loaded {
+            if let Err(error) = self.context.outbox.save() {
+                logger.log_error(&format!("Error saving queued messages: {}", error));
+            }
+            if let Err(error) = self.context.contacts.save() {
+                logger.log_error(&format!("Error saving contacts: {}", error));
+            }
+        }
+
+        for id in self.context.chat_list.peers() {
+            if !self.context.is_connected(&id) {
+                self.context.remove_chat(&id);
+                continue;
+            }
+            match timeout(DISCONNECT_TIMEOUT, engine.disconnect(&id, logger)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => {
+                    logger.log_error(&format!("Error disconnecting from {}: {}", id, error));
+                }
+                Err(_) => {
+                    logger.log_error(&format!("Timed out disconnecting from {}", id));
+                }
+            }
+            self.context.remove_chat(&id);
+        }
+
+        if transient_service {
+            let service_id = engine.id();
+            match timeout(
+                DELETE_ONION_SERVICE_TIMEOUT,
+                control_connection.delete_onion(service_id.as_str()),
+            )
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => {
+                    logger.log_error(&format!(
+                        "Error removing onion service {}: {}",
+                        service_id, error
+                    ));
+                }
+                Err(_) => {
+                    logger.log_error(&format!("Timed out removing onion service {}", service_id));
+                }
+            }
+        }
     }
 
     fn draw(&mut self, logger: &mut StandardLogger) -> Result<()> {
         self.term
             .draw(|frame| {
-                let mut root =
-                    Root::new(&self.context, logger, &self.command_input, &self.chat_input);
+                let mut root = Root::new(
+                    &self.context,
+                    logger,
+                    &self.command_input,
+                    &self.chat_input,
+                    &self.lock_input,
+                );
                 if let Some((x, y)) = root.get_cursor_location(frame.size()) {
                     frame.set_cursor(x, y);
                 }
@@ -144,22 +349,47 @@ impl App {
                 match result {
                     Ok(Some(NetworkEvent::NewConnection(connection))) => {
                         if *connection.direction() == ConnectionDirection::Incoming {
-                            self.context.connection_context = Some(ConnectionContext::new(&connection.id()));
+                            self.handle_incoming_connection(&connection.id(), engine, logger).await;
+                        } else if self.context.take_abandoned_connect(&connection.id()) {
+                            logger.log_info(&format!(
+                                "Dropping the connection to {}, which was given up on",
+                                connection.id()
+                            ));
+                            let _ = engine.disconnect(&connection.id(), logger).await;
                         } else {
+                            self.context.connect_made(&connection.id());
                             self.context.add_new_chat(&connection.id());
+                            if let Err(error) = self.context.contacts.record_connection(&connection.id()) {
+                                logger.log_error(&format!("Error saving contacts: {}", error));
+                            }
                         }
                         Ok(())
                     }
                     Ok(Some(NetworkEvent::Message(chat_message))) => {
-                        if let Some(chat) = self.context.chats.get_mut(&chat_message.sender) {
-                            chat.add_message(*chat_message);
-                        }
+                        self.handle_chat_message(*chat_message, engine, logger).await;
                         Ok(())
                     },
                     Ok(Some(NetworkEvent::ConnectionClosed(connection))) => {
-                        self.context.chat_list.remove(&connection.id());
-                        self.context.chats.remove(&connection.id());
-                        self.context.remove_id(&connection.id());
+                        let id = connection.id();
+                        // Whoever connects with this ID next may be using another client
+                        self.context.peer_protocols.remove(&id);
+                        if self.context.chats.contains_key(&id) {
+                            let interrupted = self.context.interrupt_transfers(&id);
+                            if interrupted > 0 {
+                                logger.log_error(&format!(
+                                    "{} file transfer(s) with {} interrupted",
+                                    interrupted, id
+                                ));
+                            }
+                            match self.context.set_disconnected(&id) {
+                                Some(delay) => logger.log_info(&format!(
+                                    "Connection to {} lost, reconnecting in {}s",
+                                    id,
+                                    delay.as_secs()
+                                )),
+                                None => logger.log_info(&format!("Connection to {} lost", id)),
+                            }
+                        }
                         Ok(())
                     }
                     Ok(None) => Ok(()),
@@ -177,6 +407,820 @@ impl App {
                 }
                 Ok(())
             }
+            _ = self.tick.tick() => {
+                self.time_out_connect(logger);
+                self.reconnect_chats(logger);
+                self.summarize_rejected_connections(logger);
+                self.lock_when_idle();
+                self.purge_expired_messages(logger)
+            }
+            _ = self.chunk_tick.tick(), if self.context.transfers.is_sending() => {
+                self.send_file_chunks(engine, logger).await;
+                Ok(())
+            }
+            _ = self.sigterm.recv() => {
+                logger.log_info("Received SIGTERM");
+                self.context.should_quit = true;
+                Ok(())
+            }
+            _ = self.sighup.recv() => {
+                logger.log_info("Received SIGHUP");
+                self.context.should_quit = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Load what we know about the people we've chatted with
+    fn load_contacts(&mut self, logger: &mut StandardLogger) {
+        match Contacts::load() {
+            Ok(contacts) => self.context.contacts = contacts,
+            Err(error) => {
+                logger.log_error(&format!("Error loading contacts: {}", error));
+                self.files_loaded = false;
+            }
+        }
+    }
+
+    fn load_authorized_clients(&mut self, logger: &mut StandardLogger) {
+        match AuthorizedClients::load() {
+            Ok(clients) => self.context.authorized_clients = clients,
+            Err(error) => logger.log_error(&format!("Error loading authorized clients: {}", error)),
+        }
+    }
+
+    /// Give Tor the keys for any protected onion services we're about to connect to
+    async fn add_client_auth_keys(
+        &mut self,
+        control_connection: &mut TorControl,
+        logger: &mut StandardLogger,
+    ) {
+        for (service_id, key) in self.context.client_auth_keys.drain(..) {
+            if let Err(error) = control_connection.add_client_auth(&service_id, &key).await {
+                logger.log_error(&format!(
+                    "Error adding client authorization for {}: {}",
+                    service_id, error
+                ));
+            }
+        }
+    }
+
+    /// Load the messages we didn't get to send last time, and open chats for them
+    fn restore_outbox(&mut self, logger: &mut StandardLogger) {
+        self.context.outbox = match Outbox::load() {
+            Ok(outbox) => outbox,
+            Err(error) => {
+                logger.log_error(&format!("Error loading queued messages: {}", error));
+                self.files_loaded = false;
+                return;
+            }
+        };
+
+        let mut restored = Vec::new();
+        for (service_id, peer) in self.context.outbox.iter() {
+            match TorServiceId::from_str(service_id) {
+                Ok(id) => restored.push((id, peer.address.clone(), peer.messages.clone())),
+                Err(error) => logger.log_error(&format!(
+                    "Ignoring queued messages for '{}': {}",
+                    service_id, error
+                )),
+            }
+        }
+
+        for (id, address, messages) in restored {
+            self.context.open_chat(&id, address);
+            if let Some(chat) = self.context.chats.get_mut(&id) {
+                for queued in messages.iter() {
+                    let mut message = ChatMessage::new(&self.context.id, &id, queued.text.clone());
+                    message.date = queued.date.into();
+                    chat.add_queued(message, queued.id, queued.expires).reply_to = queued.reply_to;
+                }
+            }
+            logger.log_info(&format!(
+                "{} queued message(s) waiting for {}",
+                messages.len(),
+                id
+            ));
+            if self.context.config.auto_reconnect {
+                self.context.schedule_reconnect(&id);
+            }
+        }
+    }
+
+    /// Send queued messages to any peers we're now connected to
+    async fn deliver_queued_messages(&mut self, engine: &mut Engine, logger: &mut StandardLogger) {
+        if self.context.outbox.is_empty() {
+            return;
+        }
+        let ready = self
+            .context
+            .chat_states
+            .keys()
+            .filter(|id| self.context.ready_to_send(id) && self.context.outbox.has_messages(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in ready {
+            let messages = self.context.outbox.messages(&id).to_vec();
+            let mut delivered = Vec::new();
+            for queued in messages {
+                // A disappearing message lasts for however long it had left
+                let payload = Payload::Text {
+                    id: queued.id,
+                    text: Cow::Borrowed(&queued.text),
+                    ttl: queued
+                        .expires
+                        .map(|expires| (expires - Local::now()).num_seconds().max(1) as u64),
+                    reply_to: queued.reply_to,
+                };
+                if let Err(error) =
+                    protocol::send(engine, &self.context, &id, &payload, logger).await
+                {
+                    logger.log_error(&format!(
+                        "Error sending queued message to {}: {}",
+                        id, error
+                    ));
+                    break;
+                }
+                delivered.push(queued.id);
+            }
+
+            if !delivered.is_empty() {
+                self.context.outbox.remove_delivered(&id, delivered.len());
+                if let Some(chat) = self.context.chats.get_mut(&id) {
+                    chat.update_state(&delivered, MessageState::Sent);
+                }
+                if let Err(error) = self.context.outbox.save() {
+                    logger.log_error(&format!("Error saving queued messages: {}", error));
+                }
+                logger.log_info(&format!(
+                    "Delivered {} queued message(s) to {}",
+                    delivered.len(),
+                    id
+                ));
+            }
+        }
+    }
+
+    /// Handle a chat message from a peer, which is either plain text or a voynich-term
+    /// payload
+    async fn handle_chat_message(
+        &mut self,
+        mut message: ChatMessage,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let sender = message.sender.clone();
+        let payload = match Payload::decode(&message.message) {
+            Ok(payload) => payload,
+            Err(error) => {
+                logger.log_error(&format!("Bad message from {}: {}", sender, error));
+                return;
+            }
+        };
+        if payload.is_some() {
+            // Whatever the payload holds has been decoded out of the text
+            message.message.zeroize();
+        }
+        // A hello can arrive before the chat's opened, while the user decides whether to
+        // allow the connection
+        if let Some(Payload::Hello { version }) = payload {
+            self.context.hello_from(&sender, version);
+            return;
+        }
+        match &payload {
+            None | Some(Payload::Text { .. } | Payload::RoomText { .. }) => {
+                self.context
+                    .notify_locked(format!("message from {}", sender));
+            }
+            Some(Payload::FileOffer { .. }) => {
+                self.context
+                    .notify_locked(format!("file offer from {}", sender));
+            }
+            _ => {}
+        }
+        let chat = match self.context.chats.get_mut(&sender) {
+            Some(chat) => chat,
+            None => return,
+        };
+
+        match payload {
+            None => {
+                chat.add_message(message, None, None);
+            }
+            Some(Payload::Text {
+                id,
+                text,
+                ttl,
+                reply_to,
+            }) => {
+                message.message = text.into_owned();
+                chat.add_message(message, Some(id), ttl.map(Duration::from_secs))
+                    .reply_to = reply_to;
+                self.context.set_typing(&sender, false);
+                if let Err(error) = self.context.contacts.record_chatted(&sender) {
+                    logger.log_error(&format!("Error saving contacts: {}", error));
+                }
+                let receipt = Payload::Receipt {
+                    kind: ReceiptKind::Delivered,
+                    ids: vec![id],
+                };
+                if let Err(error) =
+                    protocol::send(engine, &self.context, &sender, &receipt, logger).await
+                {
+                    logger.log_error(&format!(
+                        "Error sending delivery receipt to {}: {}",
+                        sender, error
+                    ));
+                }
+            }
+            Some(Payload::Receipt { kind, ids }) => {
+                let state = match kind {
+                    ReceiptKind::Delivered => MessageState::Delivered,
+                    ReceiptKind::Read => MessageState::Read,
+                };
+                chat.update_state(&ids, state);
+            }
+            Some(Payload::Typing) => self.context.set_typing(&sender, true),
+            Some(Payload::Ttl { seconds, room }) => {
+                let ttl = seconds.map(Duration::from_secs);
+                let chat = match room {
+                    None => Some(chat),
+                    Some(room) => self
+                        .context
+                        .rooms
+                        .get_mut(&room)
+                        .filter(|room| room.is_member(&sender))
+                        .map(|room| &mut room.chat),
+                };
+                if let Some(chat) = chat {
+                    chat.set_ttl(ttl);
+                    match ttl {
+                        Some(ttl) => logger.log_info(&format!(
+                            "{} set messages to disappear after {}",
+                            sender,
+                            format_duration(ttl)
+                        )),
+                        None => {
+                            logger.log_info(&format!("{} turned off disappearing messages", sender))
+                        }
+                    }
+                    if room.is_none() {
+                        let ack = Payload::TtlAck { seconds };
+                        if let Err(error) =
+                            protocol::send(engine, &self.context, &sender, &ack, logger).await
+                        {
+                            logger.log_error(&format!(
+                                "Error answering {}'s timer: {}",
+                                sender, error
+                            ));
+                        }
+                    }
+                }
+            }
+            Some(Payload::TtlAck { seconds }) => {
+                if chat.ack_ttl(seconds.map(Duration::from_secs)) {
+                    logger.log_info(&format!("{} changed their timer too", sender));
+                }
+            }
+            Some(Payload::Edit { id, text, room }) => {
+                let chat = match room {
+                    None => Some(chat),
+                    Some(room) => self
+                        .context
+                        .rooms
+                        .get_mut(&room)
+                        .filter(|room| room.is_member(&sender))
+                        .map(|room| &mut room.chat),
+                };
+                if !chat.is_some_and(|chat| chat.edit(&sender, id, text.into_owned())) {
+                    logger.log_debug(&format!("{} edited a message we don't have", sender));
+                }
+            }
+            Some(Payload::Delete { id, room }) => {
+                let chat = match room {
+                    None => Some(chat),
+                    Some(room) => self
+                        .context
+                        .rooms
+                        .get_mut(&room)
+                        .filter(|room| room.is_member(&sender))
+                        .map(|room| &mut room.chat),
+                };
+                if !chat.is_some_and(|chat| chat.delete(&sender, id)) {
+                    logger.log_debug(&format!("{} deleted a message we don't have", sender));
+                }
+            }
+            // Handled before the chat's looked up
+            Some(Payload::Hello { .. }) => {}
+            Some(
+                payload @ (Payload::FileOffer { .. }
+                | Payload::FileAccept { .. }
+                | Payload::FileReject { .. }
+                | Payload::FileChunk { .. }
+                | Payload::FileDone { .. }),
+            ) => {
+                self.handle_file_payload(&sender, payload, engine, logger)
+                    .await
+            }
+            Some(
+                payload @ (Payload::RoomInvite { .. }
+                | Payload::RoomInvited { .. }
+                | Payload::RoomJoin { .. }
+                | Payload::RoomLeave { .. }
+                | Payload::RoomText { .. }),
+            ) => self.handle_room_payload(&sender, payload, logger),
+        }
+    }
+
+    /// Handle a room invitation, membership change or message from a peer
+    fn handle_room_payload(
+        &mut self,
+        sender: &TorServiceId,
+        payload: Payload<'static>,
+        logger: &mut StandardLogger,
+    ) {
+        match payload {
+            Payload::RoomInvite {
+                room,
+                name,
+                members,
+            } => {
+                if self.context.rooms.contains_key(&room) {
+                    logger.log_debug(&format!("{} invited us to a room we're in", sender));
+                    return;
+                }
+                let mut joined = Vec::new();
+                for member in members.iter() {
+                    match Member::try_from(member) {
+                        Ok(member) => joined.push(member),
+                        Err(error) => logger.log_error(&error.to_string()),
+                    }
+                }
+                if !joined.iter().any(|member| member.id == *sender) {
+                    joined.push(Member {
+                        id: sender.clone(),
+                        address: None,
+                    });
+                }
+                self.context
+                    .notify_locked(format!("room invitation from {}", sender));
+                self.context.room_invites.push_back(RoomInviteContext::new(
+                    room,
+                    name,
+                    sender.clone(),
+                    joined,
+                ));
+            }
+            Payload::RoomInvited { room, id } => match self.context.rooms.get_mut(&room) {
+                Some(room) if room.is_member(sender) => match TorServiceId::from_str(&id) {
+                    Ok(id) => {
+                        if room.invite(&id) {
+                            logger.log_info(&format!("{} joined '{}'", id, room.name));
+                            self.context.add_member_color(id);
+                        }
+                    }
+                    Err(error) => logger.log_error(&format!(
+                        "{} invited a bad address '{}': {}",
+                        sender, id, error
+                    )),
+                },
+                _ => logger.log_debug(&format!(
+                    "Ignoring invitation from {} to a room they're not in",
+                    sender
+                )),
+            },
+            Payload::RoomJoin { room, address } => match self.context.rooms.get_mut(&room) {
+                Some(room) => {
+                    if room.join(sender, address) {
+                        logger.log_info(&format!("{} joined '{}'", sender, room.name));
+                        self.context.add_member_color(sender.clone());
+                    } else if !room.is_member(sender) {
+                        logger.log_debug(&format!(
+                            "{} asked to join '{}', but no member has invited them",
+                            sender, room.name
+                        ));
+                    }
+                }
+                None => logger.log_debug(&format!("{} joined a room we're not in", sender)),
+            },
+            Payload::RoomLeave { room } => {
+                if let Some(room) = self.context.rooms.get_mut(&room) {
+                    if room.remove_member(sender) {
+                        logger.log_info(&format!("{} left '{}'", sender, room.name));
+                    }
+                }
+            }
+            Payload::RoomText {
+                room,
+                id,
+                text,
+                ttl,
+                reply_to,
+            } => match self.context.rooms.get_mut(&room) {
+                Some(room) if room.is_member(sender) => {
+                    let message = ChatMessage::new(sender, &self.context.id, text.into_owned());
+                    room.chat
+                        .add_message(message, Some(id), ttl.map(Duration::from_secs))
+                        .reply_to = reply_to;
+                }
+                _ => logger.log_debug(&format!(
+                    "Ignoring message from {} for a room they're not in",
+                    sender
+                )),
+            },
+            _ => {}
+        }
+    }
+
+    /// Tell the peers we've just connected to which of our rooms they're in, so they
+    /// add us to their member lists
+    async fn announce_rooms(&mut self, engine: &mut Engine, logger: &mut StandardLogger) {
+        for peer in std::mem::take(&mut self.context.room_announcements) {
+            let rooms = self
+                .context
+                .rooms
+                .iter()
+                .filter(|(_, room)| room.is_member(&peer))
+                .map(|(room_id, _)| *room_id)
+                .collect::<Vec<_>>();
+            for room in rooms {
+                let payload = Payload::RoomJoin {
+                    room,
+                    address: Some(self.context.onion_service_address.clone()),
+                };
+                if let Err(error) =
+                    protocol::send(engine, &self.context, &peer, &payload, logger).await
+                {
+                    logger.log_error(&format!(
+                        "Error telling {} about our rooms: {}",
+                        peer, error
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Handle a step in a file transfer with a peer
+    async fn handle_file_payload(
+        &mut self,
+        sender: &TorServiceId,
+        payload: Payload<'static>,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let reply = match payload {
+            Payload::FileOffer {
+                transfer_id,
+                name,
+                size,
+                hash,
+            } => match self.context.transfers.receive_offer(
+                sender,
+                transfer_id,
+                &name,
+                size,
+                &hash,
+                &self.context.config,
+            ) {
+                Ok(()) => {
+                    let text = format!("[file] {} ({})", name, format_size(size));
+                    let message = ChatMessage::new(sender, &self.context.id, text);
+                    if let Some(chat) = self.context.chats.get_mut(sender) {
+                        chat.add_transfer(message, transfer_id, MessageState::Received);
+                    }
+                    self.context
+                        .file_offers
+                        .push_back(FileOfferContext::new(transfer_id));
+                    None
+                }
+                Err(error) => {
+                    logger.log_error(&format!(
+                        "Turned down file '{}' from {}: {}",
+                        name, sender, error
+                    ));
+                    Some(Payload::FileReject {
+                        transfer_id,
+                        reason: Some(error.to_string()),
+                    })
+                }
+            },
+            Payload::FileAccept {
+                transfer_id,
+                offset,
+            } => {
+                if let Err(error) =
+                    self.context
+                        .transfers
+                        .start_sending(sender, transfer_id, offset)
+                {
+                    logger.log_error(&format!("Can't send file to {}: {}", sender, error));
+                    self.context.transfers.fail(transfer_id);
+                } else if offset > 0 {
+                    logger.log_info(&format!(
+                        "{} already has {} of the file; sending the rest",
+                        sender,
+                        format_size(offset)
+                    ));
+                }
+                None
+            }
+            Payload::FileReject {
+                transfer_id,
+                reason,
+            } => {
+                self.context
+                    .transfers
+                    .set_status(sender, transfer_id, TransferStatus::Rejected);
+                match reason {
+                    Some(reason) => {
+                        logger.log_error(&format!("{} couldn't take the file: {}", sender, reason))
+                    }
+                    None => logger.log_info(&format!("{} declined the file", sender)),
+                }
+                None
+            }
+            Payload::FileChunk {
+                transfer_id,
+                offset,
+                data,
+            } => self.receive_file_chunk(sender, transfer_id, offset, &data, logger),
+            Payload::FileDone { transfer_id, ok } => {
+                let status = if ok {
+                    TransferStatus::Complete
+                } else {
+                    logger.log_error(&format!("{} didn't get the file intact", sender));
+                    TransferStatus::Failed
+                };
+                self.context
+                    .transfers
+                    .set_status(sender, transfer_id, status);
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(reply) = reply {
+            if let Err(error) = protocol::send(engine, &self.context, sender, &reply, logger).await
+            {
+                logger.log_error(&format!(
+                    "Error sending file transfer reply to {}: {}",
+                    sender, error
+                ));
+            }
+        }
+    }
+
+    /// Write a chunk of a file we're receiving. Once the whole file is in, it's checked
+    /// and saved, and the reply to tell the sender how that went is returned.
+    fn receive_file_chunk(
+        &mut self,
+        sender: &TorServiceId,
+        transfer_id: TransferId,
+        offset: u64,
+        data: &str,
+        logger: &mut StandardLogger,
+    ) -> Option<Payload<'static>> {
+        let result = BASE64
+            .decode(data)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| {
+                self.context
+                    .transfers
+                    .receive_chunk(sender, transfer_id, offset, &data)
+            })
+            .and_then(|complete| {
+                complete
+                    .then(|| self.context.transfers.finish(transfer_id))
+                    .transpose()
+            });
+        match result {
+            Ok(None) => None,
+            Ok(Some(path)) => {
+                logger.log_info(&format!("Saved file from {} to {}", sender, path.display()));
+                Some(Payload::FileDone {
+                    transfer_id,
+                    ok: true,
+                })
+            }
+            Err(error) => {
+                logger.log_error(&format!("Error receiving file from {}: {}", sender, error));
+                self.context.transfers.fail(transfer_id);
+                Some(Payload::FileDone {
+                    transfer_id,
+                    ok: false,
+                })
+            }
+        }
+    }
+
+    /// Send the next few chunks of each file we're sending. This happens a bit at a
+    /// time, between other events, so a big file doesn't hold everything else up.
+    async fn send_file_chunks(&mut self, engine: &mut Engine, logger: &mut StandardLogger) {
+        for transfer_id in self.context.transfers.sending() {
+            for _ in 0..CHUNKS_PER_ROUND {
+                let (peer, offset, data) = match self.context.transfers.next_chunk(transfer_id) {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(error) => {
+                        logger.log_error(&format!("Error sending file: {}", error));
+                        self.context.transfers.fail(transfer_id);
+                        break;
+                    }
+                };
+                let payload = Payload::FileChunk {
+                    transfer_id,
+                    offset,
+                    data: BASE64.encode(data),
+                };
+                if let Err(error) =
+                    protocol::send(engine, &self.context, &peer, &payload, logger).await
+                {
+                    logger.log_error(&format!("Error sending file to {}: {}", peer, error));
+                    self.context.transfers.set_status(
+                        &peer,
+                        transfer_id,
+                        TransferStatus::Interrupted,
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Tell peers we've just connected to that we speak the protocol
+    async fn say_hello(&mut self, engine: &mut Engine, logger: &mut StandardLogger) {
+        let hello = Payload::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        for peer in std::mem::take(&mut self.context.pending_hellos) {
+            if let Err(error) = protocol::send(engine, &self.context, &peer, &hello, logger).await {
+                logger.log_error(&format!("Error saying hello to {}: {}", peer, error));
+            }
+        }
+    }
+
+    /// Purge disappearing messages that have expired. The terminal is cleared too, so
+    /// nothing of them is left in its buffer.
+    fn purge_expired_messages(&mut self, logger: &mut StandardLogger) -> Result<()> {
+        match self.context.purge_expired() {
+            Ok(0) => {}
+            Ok(_) => self.term.clear().context("terminal.clear")?,
+            Err(error) => logger.log_error(&format!("Error saving queued messages: {}", error)),
+        }
+        Ok(())
+    }
+
+    /// The messages in the current chat have now been seen, so send read receipts for
+    /// them, unless the user has turned those off
+    async fn send_read_receipts(&mut self, engine: &mut Engine, logger: &mut StandardLogger) {
+        let id = match self.context.chat_list.current().cloned() {
+            Some(ChatId::Peer(id)) if self.context.is_connected(&id) => id,
+            // Nobody gets receipts for room messages
+            Some(ChatId::Room(room_id)) => {
+                if let Some(room) = self.context.rooms.get_mut(&room_id) {
+                    room.chat.take_unread();
+                }
+                return;
+            }
+            _ => return,
+        };
+        let unread = match self.context.chats.get_mut(&id) {
+            Some(chat) => chat.take_unread(),
+            None => return,
+        };
+        if unread.is_empty() || !self.context.config.read_receipts {
+            return;
+        }
+        let receipt = Payload::Receipt {
+            kind: ReceiptKind::Read,
+            ids: unread,
+        };
+        if let Err(error) = protocol::send(engine, &self.context, &id, &receipt, logger).await {
+            logger.log_error(&format!("Error sending read receipt to {}: {}", id, error));
+        }
+    }
+
+    /// Ask the user whether to accept an incoming connection, unless it's from someone
+    /// who's blocked or who's tried too often, in which case it's dropped
+    async fn handle_incoming_connection(
+        &mut self,
+        id: &TorServiceId,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let drop = if self.context.contacts.is_blocked(id) {
+            logger.log_debug(&format!("Dropped connection from blocked address {}", id));
+            true
+        } else {
+            !self.context.contacts.is_saved(id) && !self.context.connection_limiter.allow(id)
+        };
+        if drop {
+            if let Err(error) = engine.disconnect(id, logger).await {
+                logger.log_error(&format!("Error disconnecting {}: {}", id, error));
+            }
+        } else {
+            self.context
+                .notify_locked(format!("connection request from {}", id));
+            self.context.connection_context = Some(ConnectionContext::new(id));
+        }
+    }
+
+    /// Lock the screen if no key has been pressed for a while. It can only be unlocked
+    /// with the passphrase, so there has to be one.
+    fn lock_when_idle(&mut self) {
+        if let Some(lock_timeout) = self.context.config.lock_timeout() {
+            if !self.context.locked
+                && storage::is_unlocked()
+                && self.context.last_activity.elapsed() >= lock_timeout
+            {
+                self.context.lock();
+            }
+        }
+    }
+
+    /// Tell the user about connection attempts that were rejected for coming too often
+    fn summarize_rejected_connections(&mut self, logger: &mut StandardLogger) {
+        if let Some(summary) = self.context.connection_limiter.take_summary() {
+            for (id, count) in summary.peers {
+                logger.log_warning(&format!(
+                    "Rejected {} connection attempt(s) from {} for trying too often",
+                    count, id
+                ));
+            }
+            if summary.untracked > 0 {
+                logger.log_warning(&format!(
+                    "Rejected {} more connection attempt(s) from other addresses",
+                    summary.untracked
+                ));
+            }
+        }
+    }
+
+    /// Queue up any automatic reconnection attempts that are due
+    fn reconnect_chats(&mut self, logger: &mut StandardLogger) {
+        for (id, address) in self.context.due_reconnects() {
+            let attempt = match self.context.chat_states.get_mut(&id) {
+                Some(state) => {
+                    state.next_reconnect = None;
+                    state.reconnect_attempts
+                }
+                None => continue,
+            };
+            logger.log_info(&format!("Reconnecting to {} (attempt {})", id, attempt));
+            self.context.queue_connect(&address, Some(id));
+        }
+    }
+
+    /// Start the next queued outgoing connection. The engine makes it in a task of its
+    /// own, so this doesn't wait: the connection is in progress until a NewConnection
+    /// comes in for the peer, or it's cancelled or times out. The engine doesn't say
+    /// when a connection fails, beyond logging it, so a failure shows up as a timeout.
+    async fn connect(&mut self, address: String, engine: &mut Engine, logger: &mut StandardLogger) {
+        if let Err(error) = engine.connect(&address).await {
+            logger.log_error(&format!("Error connecting to {}: {}", address, error));
+            self.connect_failed(&address, logger);
+        }
+    }
+
+    /// The connection to `address` couldn't be made
+    fn connect_failed(&mut self, address: &str, logger: &mut StandardLogger) {
+        if let Some(pending) = self.context.connect_failed(address) {
+            self.reconnect_failed(pending.reconnect, false, logger);
+        }
+    }
+
+    /// Give up on the connection in progress if it's taken longer than the connect
+    /// timeout
+    fn time_out_connect(&mut self, logger: &mut StandardLogger) {
+        let connect_timeout = self.context.config.connect_timeout();
+        if let Some(pending) = self.context.connect_timed_out(connect_timeout) {
+            logger.log_error(&format!(
+                "Timed out connecting to {} after {}s",
+                pending.address,
+                connect_timeout.as_secs()
+            ));
+            self.reconnect_failed(pending.reconnect, false, logger);
+        }
+    }
+
+    fn reconnect_failed(
+        &mut self,
+        reconnect: Option<TorServiceId>,
+        cancelled: bool,
+        logger: &mut StandardLogger,
+    ) {
+        if let Some(id) = reconnect {
+            match self.context.reconnect_failed(&id, cancelled) {
+                Some(delay) => {
+                    logger.log_info(&format!("Trying {} again in {}s", id, delay.as_secs()))
+                }
+                None if !cancelled => logger.log_error(&format!(
+                    "Couldn't reconnect to {}; type '/reconnect' to try again",
+                    id
+                )),
+                None => {}
+            }
         }
     }
 
@@ -186,10 +1230,33 @@ impl App {
         engine: &mut Engine,
         logger: &mut StandardLogger,
     ) {
-        if self.context.connection_context.is_some() {
+        self.context.last_activity = Instant::now();
+        if is_panic_key(&event) {
+            self.context.panicking = true;
+        } else if self.context.locked {
+            self.lock_input
+                .handle_input_event(event, &mut self.context, engine, logger)
+                .await;
+        } else if self.context.client_keypair.is_some() {
+            if let Event::Key(KeyEvent {
+                code: KeyCode::Enter | KeyCode::Esc,
+                ..
+            }) = event
+            {
+                self.context.client_keypair = None;
+            }
+        } else if self.context.connection_context.is_some() {
             self.allow_connection_input
                 .handle_input_event(event, &mut self.context, engine, logger)
                 .await;
+        } else if !self.context.file_offers.is_empty() {
+            self.file_offer_input
+                .handle_input_event(event, &mut self.context, engine, logger)
+                .await;
+        } else if !self.context.room_invites.is_empty() {
+            self.room_invite_input
+                .handle_input_event(event, &mut self.context, engine, logger)
+                .await;
         } else if self.context.show_command_popup {
             self.command_input
                 .handle_input_event(event, &mut self.context, engine, logger)
@@ -224,6 +1291,8 @@ impl App {
                 KeyCode::Esc => {
                     if self.context.show_welcome_popup {
                         self.context.show_welcome_popup = false;
+                    } else {
+                        self.context.cancel_connects(None);
                     }
                     if self.context.show_command_popup {
                         self.context.show_command_popup = false;
@@ -235,6 +1304,33 @@ impl App {
     }
 }
 
+/// Print the warnings and errors logged since `since`. The system messages go with the
+/// terminal, so this is how anything that went wrong while shutting down gets seen.
+fn flush_log(logger: &StandardLogger, since: DateTime<Local>) {
+    for message in logger
+        .iter()
+        .filter(|message| message.date >= since && message.level >= Level::Warning)
+    {
+        eprintln!("{}", message.message);
+    }
+}
+
+/// The key chord for panic mode, ctrl-alt-p, which works everywhere
+fn is_panic_key(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('p'),
+            modifiers,
+            ..
+        }) if modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    )
+}
+
+/// Put the terminal back before a panic is reported. Nothing else is cleaned up: the
+/// engine and chats can't be reached from here, and a panic can happen halfway through
+/// changing them. Peers see the connection drop when the process exits, and a transient
+/// onion service goes when the control connection closes.
 pub fn install_panic_hook() {
     better_panic::install();
     let hook = std::panic::take_hook();
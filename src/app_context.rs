@@ -1,9 +1,36 @@
-use crate::theme::THEME;
+use crate::{
+    chat::{expiry, Chat, ChatId, ChatList},
+    client_auth::{AuthorizedClients, ClientKeypair},
+    config::TermConfig,
+    contacts::Contacts,
+    outbox::Outbox,
+    protocol::{new_message_id, MessageId},
+    rate_limit::ConnectionLimiter,
+    room::{new_room_id, Member, Room, RoomId},
+    theme::THEME,
+    transfer::{TransferId, Transfers},
+};
+use anyhow::Result;
 use rand::{self, seq::SliceRandom};
 use ratatui::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 use tor_client_lib::key::TorServiceId;
-use voynich::chat::{Chat, ChatList};
+use voynich::chat::ChatMessage;
+use zeroize::Zeroize;
+
+/// Delay before the first automatic reconnection attempt; doubled for each one after
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest we'll wait between automatic reconnection attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How long a typing notification from a peer lasts
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait for a new peer's hello before taking them for a client that only
+/// understands plain text
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub struct ConnectionContext {
@@ -20,6 +47,97 @@ impl ConnectionContext {
     }
 }
 
+/// A file a peer has offered us, waiting for the user to accept or reject it
+#[derive(Debug)]
+pub struct FileOfferContext {
+    pub transfer_id: TransferId,
+    pub accept_selected: bool,
+}
+
+impl FileOfferContext {
+    pub fn new(transfer_id: TransferId) -> Self {
+        Self {
+            transfer_id,
+            accept_selected: true,
+        }
+    }
+}
+
+/// An invitation to a room, waiting for the user to accept or reject it
+#[derive(Debug)]
+pub struct RoomInviteContext {
+    pub room: RoomId,
+    pub name: String,
+    pub inviter: TorServiceId,
+    /// Everyone in the room, as the inviter told us
+    pub members: Vec<Member>,
+    pub accept_selected: bool,
+}
+
+impl RoomInviteContext {
+    pub fn new(room: RoomId, name: String, inviter: TorServiceId, members: Vec<Member>) -> Self {
+        Self {
+            room,
+            name,
+            inviter,
+            members,
+            accept_selected: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+/// Connection health for a chat
+#[derive(Debug)]
+pub struct ChatState {
+    pub status: ConnectionStatus,
+    /// Address used to reconnect; only known if we initiated the connection
+    pub address: Option<String>,
+    pub reconnect_attempts: u32,
+    pub next_reconnect: Option<Instant>,
+    /// When the peer's last typing notification runs out
+    pub typing_until: Option<Instant>,
+    /// Until when we hold queued messages back, waiting for the peer's hello
+    pub hello_deadline: Option<Instant>,
+}
+
+impl ChatState {
+    fn new(status: ConnectionStatus, address: Option<String>) -> Self {
+        Self {
+            status,
+            address,
+            reconnect_attempts: 0,
+            next_reconnect: None,
+            typing_until: None,
+            hello_deadline: None,
+        }
+    }
+}
+
+/// An outgoing connection that's waiting to be made, or is being made
+#[derive(Debug)]
+pub struct PendingConnect {
+    pub address: String,
+    /// If this is reconnecting a dropped chat, the ID of that chat
+    pub reconnect: Option<TorServiceId>,
+    /// When the connection attempt started; None if it's still queued
+    pub started: Option<Instant>,
+}
+
+impl PendingConnect {
+    /// The service ID of the address, which is what the connection is known by once
+    /// it's made
+    fn service_id(&self) -> &str {
+        service_id_of(&self.address)
+    }
+}
+
 #[derive(Debug)]
 pub struct AppContext {
     pub id: TorServiceId,
@@ -28,15 +146,61 @@ pub struct AppContext {
     pub chat_list: ChatList,
     pub chats: HashMap<TorServiceId, Chat>,
     pub show_command_popup: bool,
+    #[allow(dead_code)]
     pub system_messages_scroll: usize,
+    #[allow(dead_code)]
     pub cursor_location: Option<(u16, u16)>,
     pub show_welcome_popup: bool,
     pub connection_context: Option<ConnectionContext>,
     pub message_colors: HashMap<TorServiceId, Color>,
+    pub chat_states: HashMap<TorServiceId, ChatState>,
+    /// Addresses of outgoing connections we've started, keyed by service ID
+    pub connect_addresses: HashMap<String, String>,
+    /// Outgoing connections, in the order they'll be made. Only the first can be in progress.
+    pub pending_connects: Vec<PendingConnect>,
+    /// Service IDs of connections that were cancelled or timed out after the engine
+    /// started making them. If one gets made after all, it's dropped.
+    pub abandoned_connects: Vec<String>,
+    pub outbox: Outbox,
+    pub contacts: Contacts,
+    pub connection_limiter: ConnectionLimiter,
+    pub authorized_clients: AuthorizedClients,
+    /// Keys for connecting to protected onion services, by service ID, waiting to be
+    /// given to Tor before the connection is made
+    pub client_auth_keys: Vec<(String, String)>,
+    /// A key pair just made with 'auth keygen'. It's shown in a popup until dismissed,
+    /// and never logged, since the private key lets anyone connect as its owner.
+    pub client_keypair: Option<ClientKeypair>,
+    pub transfers: Transfers,
+    /// Files offered to us, in the order they came in. The first is shown in a popup.
+    pub file_offers: VecDeque<FileOfferContext>,
+    pub rooms: HashMap<RoomId, Room>,
+    /// Room invitations, in the order they came in. The first is shown in a popup.
+    pub room_invites: VecDeque<RoomInviteContext>,
+    /// Peers we've just connected to, who need telling which of our rooms they're in
+    pub room_announcements: Vec<TorServiceId>,
+    /// Show the member list beside a room's chat
+    pub show_members: bool,
+    /// Peers we've just connected to, who need our hello
+    pub pending_hellos: Vec<TorServiceId>,
+    /// The protocol versions peers have said hello with. Peers that aren't here are
+    /// only sent plain text, since their clients would show payloads as raw JSON.
+    pub peer_protocols: HashMap<TorServiceId, u32>,
+    /// Show messages as they were written, rather than styling their markup
+    pub show_raw_text: bool,
+    pub config: TermConfig,
+    /// Set while the screen is locked. Everything keeps running underneath.
+    pub locked: bool,
+    /// What happened while the screen was locked, to tell the user once it's unlocked
+    pub lock_notifications: Vec<String>,
+    /// When the user last pressed a key, for locking the screen when they're away
+    pub last_activity: Instant,
+    /// Set when the user hits the panic key or command, to wipe everything and exit
+    pub panicking: bool,
 }
 
 impl AppContext {
-    pub fn new(id: TorServiceId, onion_service_address: String) -> Self {
+    pub fn new(id: TorServiceId, onion_service_address: String, config: TermConfig) -> Self {
         Self {
             id,
             onion_service_address,
@@ -49,6 +213,33 @@ impl AppContext {
             show_welcome_popup: false,
             connection_context: None,
             message_colors: HashMap::new(),
+            chat_states: HashMap::new(),
+            connect_addresses: HashMap::new(),
+            pending_connects: Vec::new(),
+            abandoned_connects: Vec::new(),
+            outbox: Outbox::default(),
+            contacts: Contacts::default(),
+            connection_limiter: ConnectionLimiter::new(
+                config.max_connection_attempts,
+                config.max_total_connection_attempts,
+            ),
+            authorized_clients: AuthorizedClients::default(),
+            client_auth_keys: Vec::new(),
+            client_keypair: None,
+            transfers: Transfers::default(),
+            file_offers: VecDeque::new(),
+            rooms: HashMap::new(),
+            room_invites: VecDeque::new(),
+            room_announcements: Vec::new(),
+            show_members: true,
+            pending_hellos: Vec::new(),
+            peer_protocols: HashMap::new(),
+            show_raw_text: !config.rich_text,
+            config,
+            locked: false,
+            lock_notifications: Vec::new(),
+            last_activity: Instant::now(),
+            panicking: false,
         }
     }
 
@@ -56,16 +247,445 @@ impl AppContext {
         self.show_command_popup = !self.show_command_popup;
     }
 
+    /// The port our onion service is listening on
+    pub fn service_port(&self) -> Option<u16> {
+        self.onion_service_address
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+    }
+
+    /// Zero and drop the messages in every chat and room, and everything else we hold
+    /// that says what was said or who to
+    pub fn wipe(&mut self) {
+        for chat in self.chats.values_mut() {
+            chat.wipe();
+        }
+        for room in self.rooms.values_mut() {
+            room.chat.wipe();
+        }
+        self.outbox.wipe();
+        for (_, key) in self.client_auth_keys.iter_mut() {
+            key.zeroize();
+        }
+        self.client_auth_keys.clear();
+        self.client_keypair = None;
+        self.lock_notifications.clear();
+        self.room_invites.clear();
+        self.file_offers.clear();
+    }
+
+    /// Every peer we have a connection to, in a chat or a room
+    pub fn connected_peers(&self) -> Vec<TorServiceId> {
+        let mut peers = self
+            .chat_states
+            .iter()
+            .filter(|(_, state)| state.status == ConnectionStatus::Connected)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for room in self.rooms.values() {
+            for peer in room.peers(&self.id) {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        peers
+    }
+
+    /// Hide everything behind the lock screen
+    pub fn lock(&mut self) {
+        self.locked = true;
+        self.show_command_popup = false;
+        self.show_welcome_popup = false;
+    }
+
+    /// Take the lock screen away, handing back what happened while it was up
+    pub fn unlock(&mut self) -> Vec<String> {
+        self.locked = false;
+        self.last_activity = Instant::now();
+        std::mem::take(&mut self.lock_notifications)
+    }
+
+    /// Keep a note of something that happened while the screen is locked
+    pub fn notify_locked(&mut self, notification: String) {
+        if self.locked {
+            self.lock_notifications.push(notification);
+        }
+    }
+
     pub fn toggle_welcome_popup(&mut self) {
         self.show_welcome_popup = !self.show_welcome_popup;
     }
 
+    /// Add a chat for a new connection. If we already have a chat with this ID (because
+    /// the connection dropped earlier), it's marked as connected again, history intact.
     pub fn add_new_chat(&mut self, id: &TorServiceId) {
-        self.chat_list.add(id);
-        self.chats.insert(id.clone(), Chat::new(id));
+        let address = self.connect_addresses.remove(id.as_str());
+        self.pending_hellos.push(id.clone());
+        match self.chat_states.get_mut(id) {
+            Some(state) => {
+                state.status = ConnectionStatus::Connected;
+                state.reconnect_attempts = 0;
+                state.next_reconnect = None;
+                if address.is_some() {
+                    state.address = address;
+                }
+            }
+            None => self.insert_chat(id, ConnectionStatus::Connected, address),
+        }
+        self.chat_states.get_mut(id).unwrap().hello_deadline = Some(Instant::now() + HELLO_TIMEOUT);
+    }
+
+    /// A peer has said hello, so from now on they're sent payloads. Once they know
+    /// we're in a room with them, they can join us there.
+    pub fn hello_from(&mut self, id: &TorServiceId, version: u32) {
+        self.peer_protocols.insert(id.clone(), version);
+        self.room_announcements.push(id.clone());
+    }
+
+    /// Whether a peer has said hello, so they understand payloads
+    pub fn speaks_protocol(&self, id: &TorServiceId) -> bool {
+        self.peer_protocols.contains_key(id)
+    }
+
+    /// Whether queued messages can go to a peer: they're connected, and either they've
+    /// said hello or we've given up waiting for it
+    pub fn ready_to_send(&self, id: &TorServiceId) -> bool {
+        self.chat_states.get(id).is_some_and(|state| {
+            state.status == ConnectionStatus::Connected
+                && (self.speaks_protocol(id)
+                    || state
+                        .hello_deadline
+                        .is_none_or(|deadline| deadline <= Instant::now()))
+        })
+    }
+
+    /// Open a chat with a peer we're not connected to, so messages can be queued for
+    /// them. Does nothing if there's already a chat with them.
+    pub fn open_chat(&mut self, id: &TorServiceId, address: Option<String>) {
+        if !self.chats.contains_key(id) {
+            self.insert_chat(id, ConnectionStatus::Disconnected, address);
+        }
+    }
+
+    fn insert_chat(
+        &mut self,
+        id: &TorServiceId,
+        status: ConnectionStatus,
+        address: Option<String>,
+    ) {
+        self.chat_list.add(ChatId::Peer(id.clone()));
+        self.chats.insert(id.clone(), Chat::new());
+        self.chat_states
+            .insert(id.clone(), ChatState::new(status, address));
         self.add_id(id.clone());
     }
 
+    /// Queue up an outgoing connection
+    pub fn queue_connect(&mut self, address: &str, reconnect: Option<TorServiceId>) {
+        if let Some(pending) = self
+            .pending_connects
+            .iter_mut()
+            .find(|pending| pending.address == address)
+        {
+            // A chat that's reconnecting shares the attempt that's already queued, so
+            // it hears how that goes and its next attempt gets scheduled
+            if pending.reconnect.is_none() {
+                pending.reconnect = reconnect;
+            }
+            return;
+        }
+        self.add_connect_address(address);
+        self.pending_connects.push(PendingConnect {
+            address: address.to_string(),
+            reconnect,
+            started: None,
+        });
+    }
+
+    /// Start the next queued connection, returning its address. Connections are made
+    /// one at a time, so nothing starts while one is in progress.
+    pub fn start_next_connect(&mut self) -> Option<String> {
+        let pending = self.pending_connects.first_mut()?;
+        if pending.started.is_some() {
+            return None;
+        }
+        pending.started = Some(Instant::now());
+        let service_id = pending.service_id().to_string();
+        self.abandoned_connects
+            .retain(|abandoned| *abandoned != service_id);
+        Some(pending.address.clone())
+    }
+
+    /// Take the connection in progress off the queue, if it's to the peer we've just
+    /// connected to
+    pub fn connect_made(&mut self, id: &TorServiceId) -> Option<PendingConnect> {
+        self.finish_connect(|pending| pending.service_id() == id.as_str())
+    }
+
+    /// Take the connection in progress off the queue, if it's the one to `address`,
+    /// which the engine couldn't make
+    pub fn connect_failed(&mut self, address: &str) -> Option<PendingConnect> {
+        self.abandoned_connects
+            .retain(|abandoned| abandoned != service_id_of(address));
+        self.finish_connect(|pending| pending.address == address)
+    }
+
+    /// Take the connection in progress off the queue if it's been going for longer than
+    /// `timeout`. The engine may still make it, so it's marked as abandoned.
+    pub fn connect_timed_out(&mut self, timeout: Duration) -> Option<PendingConnect> {
+        let pending = self.finish_connect(|pending| {
+            pending
+                .started
+                .is_some_and(|started| started.elapsed() >= timeout)
+        })?;
+        self.abandoned_connects
+            .push(pending.service_id().to_string());
+        Some(pending)
+    }
+
+    fn finish_connect(
+        &mut self,
+        matches: impl Fn(&PendingConnect) -> bool,
+    ) -> Option<PendingConnect> {
+        let pending = self.pending_connects.first()?;
+        if pending.started.is_none() || !matches(pending) {
+            return None;
+        }
+        Some(self.pending_connects.remove(0))
+    }
+
+    /// Whether a connection that's just been made is one we gave up on. If so, it's
+    /// forgotten, since it's about to be dropped.
+    pub fn take_abandoned_connect(&mut self, id: &TorServiceId) -> bool {
+        let count = self.abandoned_connects.len();
+        self.abandoned_connects
+            .retain(|abandoned| abandoned != id.as_str());
+        self.abandoned_connects.len() != count
+    }
+
+    /// Cancel pending connections to the given service ID, or all of them if there's no
+    /// service ID. Returns how many were cancelled.
+    pub fn cancel_connects(&mut self, service_id: Option<&str>) -> usize {
+        let (cancelled, kept) = self
+            .pending_connects
+            .drain(..)
+            .partition::<Vec<_>, _>(|pending| {
+                service_id.is_none_or(|service_id| pending.address.starts_with(service_id))
+            });
+        self.pending_connects = kept;
+        for pending in cancelled.iter() {
+            if pending.started.is_some() {
+                self.abandoned_connects
+                    .push(pending.service_id().to_string());
+            }
+            if let Some(id) = &pending.reconnect {
+                self.reconnect_failed(id, true);
+            }
+        }
+        cancelled.len()
+    }
+
+    /// Remember the address of an outgoing connection, so we can reconnect to it later
+    pub fn add_connect_address(&mut self, address: &str) {
+        self.connect_addresses
+            .insert(service_id_of(address).to_string(), address.to_string());
+    }
+
+    /// Queue a message for a peer we can't send to right now. It's shown in the chat as
+    /// queued, and saved in the outbox until it can be delivered.
+    pub fn queue_message(
+        &mut self,
+        id: &TorServiceId,
+        text: &str,
+        reply_to: Option<MessageId>,
+    ) -> Result<()> {
+        let address = self
+            .chat_states
+            .get(id)
+            .and_then(|state| state.address.clone());
+        let message_id = new_message_id();
+        let expires = self.chats.get(id).and_then(|chat| expiry(chat.ttl()));
+        if let Some(chat) = self.chats.get_mut(id) {
+            chat.add_queued(
+                ChatMessage::new(&self.id, id, text.to_string()),
+                message_id,
+                expires,
+            )
+            .reply_to = reply_to;
+        }
+        self.outbox
+            .push(id, address.as_deref(), message_id, text, expires, reply_to);
+        self.outbox.save()
+    }
+
+    /// Stop any file transfers with a peer, and drop their offers that haven't been
+    /// answered. Returns how many transfers were stopped.
+    pub fn interrupt_transfers(&mut self, id: &TorServiceId) -> usize {
+        let count = self.transfers.interrupt(id);
+        let transfers = &self.transfers;
+        self.file_offers.retain(|offer| {
+            transfers
+                .get(offer.transfer_id)
+                .is_some_and(|transfer| transfer.is_active())
+        });
+        count
+    }
+
+    pub fn remove_chat(&mut self, id: &TorServiceId) {
+        self.interrupt_transfers(id);
+        self.room_invites.retain(|invite| invite.inviter != *id);
+        self.chat_list.remove(&ChatId::Peer(id.clone()));
+        self.chats.remove(id);
+        self.chat_states.remove(id);
+        self.peer_protocols.remove(id);
+        self.remove_id(id);
+    }
+
+    /// The messages in a chat, whether it's with one peer or a room
+    pub fn chat(&self, id: &ChatId) -> Option<&Chat> {
+        match id {
+            ChatId::Peer(id) => self.chats.get(id),
+            ChatId::Room(room) => self.rooms.get(room).map(|room| &room.chat),
+        }
+    }
+
+    pub fn chat_mut(&mut self, id: &ChatId) -> Option<&mut Chat> {
+        match id {
+            ChatId::Peer(id) => self.chats.get_mut(id),
+            ChatId::Room(room) => self.rooms.get_mut(room).map(|room| &mut room.chat),
+        }
+    }
+
+    /// Start a new room, with just us in it, and switch to it
+    pub fn create_room(&mut self, name: &str) -> RoomId {
+        let room_id = new_room_id();
+        let mut room = Room::new(name);
+        room.add_member(&self.id, Some(self.onion_service_address.clone()));
+        self.rooms.insert(room_id, room);
+        self.chat_list.add(ChatId::Room(room_id));
+        room_id
+    }
+
+    /// Join a room we've been invited to, or update the members of one we're in
+    pub fn join_room(&mut self, room_id: RoomId, name: &str, members: Vec<Member>) {
+        let us = self.id.clone();
+        let address = self.onion_service_address.clone();
+        let room = self.rooms.entry(room_id).or_insert_with(|| Room::new(name));
+        room.add_member(&us, Some(address));
+        for member in members.iter().filter(|member| member.id != us) {
+            room.add_member(&member.id, member.address.clone());
+        }
+        self.chat_list.add(ChatId::Room(room_id));
+        for member in members {
+            self.add_member_color(member.id);
+        }
+    }
+
+    pub fn remove_room(&mut self, room_id: RoomId) {
+        self.chat_list.remove(&ChatId::Room(room_id));
+        self.rooms.remove(&room_id);
+    }
+
+    /// Purge disappearing messages that have expired from every chat, and from the
+    /// outbox. Returns how many were removed from the chats.
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for chat in self
+            .chats
+            .values_mut()
+            .chain(self.rooms.values_mut().map(|room| &mut room.chat))
+        {
+            count += chat.purge_expired();
+        }
+        if self.outbox.purge_expired() > 0 {
+            self.outbox.save()?;
+        }
+        Ok(count)
+    }
+
+    pub fn chat_status(&self, id: &TorServiceId) -> Option<ConnectionStatus> {
+        self.chat_states.get(id).map(|state| state.status)
+    }
+
+    pub fn is_connected(&self, id: &TorServiceId) -> bool {
+        self.chat_status(id) == Some(ConnectionStatus::Connected)
+    }
+
+    /// The peer says they're typing
+    pub fn set_typing(&mut self, id: &TorServiceId, typing: bool) {
+        if let Some(state) = self.chat_states.get_mut(id) {
+            state.typing_until = typing.then(|| Instant::now() + TYPING_TIMEOUT);
+        }
+    }
+
+    pub fn is_typing(&self, id: &TorServiceId) -> bool {
+        self.chat_states
+            .get(id)
+            .and_then(|state| state.typing_until)
+            .is_some_and(|time| time > Instant::now())
+    }
+
+    /// Mark a chat as disconnected, keeping its history. If automatic reconnection
+    /// is on and we know how to reach the peer, the first reconnection is scheduled,
+    /// and its delay returned.
+    pub fn set_disconnected(&mut self, id: &TorServiceId) -> Option<Duration> {
+        if let Some(state) = self.chat_states.get_mut(id) {
+            state.status = ConnectionStatus::Disconnected;
+            state.reconnect_attempts = 0;
+            state.next_reconnect = None;
+            state.typing_until = None;
+        }
+        if self.config.auto_reconnect {
+            self.schedule_reconnect(id)
+        } else {
+            None
+        }
+    }
+
+    /// Schedule the next automatic reconnection attempt, backing off exponentially.
+    /// Returns the delay, or None if we can't (or shouldn't) try again.
+    pub fn schedule_reconnect(&mut self, id: &TorServiceId) -> Option<Duration> {
+        let max_attempts = self.config.max_reconnect_attempts;
+        let state = self.chat_states.get_mut(id)?;
+        state.address.as_ref()?;
+        if state.reconnect_attempts >= max_attempts {
+            state.status = ConnectionStatus::Disconnected;
+            state.next_reconnect = None;
+            return None;
+        }
+        let delay = RECONNECT_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(state.reconnect_attempts))
+            .min(RECONNECT_MAX_DELAY);
+        state.status = ConnectionStatus::Reconnecting;
+        state.reconnect_attempts += 1;
+        state.next_reconnect = Some(Instant::now() + delay);
+        Some(delay)
+    }
+
+    /// A reconnection attempt failed or was cancelled. Schedules the next one if automatic
+    /// reconnection is on, returning its delay.
+    pub fn reconnect_failed(&mut self, id: &TorServiceId, cancelled: bool) -> Option<Duration> {
+        if self.config.auto_reconnect && !cancelled {
+            return self.schedule_reconnect(id);
+        }
+        if let Some(state) = self.chat_states.get_mut(id) {
+            state.status = ConnectionStatus::Disconnected;
+            state.next_reconnect = None;
+        }
+        None
+    }
+
+    /// Chats whose next reconnection attempt is due, along with the address to use
+    pub fn due_reconnects(&self) -> Vec<(TorServiceId, String)> {
+        let now = Instant::now();
+        self.chat_states
+            .iter()
+            .filter(|(_, state)| state.next_reconnect.is_some_and(|time| time <= now))
+            .filter_map(|(id, state)| state.address.clone().map(|address| (id.clone(), address)))
+            .collect()
+    }
+
     pub fn add_id(&mut self, id: TorServiceId) {
         let color = THEME
             .chat_message
@@ -74,6 +694,13 @@ impl AppContext {
         self.message_colors.insert(id, *color.unwrap());
     }
 
+    /// Give a room member a color, unless they've got one from a chat with them
+    pub fn add_member_color(&mut self, id: TorServiceId) {
+        if !self.message_colors.contains_key(&id) {
+            self.add_id(id);
+        }
+    }
+
     pub fn get_color(&self, id: &TorServiceId) -> Option<&Color> {
         self.message_colors.get(id)
     }
@@ -82,3 +709,119 @@ impl AppContext {
         self.message_colors.remove(id);
     }
 }
+
+/// The service ID in an address like "<service-id>.onion:<port>"
+fn service_id_of(address: &str) -> &str {
+    address
+        .split(':')
+        .next()
+        .unwrap_or(address)
+        .trim_end_matches(".onion")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_ATTEMPTS: u32 = 3;
+
+    fn context() -> AppContext {
+        let config = TermConfig {
+            auto_reconnect: true,
+            max_reconnect_attempts: MAX_ATTEMPTS,
+            ..TermConfig::default()
+        };
+        AppContext::new(
+            TorServiceId::generate(),
+            "ours.onion:2000".to_string(),
+            config,
+        )
+    }
+
+    fn dropped_chat(context: &mut AppContext) -> (TorServiceId, String) {
+        let peer = TorServiceId::generate();
+        let address = format!("{}.onion:2000", peer);
+        context.add_connect_address(&address);
+        context.add_new_chat(&peer);
+        context.set_disconnected(&peer);
+        (peer, address)
+    }
+
+    /// Run the reconnection attempt that's scheduled, and have it time out
+    fn time_out_attempt(context: &mut AppContext, peer: &TorServiceId, address: &str) {
+        context.chat_states.get_mut(peer).unwrap().next_reconnect = Some(Instant::now());
+        for (id, address) in context.due_reconnects() {
+            context.chat_states.get_mut(&id).unwrap().next_reconnect = None;
+            context.queue_connect(&address, Some(id));
+        }
+        assert_eq!(context.start_next_connect().as_deref(), Some(address));
+        let pending = context.connect_timed_out(Duration::ZERO).unwrap();
+        context.reconnect_failed(&pending.reconnect.unwrap(), false);
+    }
+
+    #[test]
+    fn reconnecting_stops_after_the_last_attempt_times_out() {
+        let mut context = context();
+        let (peer, address) = dropped_chat(&mut context);
+        for attempt in 1..=MAX_ATTEMPTS {
+            assert_eq!(context.chat_states[&peer].reconnect_attempts, attempt);
+            assert_eq!(
+                context.chat_states[&peer].status,
+                ConnectionStatus::Reconnecting
+            );
+            time_out_attempt(&mut context, &peer, &address);
+        }
+        assert_eq!(
+            context.chat_states[&peer].status,
+            ConnectionStatus::Disconnected
+        );
+        assert!(context.chat_states[&peer].next_reconnect.is_none());
+        assert!(context.pending_connects.is_empty());
+    }
+
+    #[test]
+    fn reconnecting_shares_a_connect_already_queued() {
+        let mut context = context();
+        let (peer, address) = dropped_chat(&mut context);
+        context.queue_connect(&address, None);
+        time_out_attempt(&mut context, &peer, &address);
+        assert_eq!(context.chat_states[&peer].reconnect_attempts, 2);
+        assert!(context.chat_states[&peer].next_reconnect.is_some());
+    }
+
+    #[test]
+    fn connects_given_up_on_are_dropped_if_they_arrive() {
+        let mut context = context();
+        let peer = TorServiceId::generate();
+        context.queue_connect(&format!("{}.onion:2000", peer), None);
+        context.start_next_connect();
+        assert!(context.connect_made(&TorServiceId::generate()).is_none());
+        assert!(context.connect_timed_out(Duration::ZERO).is_some());
+        assert!(context.take_abandoned_connect(&peer));
+        assert!(!context.take_abandoned_connect(&peer));
+    }
+
+    #[test]
+    fn queued_messages_wait_for_the_peers_hello() {
+        let mut context = context();
+        let peer = TorServiceId::generate();
+        context.add_new_chat(&peer);
+        assert_eq!(context.pending_hellos, vec![peer.clone()]);
+        assert!(!context.ready_to_send(&peer));
+
+        context.hello_from(&peer, 1);
+        assert!(context.speaks_protocol(&peer));
+        assert!(context.ready_to_send(&peer));
+        assert_eq!(context.room_announcements, vec![peer.clone()]);
+    }
+
+    #[test]
+    fn peers_that_never_say_hello_get_plain_text() {
+        let mut context = context();
+        let peer = TorServiceId::generate();
+        context.add_new_chat(&peer);
+        context.chat_states.get_mut(&peer).unwrap().hello_deadline = Some(Instant::now());
+        assert!(context.ready_to_send(&peer));
+        assert!(!context.speaks_protocol(&peer));
+    }
+}
@@ -0,0 +1,389 @@
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tor_client_lib::TorServiceId;
+use voynich::chat::ChatMessage;
+use zeroize::Zeroize;
+
+use crate::{protocol::MessageId, room::RoomId, transfer::TransferId};
+
+/// What a chat tab holds: a conversation with one peer, or a room
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChatId {
+    Peer(TorServiceId),
+    Room(RoomId),
+}
+
+/// The open chats, in the order their tabs are shown, and which one is current
+#[derive(Debug, Default)]
+pub struct ChatList {
+    ids: Vec<ChatId>,
+    current_index: Option<usize>,
+}
+
+impl ChatList {
+    /// Add a chat and make it the current one. A chat that's already open stays where
+    /// it is, and the current chat doesn't change.
+    pub fn add(&mut self, id: ChatId) {
+        if !self.ids.contains(&id) {
+            self.ids.push(id);
+            self.current_index = Some(self.ids.len() - 1);
+        }
+    }
+
+    pub fn remove(&mut self, id: &ChatId) {
+        if let Some(index) = self.ids.iter().position(|chat_id| chat_id == id) {
+            self.ids.remove(index);
+            self.current_index = match self.current_index {
+                _ if self.ids.is_empty() => None,
+                Some(current) if current > index || current == self.ids.len() => Some(current - 1),
+                current => current,
+            };
+        }
+    }
+
+    pub fn current(&self) -> Option<&ChatId> {
+        self.current_index.map(|index| &self.ids[index])
+    }
+
+    /// The peer in the current chat, if it's with a single peer
+    pub fn current_peer(&self) -> Option<&TorServiceId> {
+        match self.current() {
+            Some(ChatId::Peer(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current_index
+    }
+
+    pub fn ids(&self) -> &[ChatId] {
+        &self.ids
+    }
+
+    /// The peers we have one-to-one chats with
+    pub fn peers(&self) -> Vec<TorServiceId> {
+        self.ids
+            .iter()
+            .filter_map(|id| match id {
+                ChatId::Peer(id) => Some(id.clone()),
+                ChatId::Room(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn next_chat(&mut self) {
+        if let Some(current) = self.current_index {
+            self.current_index = Some((current + 1) % self.ids.len());
+        }
+    }
+
+    pub fn prev_chat(&mut self) {
+        if let Some(current) = self.current_index {
+            self.current_index = Some((current + self.ids.len() - 1) % self.ids.len());
+        }
+    }
+}
+
+/// Where a message is in its journey to the other side
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageState {
+    /// Sent to us by the peer
+    Received,
+    /// Pending: waiting in the outbox for a connection to the peer
+    Queued,
+    /// Handed to the engine to be sent
+    Sent,
+    /// The peer says they've got it
+    Delivered,
+    /// The peer says they've read it
+    Read,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatEntry {
+    /// Messages from clients that don't send IDs don't have one
+    pub id: Option<MessageId>,
+    pub message: ChatMessage,
+    pub state: MessageState,
+    /// Set if this entry is for a file being sent or received
+    pub transfer: Option<TransferId>,
+    /// The sender has changed the text since it was first sent
+    pub edited: bool,
+    /// The sender has deleted the message; its text is gone
+    pub deleted: bool,
+    /// When a disappearing message is purged from the chat
+    pub expires: Option<DateTime<Local>>,
+    /// The earlier message in the chat that this one is a reply to
+    pub reply_to: Option<MessageId>,
+}
+
+/// The text of a message is zeroed when it's dropped: when it disappears, the chat is
+/// closed or we quit
+impl Drop for ChatEntry {
+    fn drop(&mut self) {
+        self.message.message.zeroize();
+    }
+}
+
+impl ChatEntry {
+    /// How long until the message disappears, if it's going to
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expires
+            .map(|expires| (expires - Local::now()).to_std().unwrap_or_default())
+    }
+}
+
+/// When a message that lasts for `ttl` disappears, if it's sent now
+pub fn expiry(ttl: Option<Duration>) -> Option<DateTime<Local>> {
+    ttl.and_then(|ttl| Local::now().checked_add_signed(chrono::Duration::from_std(ttl).ok()?))
+}
+
+/// Format a duration the way it's given to '/ttl', like "90s", "5m" or "1h30m"
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    let mut formatted = String::new();
+    for (unit, size) in [('d', 86400), ('h', 3600), ('m', 60)] {
+        if seconds >= size {
+            formatted.push_str(&format!("{}{}", seconds / size, unit));
+            seconds %= size;
+        }
+    }
+    if seconds > 0 || formatted.is_empty() {
+        formatted.push_str(&format!("{}s", seconds));
+    }
+    formatted
+}
+
+/// How many messages a chat keeps; older ones are dropped, which zeroes their text
+const MAX_ENTRIES: usize = 200;
+
+/// The messages in a conversation with a peer, along with their state
+#[derive(Debug, Default)]
+pub struct Chat {
+    entries: VecDeque<ChatEntry>,
+    /// Messages we've received but not yet shown to the user
+    unread: Vec<MessageId>,
+    /// How long messages last, if they disappear
+    ttl: Option<Duration>,
+    /// Set when we've changed the timer and the peer hasn't said they've changed theirs
+    ttl_requested: bool,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop every message, which zeroes their text
+    pub fn wipe(&mut self) {
+        self.entries.clear();
+        self.unread.clear();
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn ttl_requested(&self) -> bool {
+        self.ttl_requested
+    }
+
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+        self.ttl_requested = false;
+    }
+
+    /// Change the timer at our end, and wait for the peer to say they've changed theirs.
+    /// Our messages use the new timer in the meantime.
+    pub fn request_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+        self.ttl_requested = true;
+    }
+
+    /// The peer has changed their timer. Returns false if it's not the one we asked for,
+    /// which happens if we've changed it again since.
+    pub fn ack_ttl(&mut self, ttl: Option<Duration>) -> bool {
+        if self.ttl_requested && self.ttl == ttl {
+            self.ttl_requested = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add a message sent to us. It disappears after `ttl` if the sender set one,
+    /// otherwise after the chat's own timer.
+    pub fn add_message(
+        &mut self,
+        message: ChatMessage,
+        id: Option<MessageId>,
+        ttl: Option<Duration>,
+    ) -> &mut ChatEntry {
+        if let Some(id) = id {
+            self.unread.push(id);
+        }
+        let expires = expiry(ttl.or(self.ttl));
+        self.push(message, id, MessageState::Received, None, expires)
+    }
+
+    /// Add a message of ours. It disappears after the chat's timer, if there is one.
+    pub fn add_entry(
+        &mut self,
+        message: ChatMessage,
+        id: Option<MessageId>,
+        state: MessageState,
+    ) -> &mut ChatEntry {
+        self.push(message, id, state, None, expiry(self.ttl))
+    }
+
+    /// Add a message of ours that's waiting in the outbox, which keeps its expiry
+    pub fn add_queued(
+        &mut self,
+        message: ChatMessage,
+        id: MessageId,
+        expires: Option<DateTime<Local>>,
+    ) -> &mut ChatEntry {
+        self.push(message, Some(id), MessageState::Queued, None, expires)
+    }
+
+    /// Add an entry for a file transfer, which is shown with its progress
+    pub fn add_transfer(
+        &mut self,
+        message: ChatMessage,
+        transfer: TransferId,
+        state: MessageState,
+    ) {
+        self.push(message, None, state, Some(transfer), expiry(self.ttl));
+    }
+
+    fn push(
+        &mut self,
+        message: ChatMessage,
+        id: Option<MessageId>,
+        state: MessageState,
+        transfer: Option<TransferId>,
+        expires: Option<DateTime<Local>>,
+    ) -> &mut ChatEntry {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(ChatEntry {
+            id,
+            message,
+            state,
+            transfer,
+            edited: false,
+            deleted: false,
+            expires,
+            reply_to: None,
+        });
+        self.entries.back_mut().unwrap()
+    }
+
+    /// Remove the messages that have expired. Returns how many there were.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Local::now();
+        let count = self.entries.len();
+        self.entries
+            .retain(|entry| entry.expires.is_none_or(|expires| expires > now));
+        count - self.entries.len()
+    }
+
+    /// Move our messages with the given IDs along to `state`. Receipts can arrive out of
+    /// order, so a message never goes back to an earlier state.
+    pub fn update_state(&mut self, ids: &[MessageId], state: MessageState) {
+        for entry in self.entries.iter_mut().filter(|entry| {
+            entry.state != MessageState::Received && entry.id.is_some_and(|id| ids.contains(&id))
+        }) {
+            entry.state = entry.state.max(state);
+        }
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<&ChatEntry> {
+        self.entries.iter().find(|entry| entry.id == Some(id))
+    }
+
+    /// The message before or after the one with the given ID, skipping any that can't
+    /// be replied to. With no ID, starts from the end of the chat.
+    pub fn neighbour(&self, id: Option<MessageId>, forward: bool) -> Option<MessageId> {
+        let ids = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.deleted)
+            .filter_map(|entry| entry.id)
+            .collect::<Vec<_>>();
+        let index = match id.and_then(|id| ids.iter().position(|&other| other == id)) {
+            Some(index) if forward => index + 1,
+            Some(index) => index.checked_sub(1)?,
+            None if forward => return None,
+            None => ids.len().checked_sub(1)?,
+        };
+        ids.get(index).copied()
+    }
+
+    /// The last message sent by `us` that can still be edited or deleted
+    pub fn last_sent(&self, us: &TorServiceId) -> Option<&ChatEntry> {
+        self.entries.iter().rev().find(|entry| {
+            entry.message.sender == *us
+                && entry.id.is_some()
+                && entry.transfer.is_none()
+                && !entry.deleted
+        })
+    }
+
+    /// Find a message by its sender and ID. Checking the sender means a peer can only
+    /// change their own messages.
+    fn find_mut(&mut self, sender: &TorServiceId, id: MessageId) -> Option<&mut ChatEntry> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.id == Some(id) && entry.message.sender == *sender && !entry.deleted)
+    }
+
+    /// Change the text of a message. Returns false if there's no such message.
+    pub fn edit(&mut self, sender: &TorServiceId, id: MessageId, text: String) -> bool {
+        match self.find_mut(sender, id) {
+            Some(entry) => {
+                entry.message.message.zeroize();
+                entry.message.message = text;
+                entry.edited = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delete a message, leaving a marker in its place. Returns false if there's no
+    /// such message.
+    pub fn delete(&mut self, sender: &TorServiceId, id: MessageId) -> bool {
+        match self.find_mut(sender, id) {
+            Some(entry) => {
+                entry.message.message.zeroize();
+                entry.deleted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the last message from our copy of the chat, without telling the peer
+    pub fn remove_last(&mut self) -> Option<ChatEntry> {
+        self.entries.pop_back()
+    }
+
+    /// Remove a message from our copy of the chat, without telling the peer
+    pub fn remove(&mut self, id: MessageId) -> Option<ChatEntry> {
+        let index = self.entries.iter().position(|entry| entry.id == Some(id))?;
+        self.entries.remove(index)
+    }
+
+    /// Take the IDs of the messages received since this was last called
+    pub fn take_unread(&mut self) -> Vec<MessageId> {
+        std::mem::take(&mut self.unread)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChatEntry> {
+        self.entries.iter()
+    }
+}
@@ -1,11 +1,17 @@
 use anyhow::{anyhow, Result};
 use clap::{Args, Parser, ValueEnum};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 use tor_client_lib::control_connection::TorSocketAddr;
 use voynich::config::{Config, TorAuthConfig};
 use voynich::onion_service::OnionType;
 
+use crate::{client_auth::ClientAuthKey, tor_socket::TorAddress};
+
+const DEFAULT_TOR_ADDRESS: &str = "127.0.0.1:9051";
+const DEFAULT_TOR_PROXY_ADDRESS: &str = "127.0.0.1:9050";
+
 static SHORT_HELP: &str = "Voynich-term - Anonymous, end-to-end encrypted chat";
 static LONG_HELP: &str = "Voynich-term - Anonymous, end-to-end encrypted chat
 
@@ -28,13 +34,21 @@ If you want a transient service that only lasts for the current session:
 #[derive(Debug, Parser)]
 #[command(author, version, about = SHORT_HELP, long_about = LONG_HELP)]
 pub struct Cli {
-    /// Tor control address - default is 127.0.0.1:9051
-    #[arg(long, value_name = "ADDRESS", default_value_t = SocketAddr::from_str("127.0.0.1:9051").unwrap())]
-    pub tor_address: SocketAddr,
+    /// Tor control address, or unix:<path> for a Unix socket - default is 127.0.0.1:9051
+    #[arg(long, value_name = "ADDRESS")]
+    pub tor_address: Option<TorAddress>,
+
+    /// Tor proxy address, or unix:<path> for a Unix socket - default is 127.0.0.1:9050
+    #[arg(long, value_name = "ADDRESS")]
+    pub tor_proxy_address: Option<TorAddress>,
 
-    /// Tor proxy address - default is 127.0.0.1:9050
-    #[arg(long, value_name = "ADDRESS", default_value_t = SocketAddr::from_str("127.0.0.1:9050").unwrap())]
-    pub tor_proxy_address: SocketAddr,
+    /// Start a tor of our own for this session, instead of using the system Tor
+    #[arg(long, default_value_t = false, conflicts_with_all = ["tor_address", "tor_proxy_address"])]
+    pub spawn_tor: bool,
+
+    /// The tor binary to run with --spawn-tor - default is "tor", from the PATH
+    #[arg(long, value_name = "PATH")]
+    pub tor_binary: Option<PathBuf>,
 
     /// Listen address to use for onion service
     /// Default is "127.0.0.1:<service-port>"
@@ -57,6 +71,63 @@ pub struct Cli {
     #[arg(short, long, default_value_t = false)]
     pub debug: bool,
 
+    /// Automatically reconnect outgoing chats when the connection drops
+    #[arg(long, default_value_t = false)]
+    pub auto_reconnect: bool,
+
+    /// How long to wait for an outgoing connection, in seconds - default is 120
+    #[arg(long, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Don't let peers know when we've read their messages
+    #[arg(long, default_value_t = false)]
+    pub no_read_receipts: bool,
+
+    /// Don't let peers know when we're typing, or show when they are
+    #[arg(long, default_value_t = false)]
+    pub no_typing_indicators: bool,
+
+    /// Show messages as they were written, without styling markup like *bold* and `code`
+    #[arg(long, default_value_t = false)]
+    pub raw_text: bool,
+
+    /// Where to save files peers send us
+    #[arg(long, value_name = "DIR")]
+    pub download_dir: Option<PathBuf>,
+
+    /// Largest file to accept from a peer, in bytes - default is 104857600 (100 MB)
+    #[arg(long, value_name = "BYTES")]
+    pub max_file_size: Option<u64>,
+
+    /// How many times a minute someone who isn't a saved contact can try to connect
+    /// before being rejected automatically; 0 means no limit - default is 3
+    #[arg(long, value_name = "COUNT")]
+    pub max_connection_attempts: Option<u32>,
+
+    /// How many times a minute everyone who isn't a saved contact can try to connect,
+    /// all together; 0 means no limit - default is 20
+    #[arg(long, value_name = "COUNT")]
+    pub max_total_connection_attempts: Option<u32>,
+
+    /// Lock the screen after this many seconds without a key press; it needs a
+    /// passphrase (see the 'passphrase' command) to unlock - default is 0, never
+    #[arg(long, value_name = "SECONDS")]
+    pub lock_timeout: Option<u64>,
+
+    /// In panic mode, remove our onion service from Tor even if it's persistent
+    #[arg(long, default_value_t = false)]
+    pub panic_delete_service: bool,
+
+    /// In panic mode, delete the files voynich-term has saved, like contacts and queued
+    /// messages
+    #[arg(long, default_value_t = false)]
+    pub panic_delete_files: bool,
+
+    /// A key for connecting to a peer's protected onion service, as
+    /// <onion-address>:<private-key>; can be given more than once
+    #[arg(long, value_name = "ONION-ADDRESS:KEY")]
+    pub client_auth_key: Vec<ClientAuthKey>,
+
     /// Type of the onion service
     #[arg(short, long, value_enum)]
     pub onion_type: OnionServiceType,
@@ -116,8 +187,15 @@ impl From<&Cli> for Config {
         let mut config = Config::default();
         config.system.debug = cli.debug;
         config.system.connection_test = !cli.no_connection_test;
-        config.tor.proxy_address = cli.tor_proxy_address;
-        config.tor.control_address = cli.tor_address;
+        // Unix sockets are set in the term config, and bridged to TCP for voynich
+        config.tor.proxy_address = match cli.tor_proxy_address {
+            Some(TorAddress::Tcp(address)) => address,
+            _ => SocketAddr::from_str(DEFAULT_TOR_PROXY_ADDRESS).unwrap(),
+        };
+        config.tor.control_address = match cli.tor_address {
+            Some(TorAddress::Tcp(address)) => address,
+            _ => SocketAddr::from_str(DEFAULT_TOR_ADDRESS).unwrap(),
+        };
         match &cli.auth_args {
             AuthArgs {
                 safe_cookie: None,
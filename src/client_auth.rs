@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{onion_address::OnionAddress, storage};
+
+const CLIENT_AUTH_FILE: &str = "client_auth.json";
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// Clients allowed to connect to our onion service when it's persistent, by name, with
+/// their x25519 public keys in base32. When there are any, nobody else can even find
+/// the service.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AuthorizedClients {
+    clients: BTreeMap<String, String>,
+}
+
+impl AuthorizedClients {
+    pub fn load() -> Result<Self> {
+        storage::load(CLIENT_AUTH_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        storage::save(CLIENT_AUTH_FILE, self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.clients.iter()
+    }
+
+    /// Authorize a client, or replace the key of one with the same name
+    pub fn add(&mut self, name: &str, public_key: &str) -> Result<()> {
+        let public_key = public_key
+            .trim_start_matches("x25519:")
+            .to_ascii_uppercase();
+        match base32::decode(BASE32, &public_key) {
+            Some(bytes) if bytes.len() == 32 => {}
+            _ => {
+                return Err(anyhow!(
+                    "'{}' isn't an x25519 public key; it should be 52 characters of base32",
+                    public_key
+                ))
+            }
+        }
+        self.clients.insert(name.to_string(), public_key);
+        self.save()
+    }
+
+    /// Take away a client's authorization. Returns false if there's no such client.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        if self.clients.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+/// A new client key pair. The public key goes to the owner of the service, who
+/// authorizes it; the private key is used to connect.
+pub struct ClientKeypair {
+    /// Base64, as Tor takes it in ONION_CLIENT_AUTH_ADD
+    pub private_key: Zeroizing<String>,
+    /// Base32, as Tor takes it in ADD_ONION
+    pub public_key: String,
+}
+
+/// The private key is left out, so it can't end up in a log
+impl fmt::Debug for ClientKeypair {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "ClientKeypair({})", self.public_key)
+    }
+}
+
+pub fn generate_keypair() -> ClientKeypair {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+    ClientKeypair {
+        private_key: Zeroizing::new(BASE64.encode(Zeroizing::new(secret.to_bytes()))),
+        public_key: base32::encode(BASE32, public_key.as_bytes()),
+    }
+}
+
+/// Check a private key given to connect to a protected service, in the base64 form
+/// `generate_keypair` gives, with or without an "x25519:" prefix
+pub fn parse_private_key(key: &str) -> Result<String> {
+    let key = key.trim_start_matches("x25519:");
+    match BASE64.decode(key) {
+        Ok(bytes) if bytes.len() == 32 => Ok(key.to_string()),
+        _ => Err(anyhow!(
+            "Client authorization key isn't an x25519 private key; it should be 44 characters of base64"
+        )),
+    }
+}
+
+/// A key for connecting to someone else's protected service, given on the command line
+/// as `<onion-address>:<private-key>`
+#[derive(Clone)]
+pub struct ClientAuthKey {
+    pub service_id: String,
+    pub private_key: Zeroizing<String>,
+}
+
+/// The private key is left out, so it can't end up in a log
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "ClientAuthKey({})", self.service_id)
+    }
+}
+
+impl FromStr for ClientAuthKey {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        let (address, key) = string
+            .split_once(':')
+            .ok_or_else(|| anyhow!("should be <onion-address>:<private-key>"))?;
+        let address = OnionAddress::from_str(address)?;
+        Ok(Self {
+            service_id: address.service_id,
+            private_key: Zeroizing::new(parse_private_key(key)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOR_PROJECT: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+
+    #[test]
+    fn client_auth_key_from_the_command_line() {
+        let private_key = generate_keypair().private_key;
+        for prefix in ["", "x25519:"] {
+            let argument = format!("{}.onion:{}{}", TOR_PROJECT, prefix, private_key.as_str());
+            let key = ClientAuthKey::from_str(&argument).unwrap();
+            assert_eq!(key.service_id, TOR_PROJECT);
+            assert_eq!(key.private_key, private_key);
+            assert!(!format!("{:?}", key).contains(private_key.as_str()));
+        }
+        assert!(ClientAuthKey::from_str(TOR_PROJECT).is_err());
+        assert!(ClientAuthKey::from_str(&format!("{}:not-a-key", TOR_PROJECT)).is_err());
+    }
+}
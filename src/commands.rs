@@ -1,11 +1,70 @@
+use crate::{
+    client_auth::parse_private_key, contacts::MAX_NICKNAME_LENGTH, export::ExportFormat,
+    onion_address::OnionAddress,
+};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Command {
-    Connect { address: String },
+    Connect {
+        address: OnionAddress,
+        /// Private key for a peer whose onion service only lets authorized clients in
+        client_auth_key: Option<String>,
+    },
+    Chat {
+        address: OnionAddress,
+    },
+    Cancel {
+        address: Option<OnionAddress>,
+    },
+    Room {
+        name: String,
+    },
+    /// Write the system messages to a file
+    Export {
+        path: PathBuf,
+        format: Option<ExportFormat>,
+    },
+    /// Put an address on the block list, or with None, list the blocked addresses
+    Block {
+        address: Option<OnionAddress>,
+    },
+    Unblock {
+        address: OnionAddress,
+    },
+    /// Manage who can connect to our onion service
+    Auth {
+        action: AuthAction,
+    },
+    /// Set or change the passphrase our files are encrypted with, or with `remove`,
+    /// stop encrypting them
+    Passphrase {
+        remove: bool,
+    },
+    /// Hide everything until the passphrase is typed
+    Lock,
+    /// Wipe everything in memory and exit at once
+    Panic,
     Quit,
 }
 
+#[derive(Debug)]
+pub enum AuthAction {
+    /// Let the client with this public key connect
+    Add {
+        name: String,
+        public_key: String,
+    },
+    Remove {
+        name: String,
+    },
+    List,
+    /// Make a key pair for connecting to someone else's protected service
+    Keygen,
+}
+
 impl FromStr for Command {
     type Err = anyhow::Error;
 
@@ -13,15 +72,104 @@ impl FromStr for Command {
         let tokens = string.split_whitespace().collect::<Vec<&str>>();
         if !tokens.is_empty() {
             match tokens[0] {
-                "connect" => {
+                "connect" => match tokens[1..] {
+                    [address] => Ok(Self::Connect {
+                        address: parse_address(address)?,
+                        client_auth_key: None,
+                    }),
+                    [address, "--client-auth-key", key] | ["--client-auth-key", key, address] => {
+                        Ok(Self::Connect {
+                            address: parse_address(address)?,
+                            client_auth_key: Some(parse_private_key(key)?),
+                        })
+                    }
+                    _ => Err(anyhow::anyhow!(
+                        "'connect' command takes an address, and optionally '--client-auth-key <key>'"
+                    )),
+                },
+                "chat" => {
                     if tokens.len() != 2 {
-                        Err(anyhow::anyhow!("'connect' command only takes one argument"))
+                        Err(anyhow::anyhow!("'chat' command only takes one argument"))
                     } else {
-                        Ok(Self::Connect {
-                            address: tokens[1].to_string(),
+                        Ok(Self::Chat {
+                            address: parse_address(tokens[1])?,
                         })
                     }
                 }
+                "cancel" => match tokens.len() {
+                    1 => Ok(Self::Cancel { address: None }),
+                    2 => Ok(Self::Cancel {
+                        address: Some(parse_address(tokens[1])?),
+                    }),
+                    _ => Err(anyhow::anyhow!(
+                        "'cancel' command takes at most one argument"
+                    )),
+                },
+                "room" => {
+                    if tokens.len() < 2 {
+                        Err(anyhow::anyhow!("'room' command needs a name for the room"))
+                    } else {
+                        Ok(Self::Room {
+                            name: tokens[1..].join(" "),
+                        })
+                    }
+                }
+                "export" => {
+                    // The path is taken as typed, so it's cut from the string rather
+                    // than put back together from the tokens
+                    let argument = &string.trim_start()[tokens[0].len()..];
+                    let (path, format) = parse_export(argument, "export")?;
+                    Ok(Self::Export { path, format })
+                }
+                "block" => match tokens.len() {
+                    1 => Ok(Self::Block { address: None }),
+                    2 => Ok(Self::Block {
+                        address: Some(parse_address(tokens[1])?),
+                    }),
+                    _ => Err(anyhow::anyhow!(
+                        "'block' command takes at most one argument"
+                    )),
+                },
+                "unblock" => {
+                    if tokens.len() != 2 {
+                        Err(anyhow::anyhow!("'unblock' command only takes one argument"))
+                    } else {
+                        Ok(Self::Unblock {
+                            address: parse_address(tokens[1])?,
+                        })
+                    }
+                }
+                "auth" => match tokens[1..] {
+                    ["add", name, public_key] => Ok(Self::Auth {
+                        action: AuthAction::Add {
+                            name: name.to_string(),
+                            public_key: public_key.to_string(),
+                        },
+                    }),
+                    ["remove", name] => Ok(Self::Auth {
+                        action: AuthAction::Remove {
+                            name: name.to_string(),
+                        },
+                    }),
+                    ["list"] => Ok(Self::Auth {
+                        action: AuthAction::List,
+                    }),
+                    ["keygen"] => Ok(Self::Auth {
+                        action: AuthAction::Keygen,
+                    }),
+                    _ => Err(anyhow::anyhow!(
+                        "'auth' command is one of 'auth add <name> <public-key>', 'auth remove <name>', 'auth list' or 'auth keygen'"
+                    )),
+                },
+                "passphrase" => match tokens[1..] {
+                    ["change"] => Ok(Self::Passphrase { remove: false }),
+                    ["remove"] => Ok(Self::Passphrase { remove: true }),
+                    _ => Err(anyhow::anyhow!(
+                        "'passphrase' command is either 'passphrase change' or 'passphrase remove'"
+                    )),
+                },
+                "lock" => Ok(Self::Lock),
+                "panic" => Ok(Self::Panic),
                 "quit" => Ok(Self::Quit),
                 _ => Err(anyhow::anyhow!("Unknown command '{}'", tokens[0])),
             }
@@ -30,3 +178,434 @@ impl FromStr for Command {
         }
     }
 }
+
+/// Commands typed in the chat input box, after the '/'
+#[derive(Debug)]
+pub enum ChatCommand {
+    Quit,
+    Reconnect,
+    Send {
+        path: PathBuf,
+    },
+    Invite {
+        address: OnionAddress,
+    },
+    Leave,
+    /// Change the text of our last message
+    Edit {
+        text: String,
+    },
+    /// Delete our last message for everyone, or if `local` is set, delete the last
+    /// message in the chat from our copy only
+    Delete {
+        local: bool,
+    },
+    /// Make messages in the chat disappear after a while, or with None, stop them
+    /// disappearing
+    Ttl {
+        ttl: Option<Duration>,
+    },
+    /// Write the chat's messages to a file
+    Export {
+        path: PathBuf,
+        format: Option<ExportFormat>,
+    },
+    /// Find messages in the chat, or with `all`, in every chat and the system log
+    Search {
+        query: String,
+        regex: bool,
+        ignore_case: bool,
+        all: bool,
+    },
+    /// Show the safety number for the chat, or mark the contact as verified or not
+    Verify {
+        action: VerifyAction,
+    },
+    /// Give the contact a nickname, or with None, take it away
+    Nick {
+        nickname: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyAction {
+    Show,
+    Confirm,
+    Clear,
+}
+
+impl FromStr for ChatCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (command, argument) = match string.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (string.trim(), ""),
+        };
+        match (command, argument) {
+            ("quit", "") => Ok(Self::Quit),
+            ("reconnect", "") => Ok(Self::Reconnect),
+            ("leave", "") => Ok(Self::Leave),
+            ("quit" | "reconnect" | "leave", _) => Err(anyhow::anyhow!(
+                "'/{}' command doesn't take any arguments",
+                command
+            )),
+            ("send", "") => Err(anyhow::anyhow!("'/send' command needs a file to send")),
+            // The path is the rest of the line, so it can have spaces in it
+            ("send", path) => Ok(Self::Send {
+                path: expand_home(path),
+            }),
+            ("edit", "") => Err(anyhow::anyhow!(
+                "'/edit' command needs the new text; or press up-arrow to edit your last message"
+            )),
+            ("edit", text) => Ok(Self::Edit {
+                text: text.to_string(),
+            }),
+            ("delete", "") => Ok(Self::Delete { local: false }),
+            ("delete", "local") => Ok(Self::Delete { local: true }),
+            ("delete", _) => Err(anyhow::anyhow!(
+                "'/delete' command only takes 'local' as an argument"
+            )),
+            ("ttl", "") => Err(anyhow::anyhow!(
+                "'/ttl' command needs how long messages should last, like '5m', or 'off'"
+            )),
+            ("ttl", "off") => Ok(Self::Ttl { ttl: None }),
+            ("ttl", duration) => Ok(Self::Ttl {
+                ttl: Some(parse_duration(duration)?),
+            }),
+            ("search", argument) => parse_search(argument),
+            ("export", argument) => {
+                let (path, format) = parse_export(argument, "/export")?;
+                Ok(Self::Export { path, format })
+            }
+            ("verify", "") => Ok(Self::Verify {
+                action: VerifyAction::Show,
+            }),
+            ("verify", "confirm") => Ok(Self::Verify {
+                action: VerifyAction::Confirm,
+            }),
+            ("verify", "clear") => Ok(Self::Verify {
+                action: VerifyAction::Clear,
+            }),
+            ("verify", _) => Err(anyhow::anyhow!(
+                "'/verify' command only takes 'confirm' or 'clear' as an argument"
+            )),
+            ("nick", "") => Ok(Self::Nick { nickname: None }),
+            ("nick", nickname) if nickname.chars().count() > MAX_NICKNAME_LENGTH => {
+                Err(anyhow::anyhow!(
+                    "Nicknames can be at most {} characters",
+                    MAX_NICKNAME_LENGTH
+                ))
+            }
+            ("nick", nickname) => Ok(Self::Nick {
+                nickname: Some(nickname.to_string()),
+            }),
+            ("invite", "") => Err(anyhow::anyhow!(
+                "'/invite' command needs the address of someone to invite"
+            )),
+            ("invite", address) => Ok(Self::Invite {
+                address: parse_address(address)?,
+            }),
+            _ => Err(anyhow::anyhow!("Unknown command '{}'", command)),
+        }
+    }
+}
+
+/// Expand a leading '~' in a path to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Parse the arguments to an export command: a path, and a "--format <format>" or
+/// "--format=<format>" option before or after it. The path is taken as it was typed,
+/// apart from space at either end. A "--" ends the options, for a path that starts or
+/// ends with "--format".
+fn parse_export(
+    argument: &str,
+    command: &str,
+) -> Result<(PathBuf, Option<ExportFormat>), anyhow::Error> {
+    let mut format = None;
+    let mut set_format = |value: &str| {
+        if value.is_empty() {
+            return Err(anyhow::anyhow!("'--format' needs one of txt, md or json"));
+        }
+        if format.is_some() {
+            return Err(anyhow::anyhow!("'--format' can only be given once"));
+        }
+        format = Some(ExportFormat::from_str(value)?);
+        Ok(())
+    };
+
+    // Options before the path
+    let mut rest = argument.trim();
+    let mut options_ended = false;
+    loop {
+        let (word, remainder) = split_first_word(rest);
+        match word {
+            "--" => {
+                rest = remainder;
+                options_ended = true;
+                break;
+            }
+            "--format" => {
+                let (value, remainder) = split_first_word(remainder);
+                set_format(value)?;
+                rest = remainder;
+            }
+            word => match word.strip_prefix("--format=") {
+                Some(value) => {
+                    set_format(value)?;
+                    rest = remainder;
+                }
+                None => break,
+            },
+        }
+    }
+
+    // Options after it
+    if !options_ended {
+        loop {
+            let (front, last) = split_last_word(rest);
+            if last == "--format" {
+                set_format("")?;
+            }
+            if let Some(value) = last.strip_prefix("--format=") {
+                set_format(value)?;
+                rest = front;
+                continue;
+            }
+            match split_last_word(front) {
+                (front, "--format") => {
+                    set_format(last)?;
+                    rest = front;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    if rest.is_empty() {
+        return Err(anyhow::anyhow!(
+            "'{}' command needs a file to write to",
+            command
+        ));
+    }
+    Ok((expand_home(rest), format))
+}
+
+/// Split off the first word of a string, and the rest with the space before it trimmed
+fn split_first_word(string: &str) -> (&str, &str) {
+    match string.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (string, ""),
+    }
+}
+
+/// Split off the last word of a string, and what's before it with the space after it
+/// trimmed
+fn split_last_word(string: &str) -> (&str, &str) {
+    match string.rsplit_once(char::is_whitespace) {
+        Some((front, word)) => (front.trim_end(), word),
+        None => ("", string),
+    }
+}
+
+/// Parse the arguments to '/search': any of the flags -r (regex), -i (ignore case) and
+/// -a (all chats), then the text to look for, which is the rest of the line. A "--"
+/// ends the flags, for text that starts with '-'.
+fn parse_search(argument: &str) -> Result<ChatCommand, anyhow::Error> {
+    let (mut regex, mut ignore_case, mut all) = (false, false, false);
+    let mut rest = argument;
+    while let Some(flag) = rest.strip_prefix('-') {
+        let (flags, remainder) = flag.split_once(char::is_whitespace).unwrap_or((flag, ""));
+        if flags == "-" {
+            rest = remainder.trim_start();
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'r' => regex = true,
+                'i' => ignore_case = true,
+                'a' => all = true,
+                _ => return Err(anyhow::anyhow!("Unknown '/search' option '-{}'", flag)),
+            }
+        }
+        rest = remainder.trim_start();
+    }
+    if rest.is_empty() {
+        return Err(anyhow::anyhow!(
+            "'/search' command needs something to search for"
+        ));
+    }
+    Ok(ChatCommand::Search {
+        query: rest.to_string(),
+        regex,
+        ignore_case,
+        all,
+    })
+}
+
+/// Parse a duration like "30s", "5m", "1h30m" or "7d". A number without a unit is
+/// taken as seconds.
+fn parse_duration(string: &str) -> Result<Duration, anyhow::Error> {
+    let error = || {
+        anyhow::anyhow!(
+            "Bad duration '{}'; use something like '30s', '5m', '2h' or '1d'",
+            string
+        )
+    };
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for character in string.chars() {
+        if character.is_ascii_digit() {
+            number.push(character);
+            continue;
+        }
+        let unit = match character {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(error()),
+        };
+        let value = number.parse::<u64>().map_err(|_| error())?;
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .ok_or_else(error)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let value = number.parse::<u64>().map_err(|_| error())?;
+        seconds = seconds.checked_add(value).ok_or_else(error)?;
+    }
+    if seconds == 0 {
+        return Err(anyhow::anyhow!(
+            "Messages have to last for some time; use '/ttl off' to stop them disappearing"
+        ));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_address(address: &str) -> Result<OnionAddress, anyhow::Error> {
+    OnionAddress::from_str(address)
+        .map_err(|error| anyhow::anyhow!("Bad address '{}': {}", address, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(argument: &str) -> Result<(String, bool, bool, bool), anyhow::Error> {
+        match parse_search(argument)? {
+            ChatCommand::Search {
+                query,
+                regex,
+                ignore_case,
+                all,
+            } => Ok((query, regex, ignore_case, all)),
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn export_format_before_or_after_the_path() {
+        let expected = (PathBuf::from("chat.json"), Some(ExportFormat::Json));
+        assert_eq!(
+            parse_export("--format json chat.json", "/export").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_export("chat.json --format json", "/export").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_export("chat.json --format=json", "/export").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn export_path_can_hold_spaces_and_dashes() {
+        assert_eq!(
+            parse_export("my chat.txt", "/export").unwrap(),
+            (PathBuf::from("my chat.txt"), None)
+        );
+        assert_eq!(
+            parse_export("notes--format.md", "/export").unwrap(),
+            (PathBuf::from("notes--format.md"), None)
+        );
+        assert_eq!(
+            parse_export("--format md -- --format", "/export").unwrap(),
+            (PathBuf::from("--format"), Some(ExportFormat::Markdown))
+        );
+    }
+
+    #[test]
+    fn export_path_is_kept_as_typed() {
+        assert_eq!(
+            parse_export("  my  chat.txt ", "/export").unwrap(),
+            (PathBuf::from("my  chat.txt"), None)
+        );
+        assert_eq!(
+            parse_export("--format md  old  notes.md --format=md", "/export")
+                .unwrap_err()
+                .to_string(),
+            "'--format' can only be given once"
+        );
+        match Command::from_str("export  my  chat.txt --format txt").unwrap() {
+            Command::Export { path, format } => {
+                assert_eq!(path, PathBuf::from("my  chat.txt"));
+                assert_eq!(format, Some(ExportFormat::Text));
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn export_needs_a_path_and_a_known_format() {
+        assert!(parse_export("", "/export").is_err());
+        assert!(parse_export("--format json", "/export").is_err());
+        assert!(parse_export("chat.txt --format", "/export").is_err());
+        assert!(parse_export("chat.txt --format pdf", "/export").is_err());
+        assert!(parse_export("chat.txt --format md --format json", "/export").is_err());
+    }
+
+    #[test]
+    fn search_query_without_options() {
+        assert_eq!(
+            search("hello there").unwrap(),
+            ("hello there".to_string(), false, false, false)
+        );
+    }
+
+    #[test]
+    fn search_options_can_be_combined() {
+        assert_eq!(
+            search("-r -i fo+").unwrap(),
+            ("fo+".to_string(), true, true, false)
+        );
+        assert_eq!(
+            search("-ria x").unwrap(),
+            ("x".to_string(), true, true, true)
+        );
+    }
+
+    #[test]
+    fn search_options_end_at_double_dash() {
+        assert_eq!(
+            search("-i -- -r").unwrap(),
+            ("-r".to_string(), false, true, false)
+        );
+    }
+
+    #[test]
+    fn search_needs_a_query_and_known_options() {
+        assert!(search("").is_err());
+        assert!(search("-i").is_err());
+        assert!(search("-x foo").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{cli::Cli, storage, tor_socket::TorAddress};
+
+/// Settings specific to voynich-term. These live in the `[term]` section of the voynich
+/// config file, alongside the `[system]` and `[tor]` sections that voynich reads.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TermConfig {
+    /// Automatically reconnect outgoing chats when the connection drops
+    pub auto_reconnect: bool,
+
+    /// How many times to try reconnecting before giving up
+    pub max_reconnect_attempts: u32,
+
+    /// How long to wait for an outgoing connection, in seconds
+    pub connect_timeout: u64,
+
+    /// Let peers know when we've read their messages
+    pub read_receipts: bool,
+
+    /// Let peers know when we're typing, and show when they are
+    pub typing_indicators: bool,
+
+    /// Style the markup in messages, like *bold* and `code`, rather than showing the
+    /// raw text
+    pub rich_text: bool,
+
+    /// Where to save files peers send us. Default is a "voynich" folder in the
+    /// downloads directory.
+    pub download_dir: Option<PathBuf>,
+
+    /// Largest file we'll accept from a peer, in bytes
+    pub max_file_size: u64,
+
+    /// How many times a minute someone who isn't a saved contact can try to connect
+    /// before further attempts are rejected without asking; 0 means no limit
+    pub max_connection_attempts: u32,
+
+    /// How many times a minute everyone who isn't a saved contact can try to connect,
+    /// all together; 0 means no limit
+    pub max_total_connection_attempts: u32,
+
+    /// Lock the screen after this many seconds without a key press, if there's a
+    /// passphrase to unlock it with; 0 means never
+    pub lock_timeout: u64,
+
+    /// In panic mode, remove our onion service from Tor even if it's persistent
+    pub panic_delete_service: bool,
+
+    /// In panic mode, delete the files voynich-term has saved, like contacts and queued
+    /// messages
+    pub panic_delete_files: bool,
+
+    /// Reach the Tor control port through this Unix socket, instead of the
+    /// `control_address` in the `[tor]` section
+    pub tor_control_socket: Option<PathBuf>,
+
+    /// Reach the Tor proxy through this Unix socket, instead of the `proxy_address` in
+    /// the `[tor]` section
+    pub tor_proxy_socket: Option<PathBuf>,
+
+    /// Start a tor of our own, rather than using the system Tor
+    pub spawn_tor: bool,
+
+    /// The tor binary to start; a bare name is looked for in the PATH
+    pub tor_binary: PathBuf,
+}
+
+impl Default for TermConfig {
+    fn default() -> Self {
+        Self {
+            auto_reconnect: false,
+            max_reconnect_attempts: 8,
+            connect_timeout: 120,
+            read_receipts: true,
+            typing_indicators: true,
+            rich_text: true,
+            download_dir: None,
+            max_file_size: 100 * 1024 * 1024,
+            max_connection_attempts: 3,
+            max_total_connection_attempts: 20,
+            lock_timeout: 0,
+            panic_delete_service: false,
+            panic_delete_files: false,
+            tor_control_socket: None,
+            tor_proxy_socket: None,
+            spawn_tor: false,
+            tor_binary: PathBuf::from("tor"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    term: TermConfig,
+}
+
+impl TermConfig {
+    /// Read the `[term]` section of the config file. A missing file gives the defaults.
+    pub fn load() -> Result<Self> {
+        let path = config_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let config_file: ConfigFile =
+            toml::from_str(&contents).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(config_file.term)
+    }
+
+    /// Override the config file settings with anything given on the command line
+    pub fn update(mut self, cli: &Cli) -> Self {
+        if cli.auto_reconnect {
+            self.auto_reconnect = true;
+        }
+        if let Some(connect_timeout) = cli.connect_timeout {
+            self.connect_timeout = connect_timeout;
+        }
+        if cli.no_read_receipts {
+            self.read_receipts = false;
+        }
+        if cli.no_typing_indicators {
+            self.typing_indicators = false;
+        }
+        if cli.raw_text {
+            self.rich_text = false;
+        }
+        if let Some(download_dir) = &cli.download_dir {
+            self.download_dir = Some(download_dir.clone());
+        }
+        if let Some(max_file_size) = cli.max_file_size {
+            self.max_file_size = max_file_size;
+        }
+        if let Some(max_connection_attempts) = cli.max_connection_attempts {
+            self.max_connection_attempts = max_connection_attempts;
+        }
+        if let Some(max_total_connection_attempts) = cli.max_total_connection_attempts {
+            self.max_total_connection_attempts = max_total_connection_attempts;
+        }
+        if let Some(lock_timeout) = cli.lock_timeout {
+            self.lock_timeout = lock_timeout;
+        }
+        if cli.panic_delete_service {
+            self.panic_delete_service = true;
+        }
+        if cli.panic_delete_files {
+            self.panic_delete_files = true;
+        }
+        if let Some(tor_binary) = &cli.tor_binary {
+            self.tor_binary = tor_binary.clone();
+        }
+        // A TCP address on the command line wins over a socket in the config file
+        match &cli.tor_address {
+            Some(TorAddress::Unix(path)) => self.tor_control_socket = Some(path.clone()),
+            Some(TorAddress::Tcp(_)) => self.tor_control_socket = None,
+            None => {}
+        }
+        match &cli.tor_proxy_address {
+            Some(TorAddress::Unix(path)) => self.tor_proxy_socket = Some(path.clone()),
+            Some(TorAddress::Tcp(_)) => self.tor_proxy_socket = None,
+            None => {}
+        }
+        // Giving the address of a Tor that's already running means not starting one
+        if cli.spawn_tor {
+            self.spawn_tor = true;
+        } else if cli.tor_address.is_some() || cli.tor_proxy_address.is_some() {
+            self.spawn_tor = false;
+        }
+        self
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        match self.lock_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Where to save files peers send us
+    pub fn download_dir(&self) -> Result<PathBuf> {
+        match &self.download_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => match dirs::download_dir() {
+                Some(dir) => Ok(dir.join("voynich")),
+                None => Ok(storage::data_dir()?.join("downloads")),
+            },
+        }
+    }
+}
+
+/// Directory holding the voynich config file
+pub fn config_dir() -> Result<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join("voynich"))
+        .ok_or_else(|| anyhow::anyhow!("Unable to determine the config directory"))
+}
+
+fn config_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
@@ -0,0 +1,272 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::BTreeMap;
+use tor_client_lib::TorServiceId;
+
+use crate::{onion_address::public_key, storage};
+
+const CONTACTS_FILE: &str = "contacts.json";
+
+/// Mixed into the hash behind safety numbers, so they can't be confused with any other
+/// hash of the keys
+const SAFETY_NUMBER_PREFIX: &[u8] = b"voynich-term safety number";
+
+/// How many groups of five digits make up a safety number
+const SAFETY_NUMBER_GROUPS: usize = 6;
+
+/// Longest nickname we'll take
+pub const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// Most people we'll keep track of who aren't saved contacts. They're only kept in
+/// memory, so this just stops strangers connecting over and over from using it up.
+const MAX_UNSAVED: usize = 256;
+
+/// What we know about someone we've chatted with
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Contact {
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// The user has checked the safety number with them out of band. Since the service
+    /// ID is their public key, this vouches for the key.
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub verified_at: Option<DateTime<Local>>,
+    /// When we last sent them a message or got one from them
+    #[serde(default)]
+    pub last_chatted: Option<DateTime<Local>>,
+    /// How many connections with them have been made, in either direction
+    #[serde(default)]
+    pub connections: u32,
+    /// How many of their incoming connections we've rejected
+    #[serde(default)]
+    pub rejections: u32,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+impl Contact {
+    /// Whether the user has saved them as a contact, by naming or verifying them, rather
+    /// than us just keeping track of them
+    pub fn is_saved(&self) -> bool {
+        self.nickname.is_some() || self.verified
+    }
+
+    /// Whether they're written to disk. Only people the user has saved or blocked are;
+    /// what we know about anyone else lasts for the session, so strangers who try to
+    /// connect don't leave a trail in the contacts file.
+    fn is_persisted(&self) -> bool {
+        self.is_saved() || self.blocked
+    }
+}
+
+/// Everyone we've connected to, keyed by service ID, with what we know about them.
+/// Saved contacts and blocked peers are written to disk whenever they change.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Contacts {
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl Contacts {
+    pub fn load() -> Result<Self> {
+        storage::load(CONTACTS_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        storage::save(CONTACTS_FILE, &self.persisted())
+    }
+
+    /// The contacts that are written to disk
+    fn persisted(&self) -> Self {
+        Self {
+            contacts: self
+                .contacts
+                .iter()
+                .filter(|(_, contact)| contact.is_persisted())
+                .map(|(service_id, contact)| (service_id.clone(), contact.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, id: &TorServiceId) -> Option<&Contact> {
+        self.contacts.get(id.as_str())
+    }
+
+    pub fn is_verified(&self, id: &TorServiceId) -> bool {
+        self.get(id).is_some_and(|contact| contact.verified)
+    }
+
+    pub fn is_blocked(&self, id: &TorServiceId) -> bool {
+        self.get(id).is_some_and(|contact| contact.blocked)
+    }
+
+    /// Whether the user has saved them as a contact
+    pub fn is_saved(&self, id: &TorServiceId) -> bool {
+        self.get(id).is_some_and(|contact| contact.is_saved())
+    }
+
+    /// The service IDs on the block list
+    pub fn blocked(&self) -> Vec<&str> {
+        self.contacts
+            .iter()
+            .filter(|(_, contact)| contact.blocked)
+            .map(|(service_id, _)| service_id.as_str())
+            .collect()
+    }
+
+    pub fn nickname(&self, id: &TorServiceId) -> Option<&str> {
+        self.get(id).and_then(|contact| contact.nickname.as_deref())
+    }
+
+    /// What to call someone: their nickname if they have one, otherwise their service ID
+    pub fn display_name(&self, id: &TorServiceId) -> String {
+        match self.nickname(id) {
+            Some(nickname) => nickname.to_string(),
+            None => id.to_string(),
+        }
+    }
+
+    /// A verified contact other than `id` who has the given nickname. If there is one,
+    /// giving `id` the same nickname could pass off a different key as theirs.
+    pub fn verified_with_nickname(&self, nickname: &str, id: &TorServiceId) -> Option<&str> {
+        self.contacts
+            .iter()
+            .find(|(service_id, contact)| {
+                contact.verified
+                    && contact.nickname.as_deref() == Some(nickname)
+                    && service_id.as_str() != id.as_str()
+            })
+            .map(|(service_id, _)| service_id.as_str())
+    }
+
+    pub fn set_nickname(&mut self, id: &TorServiceId, nickname: Option<String>) {
+        self.entry(id).nickname = nickname;
+    }
+
+    pub fn set_blocked(&mut self, id: &TorServiceId, blocked: bool) -> Result<()> {
+        self.entry(id).blocked = blocked;
+        self.save()
+    }
+
+    /// Count a connection made with them
+    pub fn record_connection(&mut self, id: &TorServiceId) -> Result<()> {
+        self.record(id, |contact| contact.connections += 1)
+    }
+
+    /// Count an incoming connection from them that we rejected
+    pub fn record_rejection(&mut self, id: &TorServiceId) -> Result<()> {
+        self.record(id, |contact| contact.rejections += 1)
+    }
+
+    /// Note that we've just exchanged a message with them
+    pub fn record_chatted(&mut self, id: &TorServiceId) -> Result<()> {
+        self.record(id, |contact| contact.last_chatted = Some(Local::now()))
+    }
+
+    /// Update what we know about them, saving it if they're a contact that's kept on disk
+    fn record(&mut self, id: &TorServiceId, update: impl FnOnce(&mut Contact)) -> Result<()> {
+        let contact = self.entry(id);
+        update(contact);
+        if contact.is_persisted() {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn entry(&mut self, id: &TorServiceId) -> &mut Contact {
+        if !self.contacts.contains_key(id.as_str()) {
+            let unsaved = self
+                .contacts
+                .iter()
+                .filter(|(_, contact)| !contact.is_persisted())
+                .map(|(service_id, _)| service_id);
+            if unsaved.clone().count() >= MAX_UNSAVED {
+                let evicted = unsaved.min().cloned().unwrap();
+                self.contacts.remove(&evicted);
+            }
+        }
+        self.contacts.entry(id.as_str().to_string()).or_default()
+    }
+
+    pub fn set_verified(&mut self, id: &TorServiceId, verified: bool) {
+        let contact = self.entry(id);
+        contact.verified = verified;
+        contact.verified_at = verified.then(Local::now);
+    }
+}
+
+/// A number for two users to read to each other out of band, to check that each has the
+/// other's real onion address. It's made from both public keys, sorted so that both
+/// sides get the same number, as six groups of five digits.
+pub fn safety_number(us: &TorServiceId, them: &TorServiceId) -> Result<String> {
+    let mut keys = [public_key(us.as_str())?, public_key(them.as_str())?];
+    keys.sort();
+    let mut hasher = Sha3_256::new();
+    hasher.update(SAFETY_NUMBER_PREFIX);
+    for key in keys.iter() {
+        hasher.update(key);
+    }
+    let digest = hasher.finalize();
+    Ok(digest
+        .chunks(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |value, &byte| (value << 8) | byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const TOR_PROJECT: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+    const DUCKDUCKGO: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad";
+
+    #[test]
+    fn safety_number_is_the_same_both_ways() {
+        let tor_project = TorServiceId::from_str(TOR_PROJECT).unwrap();
+        let duckduckgo = TorServiceId::from_str(DUCKDUCKGO).unwrap();
+        let number = safety_number(&tor_project, &duckduckgo).unwrap();
+        assert_eq!(number, "94051 15077 40192 83534 85408 12611");
+        assert_eq!(safety_number(&duckduckgo, &tor_project).unwrap(), number);
+    }
+
+    #[test]
+    fn only_saved_and_blocked_contacts_are_persisted() {
+        let mut contacts = Contacts::default();
+        let stranger = TorServiceId::generate();
+        let friend = TorServiceId::generate();
+        contacts.record_rejection(&stranger).unwrap();
+        contacts.record_connection(&friend).unwrap();
+        assert!(contacts.persisted().contacts.is_empty());
+        assert_eq!(contacts.get(&stranger).unwrap().rejections, 1);
+
+        contacts.set_nickname(&friend, Some("friend".to_string()));
+        let persisted = contacts.persisted();
+        assert_eq!(persisted.contacts.len(), 1);
+        assert_eq!(persisted.get(&friend).unwrap().connections, 1);
+    }
+
+    #[test]
+    fn strangers_kept_in_memory_are_bounded() {
+        let mut contacts = Contacts::default();
+        let friend = TorServiceId::generate();
+        contacts.set_nickname(&friend, Some("friend".to_string()));
+        for _ in 0..MAX_UNSAVED + 10 {
+            contacts
+                .record_rejection(&TorServiceId::generate())
+                .unwrap();
+        }
+        assert_eq!(contacts.contacts.len(), MAX_UNSAVED + 1);
+        assert!(contacts.get(&friend).unwrap().is_saved());
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Start of every encrypted file, so they can be told apart from plain ones
+const MAGIC: &[u8] = b"voynich-term encrypted 1\n";
+
+const NONCE_LENGTH: usize = 24;
+const SALT_LENGTH: usize = 16;
+
+/// A key for XChaCha20-Poly1305, zeroed when it's dropped
+pub type Key = Zeroizing<[u8; 32]>;
+
+/// What's kept on disk to unlock the files: the key they're encrypted with, itself
+/// encrypted with a key derived from the passphrase. Changing the passphrase only
+/// changes this.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WrappedKey {
+    salt: String,
+    key: String,
+}
+
+impl WrappedKey {
+    /// Encrypt `key` with a key derived from `passphrase`
+    pub fn new(key: &Key, passphrase: &str) -> Result<Self> {
+        let mut salt = [0; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let wrapping_key = derive_key(passphrase, &salt)?;
+        Ok(Self {
+            salt: BASE64.encode(salt),
+            key: BASE64.encode(encrypt(&wrapping_key, key.as_slice())?),
+        })
+    }
+
+    /// Get the key back, which only works with the right passphrase
+    pub fn unwrap(&self, passphrase: &str) -> Result<Key> {
+        let salt = BASE64.decode(&self.salt)?;
+        let wrapping_key = derive_key(passphrase, &salt)?;
+        let decrypted = Zeroizing::new(
+            decrypt(&wrapping_key, &BASE64.decode(&self.key)?)
+                .map_err(|_| anyhow!("Wrong passphrase"))?,
+        );
+        let mut key = Key::default();
+        if decrypted.len() != key.len() {
+            return Err(anyhow!("The saved key is the wrong length"));
+        }
+        key.copy_from_slice(&decrypted);
+        Ok(key)
+    }
+}
+
+pub fn new_key() -> Key {
+    let mut key = Key::default();
+    key.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng));
+    key
+}
+
+/// Turn a passphrase into a key with Argon2id, which is slow enough to make guessing
+/// passphrases expensive
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|error| anyhow!("Error deriving key: {}", error))?;
+    Ok(key)
+}
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Encrypt with XChaCha20-Poly1305. The result is the magic header, a random nonce and
+/// the ciphertext.
+pub fn encrypt(key: &Key, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.as_slice().into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Error encrypting"))?;
+    let mut contents = MAGIC.to_vec();
+    contents.extend_from_slice(&nonce);
+    contents.extend(ciphertext);
+    Ok(contents)
+}
+
+/// Decrypt what `encrypt` made. It fails if the key is wrong or the contents have been
+/// tampered with.
+pub fn decrypt(key: &Key, contents: &[u8]) -> Result<Vec<u8>> {
+    let contents = contents
+        .strip_prefix(MAGIC)
+        .filter(|contents| contents.len() >= NONCE_LENGTH)
+        .ok_or_else(|| anyhow!("Not an encrypted file"))?;
+    let (nonce, ciphertext) = contents.split_at(NONCE_LENGTH);
+    XChaCha20Poly1305::new(key.as_slice().into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Can't decrypt; the key is wrong or the file is damaged"))
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use voynich::logger::StandardLogger;
+
+use crate::chat::Chat;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Text,
+    Markdown,
+    Json,
+}
+
+impl ExportFormat {
+    /// The format to use when none is given, going by the file's extension
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("md" | "markdown") => Self::Markdown,
+            Some("json") => Self::Json,
+            _ => Self::Text,
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "txt" | "text" => Ok(Self::Text),
+            "md" | "markdown" => Ok(Self::Markdown),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow::anyhow!(
+                "Unknown export format '{}'; use txt, md or json",
+                string
+            )),
+        }
+    }
+}
+
+/// A chat message or system message, as it's written out
+#[derive(Debug, Serialize)]
+pub struct ExportedMessage {
+    pub date: DateTime<Local>,
+    /// Who sent a chat message, or the level of a system message
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Export<'a> {
+    title: &'a str,
+    exported: DateTime<Local>,
+    messages: &'a [ExportedMessage],
+}
+
+/// The messages in a chat, leaving out any that have been deleted
+pub fn chat_messages(chat: &Chat) -> Vec<ExportedMessage> {
+    chat.iter()
+        .filter(|entry| !entry.deleted)
+        .map(|entry| ExportedMessage {
+            date: DateTime::<Local>::from(entry.message.date),
+            from: entry.message.sender.to_string(),
+            text: entry.message.message.clone(),
+        })
+        .collect()
+}
+
+/// The messages in the system log
+pub fn log_messages(logger: &StandardLogger) -> Vec<ExportedMessage> {
+    logger
+        .iter()
+        .map(|message| ExportedMessage {
+            date: message.date,
+            from: format!("{:?}", message.level).to_lowercase(),
+            text: message.message.clone(),
+        })
+        .collect()
+}
+
+/// What to tell the user once they've exported messages
+pub fn warning(count: usize, path: &Path) -> String {
+    format!(
+        "Exported {} message(s) to {}. The file is NOT encrypted: anyone who can read it can read them",
+        count,
+        path.display()
+    )
+}
+
+/// Write messages to a new file. The file is only readable by us, but it isn't
+/// encrypted, and an existing file is never overwritten.
+pub fn export(
+    path: &Path,
+    format: ExportFormat,
+    title: &str,
+    messages: &[ExportedMessage],
+) -> Result<()> {
+    let exported = Local::now();
+    let contents = match format {
+        ExportFormat::Text => {
+            let mut contents = format!("{}\nExported {}\n\n", title, exported.format(DATE_FORMAT));
+            for message in messages {
+                contents.push_str(&format!(
+                    "{} {}: {}\n",
+                    message.date.format(DATE_FORMAT),
+                    message.from,
+                    // Line up the rest of a message that runs over several lines
+                    message.text.replace('\n', "\n    ")
+                ));
+            }
+            contents
+        }
+        ExportFormat::Markdown => {
+            let mut contents = format!(
+                "# {}\n\n_Exported {}_\n\n",
+                title,
+                exported.format(DATE_FORMAT)
+            );
+            for message in messages {
+                contents.push_str(&format!(
+                    "**{}** — {}\n\n{}\n\n",
+                    message.from,
+                    message.date.format(DATE_FORMAT),
+                    message.text
+                ));
+            }
+            contents
+        }
+        ExportFormat::Json => {
+            let export = Export {
+                title,
+                exported,
+                messages,
+            };
+            serde_json::to_string_pretty(&export)? + "\n"
+        }
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|error| anyhow::anyhow!("Can't create {}: {}", path.display(), error))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|error| anyhow::anyhow!("Can't write {}: {}", path.display(), error))?;
+    Ok(())
+}
@@ -1,6 +1,12 @@
 pub mod allow_connection_input;
 pub mod chat_input;
 pub mod command_input;
+pub mod file_offer_input;
+pub mod lock_input;
+pub mod room_invite_input;
+
+use crate::secret::SecretChars;
+use zeroize::Zeroizing;
 
 pub enum CursorMovement {
     Left,
@@ -17,17 +23,17 @@ pub enum CursorMovement {
 
 #[derive(Clone, Debug, Default)]
 pub struct Input {
-    buffer: Vec<char>,
+    buffer: SecretChars,
     cursor: usize,
     prompt_size: usize,
 }
 
 impl Input {
     pub fn new(prompt: Option<&str>) -> Self {
-        let mut buffer = Vec::new();
+        let mut buffer = SecretChars::new();
         let prompt_size = match prompt {
             Some(prompt) => {
-                buffer.extend_from_slice(prompt.chars().collect::<Vec<char>>().as_slice());
+                buffer.extend(prompt.chars());
                 prompt.len()
             }
             None => 0,
@@ -39,8 +45,30 @@ impl Input {
         }
     }
 
-    pub fn get_input(&self) -> String {
-        self.buffer[..].iter().collect::<String>()
+    /// A copy of the input, prompt and all, which is zeroed once it's been used
+    pub fn get_input(&self) -> Zeroizing<String> {
+        let mut input = Zeroizing::new(String::with_capacity(self.buffer.utf8_len()));
+        input.extend(self.buffer.iter());
+        input
+    }
+
+    /// The input with what's been typed hidden, for passphrases
+    pub fn get_masked_input(&self) -> String {
+        let hidden = self.buffer.len() - self.prompt_size;
+        self.buffer.as_slice()[..self.prompt_size]
+            .iter()
+            .chain(std::iter::repeat_n(&'*', hidden))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.len() == self.prompt_size
+    }
+
+    pub fn set_input(&mut self, input: &str) {
+        self.buffer.truncate(self.prompt_size);
+        self.buffer.extend(input.chars());
+        self.cursor = self.buffer.len();
     }
 
     pub fn write(&mut self, character: char) {
@@ -84,15 +112,23 @@ impl Input {
 
     pub fn clear_input_to_cursor(&mut self) {
         if !self.buffer.is_empty() {
-            self.buffer.drain(self.prompt_size..self.cursor);
+            self.buffer.remove_range(self.prompt_size, self.cursor);
             self.cursor = self.prompt_size;
         }
     }
 
-    pub fn reset_input(&mut self) -> Option<String> {
+    /// Zero everything in the buffer, prompt and all
+    pub fn wipe(&mut self) {
+        self.buffer.truncate(0);
+        self.cursor = 0;
+        self.prompt_size = 0;
+    }
+
+    /// Take what's been typed, leaving the prompt. It's zeroed once it's been used.
+    pub fn reset_input(&mut self) -> Option<Zeroizing<String>> {
         if !self.buffer.is_empty() {
             self.cursor = self.prompt_size;
-            return Some(self.buffer.drain(self.prompt_size..).collect());
+            return Some(self.buffer.take_from(self.prompt_size));
         }
         None
     }
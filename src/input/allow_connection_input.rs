@@ -1,6 +1,9 @@
 use crate::{app::InputHandler, app_context::AppContext};
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
-use voynich::{engine::Engine, logger::StandardLogger};
+use voynich::{
+    engine::Engine,
+    logger::{Logger, StandardLogger},
+};
 
 #[derive(Debug)]
 pub struct AllowConnectionInput {}
@@ -34,10 +37,8 @@ impl InputHandler for AllowConnectionInput {
                 .connection_address
                 .clone();
             match code {
-                KeyCode::Char(character) => {
-                    if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
-                        context.should_quit = true;
-                    }
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    context.should_quit = true;
                 }
                 KeyCode::Esc => {
                     context.connection_context.as_mut().unwrap().accept_selected = false;
@@ -48,11 +49,18 @@ impl InputHandler for AllowConnectionInput {
                             .send_connection_authorized_message(&connection_address, logger)
                             .await;
                         context.add_new_chat(&connection_address);
+                        if let Err(error) = context.contacts.record_connection(&connection_address)
+                        {
+                            logger.log_error(&format!("Error saving contacts: {}", error));
+                        }
                     } else {
                         engine
                             .disconnect(&connection_address, logger)
                             .await
                             .unwrap();
+                        if let Err(error) = context.contacts.record_rejection(&connection_address) {
+                            logger.log_error(&format!("Error saving contacts: {}", error));
+                        }
                     }
                     context.connection_context = None;
                 }
@@ -1,34 +1,986 @@
 use crate::{
     app::InputHandler,
-    app_context::AppContext,
+    app_context::{AppContext, ConnectionStatus},
+    chat::{format_duration, ChatId, MessageState},
+    commands::{ChatCommand, VerifyAction},
+    contacts,
+    export::{self, ExportFormat},
     input::{CursorMovement, Input},
+    onion_address::OnionAddress,
+    protocol::{self, new_message_id, MessageId, Payload},
+    room::RoomId,
+    search::{ChatSearch, Search},
+    transfer::format_size,
 };
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use std::borrow::Cow;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tor_client_lib::TorServiceId;
 use voynich::{
     chat::ChatMessage,
     engine::Engine,
     logger::{Logger, StandardLogger},
 };
+use zeroize::Zeroizing;
+
+/// Most matches a search of all chats lists in the system log
+const MAX_LISTED_MATCHES: usize = 50;
+
+/// Don't tell a peer we're typing more often than this
+const TYPING_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(3);
+
+/// What the chat input is being used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputMode {
+    /// Writing a new message
+    Message,
+    /// Changing the text of one of our messages
+    Editing(MessageId),
+    /// Moving the message cursor through the chat, to pick a message to reply to, edit
+    /// or delete
+    Selecting(MessageId),
+    /// Writing a reply to a message
+    Replying(MessageId),
+    /// Asking whether a message picked with the cursor goes for everyone or just us
+    Deleting(MessageId),
+    /// Moving between the messages that match a search
+    Searching,
+}
 
 #[derive(Debug)]
 pub struct ChatInput {
     input: Input,
+    /// Who we last told we were typing, and when
+    last_typing_notification: Option<(TorServiceId, Instant)>,
+    mode: InputMode,
+    /// The search through the current chat, while the mode is `Searching`
+    search: Option<ChatSearch>,
 }
 
 impl ChatInput {
     pub fn new() -> Self {
         Self {
             input: Input::new(None),
+            last_typing_notification: None,
+            mode: InputMode::Message,
+            search: None,
+        }
+    }
+
+    pub fn wipe(&mut self) {
+        self.input.wipe();
+        self.search = None;
+    }
+
+    pub fn mode(&self) -> InputMode {
+        self.mode
+    }
+
+    pub fn search(&self) -> Option<&ChatSearch> {
+        self.search.as_ref()
+    }
+
+    /// Load one of our messages in the current chat into the input, to edit it: the
+    /// one picked with the message cursor, or if none was, our last message
+    fn start_edit(
+        &mut self,
+        selected: Option<MessageId>,
+        context: &AppContext,
+    ) -> Result<(), &'static str> {
+        let chat_id = context.chat_list.current().ok_or("No current chat")?;
+        let message_id = Self::our_message(chat_id, selected, context)?;
+        if let Some(entry) = context.chat(chat_id).and_then(|chat| chat.get(message_id)) {
+            self.mode = InputMode::Editing(message_id);
+            self.input.set_input(&entry.message.message);
+        }
+        Ok(())
+    }
+
+    /// Put the message cursor on the last message in the current chat
+    fn start_select(&mut self, context: &AppContext) {
+        if let Some(message_id) = context
+            .chat_list
+            .current()
+            .and_then(|id| context.chat(id))
+            .and_then(|chat| chat.neighbour(None, false))
+        {
+            self.mode = InputMode::Selecting(message_id);
+        }
+    }
+
+    /// Go back to writing a new message. An edit that wasn't saved is thrown away, but
+    /// the text of a reply is kept.
+    fn reset_mode(&mut self) {
+        if let InputMode::Editing(_) = self.mode {
+            self.input.reset_input();
+        }
+        self.mode = InputMode::Message;
+    }
+
+    /// Search the chat with `chat_id`, and go to the most recent match. A search of all
+    /// chats just lists what it finds in the system log.
+    fn start_search(
+        &mut self,
+        chat_id: &ChatId,
+        search: Search,
+        all: bool,
+        context: &AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        if all {
+            Self::search_all(&search, context, logger);
+            return;
+        }
+        let chat_search = match context.chat(chat_id) {
+            Some(chat) => ChatSearch::new(search, chat_id.clone(), chat),
+            None => return,
+        };
+        if chat_search.current.is_none() {
+            logger.log_info(&format!("No messages match '{}'", chat_search.search.query));
+            return;
+        }
+        self.search = Some(chat_search);
+        self.mode = InputMode::Searching;
+    }
+
+    /// Search every open chat and the system log, and list the matches in the log
+    fn search_all(search: &Search, context: &AppContext, logger: &mut StandardLogger) {
+        let mut found = Vec::new();
+        for chat_id in context.chat_list.ids() {
+            let name = match chat_id {
+                ChatId::Peer(id) => id.to_string(),
+                ChatId::Room(room_id) => match context.rooms.get(room_id) {
+                    Some(room) => format!("#{}", room.name),
+                    None => continue,
+                },
+            };
+            let entries = context
+                .chat(chat_id)
+                .into_iter()
+                .flat_map(|chat| chat.iter());
+            for entry in entries.filter(|entry| !entry.deleted) {
+                if search.is_match(&entry.message.message) {
+                    found.push(format!(
+                        "{} {} {}: {}",
+                        name,
+                        entry.message.date.format("%H:%M:%S"),
+                        entry.message.sender,
+                        entry.message.message
+                    ));
+                }
+            }
+        }
+        for message in logger
+            .iter()
+            .filter(|message| search.is_match(&message.message))
+        {
+            found.push(format!(
+                "system log {}: {}",
+                message.date.format("%H:%M:%S"),
+                message.message
+            ));
+        }
+
+        logger.log_info(&format!(
+            "{} match(es) for '{}' in all chats{}",
+            found.len(),
+            search.query,
+            if found.len() > MAX_LISTED_MATCHES {
+                format!("; showing the last {}", MAX_LISTED_MATCHES)
+            } else {
+                String::new()
+            }
+        ));
+        let skip = found.len().saturating_sub(MAX_LISTED_MATCHES);
+        for line in found.into_iter().skip(skip) {
+            logger.log_info(&line);
+        }
+    }
+
+    /// Handle a key while moving between search matches
+    fn handle_searching_key(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+        context: &mut AppContext,
+    ) {
+        let chat = self
+            .search
+            .as_ref()
+            .and_then(|search| context.chat(&search.chat_id));
+        let (search, chat) = match (self.search.as_mut(), chat) {
+            (Some(search), Some(chat)) => (search, chat),
+            _ => {
+                self.mode = InputMode::Message;
+                return;
+            }
+        };
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                context.should_quit = true;
+            }
+            KeyCode::Char('n') | KeyCode::Up => search.step(chat, false),
+            KeyCode::Char('N') | KeyCode::Down => search.step(chat, true),
+            KeyCode::Esc | KeyCode::Enter => {
+                self.search = None;
+                self.mode = InputMode::Message;
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a key while the message cursor is up
+    fn handle_selecting_key(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+        selected: MessageId,
+        context: &mut AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        let chat = context.chat_list.current().and_then(|id| context.chat(id));
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                context.should_quit = true;
+            }
+            KeyCode::Up => {
+                if let Some(message_id) =
+                    chat.and_then(|chat| chat.neighbour(Some(selected), false))
+                {
+                    self.mode = InputMode::Selecting(message_id);
+                }
+            }
+            // Moving past the last message puts the cursor away
+            KeyCode::Down => {
+                self.mode = match chat.and_then(|chat| chat.neighbour(Some(selected), true)) {
+                    Some(message_id) => InputMode::Selecting(message_id),
+                    None => InputMode::Message,
+                };
+            }
+            KeyCode::Char('r') | KeyCode::Enter => {
+                self.mode = InputMode::Replying(selected);
+            }
+            KeyCode::Char('e') => {
+                if let Err(error) = self.start_edit(Some(selected), context) {
+                    logger.log_error(error);
+                }
+            }
+            KeyCode::Char('d') => {
+                self.mode = InputMode::Deleting(selected);
+            }
+            KeyCode::Esc => {
+                self.mode = InputMode::Message;
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a key while asking how to delete a message picked with the cursor
+    async fn handle_deleting_key(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+        selected: MessageId,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let local = match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                context.should_quit = true;
+                return;
+            }
+            KeyCode::Char('d') | KeyCode::Enter => false,
+            KeyCode::Char('l') => true,
+            KeyCode::Esc => {
+                self.mode = InputMode::Selecting(selected);
+                return;
+            }
+            _ => return,
+        };
+        self.mode = InputMode::Message;
+        if let Some(chat_id) = context.chat_list.current().cloned() {
+            Self::delete(&chat_id, Some(selected), local, context, engine, logger).await;
         }
     }
 
-    pub fn get_input(&self) -> String {
+    pub fn get_input(&self) -> Zeroizing<String> {
         self.input.get_input()
     }
 
     pub fn cursor_location(&self, width: usize) -> (u16, u16) {
         self.input.cursor_location(width)
     }
+
+    /// Let the peer in the current chat know we're typing, unless we've told them recently
+    async fn notify_typing(
+        &mut self,
+        context: &AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if !context.config.typing_indicators || self.input.is_empty() {
+            return;
+        }
+        let id = match context.chat_list.current_peer() {
+            Some(id) if context.is_connected(id) => id,
+            _ => return,
+        };
+        if let Some((last_id, time)) = &self.last_typing_notification {
+            if last_id == id && time.elapsed() < TYPING_NOTIFICATION_INTERVAL {
+                return;
+            }
+        }
+        self.last_typing_notification = Some((id.clone(), Instant::now()));
+        if let Err(error) = protocol::send(engine, context, id, &Payload::Typing, logger).await {
+            logger.log_debug(&format!(
+                "Error sending typing notification to {}: {}",
+                id, error
+            ));
+        }
+    }
+
+    /// Handle a line typed into the chat with `id`: either a command, or a message,
+    /// which might be a reply to an earlier one
+    async fn handle_line(
+        &mut self,
+        id: &TorServiceId,
+        mut input: Zeroizing<String>,
+        reply_to: Option<MessageId>,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if let Some(command) = input.strip_prefix('/') {
+            match ChatCommand::from_str(command) {
+                Ok(ChatCommand::Quit) => {
+                    if context.is_connected(id) {
+                        let _ = engine.disconnect(id, logger).await;
+                    }
+                    context.remove_chat(id);
+                }
+                Ok(ChatCommand::Reconnect) => Self::reconnect(id, context, logger),
+                Ok(ChatCommand::Send { path }) => {
+                    Self::send_file(id, &path, context, engine, logger).await
+                }
+                Ok(ChatCommand::Edit { text }) => {
+                    Self::edit_last(&ChatId::Peer(id.clone()), text, context, engine, logger).await
+                }
+                Ok(ChatCommand::Delete { local }) => {
+                    Self::delete(&ChatId::Peer(id.clone()), None, local, context, engine, logger)
+                        .await
+                }
+                Ok(ChatCommand::Ttl { ttl }) => {
+                    Self::set_ttl(&ChatId::Peer(id.clone()), ttl, context, engine, logger).await
+                }
+                Ok(ChatCommand::Export { path, format }) => {
+                    Self::export_chat(&ChatId::Peer(id.clone()), &path, format, context, logger)
+                }
+                Ok(ChatCommand::Search {
+                    query,
+                    regex,
+                    ignore_case,
+                    all,
+                }) => match Search::new(&query, regex, ignore_case) {
+                    Ok(search) => {
+                        self.start_search(&ChatId::Peer(id.clone()), search, all, context, logger)
+                    }
+                    Err(error) => logger.log_error(&error.to_string()),
+                },
+                Ok(ChatCommand::Verify { action }) => Self::verify(id, action, context, logger),
+                Ok(ChatCommand::Nick { nickname }) => {
+                    Self::set_nickname(id, nickname, context, logger)
+                }
+                Ok(ChatCommand::Invite { .. } | ChatCommand::Leave) => logger.log_error(
+                    "That only works in a room; type 'room <name>' in the command window to start one",
+                ),
+                Err(error) => logger.log_error(&error.to_string()),
+            }
+        } else if !context.is_connected(id) {
+            // The engine doesn't report failed sends, so anything typed while the peer is
+            // away goes straight to the outbox
+            match context.queue_message(id, &input, reply_to) {
+                Ok(()) => logger.log_info(&format!("Not connected to {}; message queued", id)),
+                Err(error) => logger.log_error(&format!("Error saving queued message: {}", error)),
+            }
+        } else {
+            let message_id = new_message_id();
+            let payload = Payload::Text {
+                id: message_id,
+                text: Cow::Borrowed(&input),
+                ttl: context
+                    .chats
+                    .get(id)
+                    .and_then(|chat| chat.ttl())
+                    .map(|ttl| ttl.as_secs()),
+                reply_to,
+            };
+            if let Err(error) = protocol::send(engine, context, id, &payload, logger).await {
+                logger.log_error(&format!("Error sending chat message: {}", error));
+                return;
+            }
+            let message = ChatMessage::new(&context.id, id, std::mem::take(&mut *input));
+            if let Some(chat) = context.chats.get_mut(id) {
+                chat.add_entry(message, Some(message_id), MessageState::Sent)
+                    .reply_to = reply_to;
+            }
+            if let Err(error) = context.contacts.record_chatted(id) {
+                logger.log_error(&format!("Error saving contacts: {}", error));
+            }
+        }
+    }
+
+    /// Handle a line typed into a room: either a command, or a message for its members
+    async fn handle_room_line(
+        &mut self,
+        room_id: RoomId,
+        mut input: Zeroizing<String>,
+        reply_to: Option<MessageId>,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if let Some(command) = input.strip_prefix('/') {
+            match ChatCommand::from_str(command) {
+                Ok(ChatCommand::Invite { address }) => {
+                    Self::invite(room_id, &address, context, engine, logger).await
+                }
+                Ok(ChatCommand::Leave | ChatCommand::Quit) => {
+                    Self::leave_room(room_id, context, engine, logger).await
+                }
+                Ok(ChatCommand::Edit { text }) => {
+                    Self::edit_last(&ChatId::Room(room_id), text, context, engine, logger).await
+                }
+                Ok(ChatCommand::Delete { local }) => {
+                    Self::delete(&ChatId::Room(room_id), None, local, context, engine, logger).await
+                }
+                Ok(ChatCommand::Ttl { ttl }) => {
+                    Self::set_ttl(&ChatId::Room(room_id), ttl, context, engine, logger).await
+                }
+                Ok(ChatCommand::Export { path, format }) => {
+                    Self::export_chat(&ChatId::Room(room_id), &path, format, context, logger)
+                }
+                Ok(ChatCommand::Search {
+                    query,
+                    regex,
+                    ignore_case,
+                    all,
+                }) => match Search::new(&query, regex, ignore_case) {
+                    Ok(search) => {
+                        self.start_search(&ChatId::Room(room_id), search, all, context, logger)
+                    }
+                    Err(error) => logger.log_error(&error.to_string()),
+                },
+                Ok(
+                    ChatCommand::Reconnect
+                    | ChatCommand::Send { .. }
+                    | ChatCommand::Verify { .. }
+                    | ChatCommand::Nick { .. },
+                ) => logger.log_error("That doesn't work in a room"),
+                Err(error) => logger.log_error(&error.to_string()),
+            }
+            return;
+        }
+
+        let (peers, ttl) = match context.rooms.get(&room_id) {
+            Some(room) => (room.peers(&context.id), room.chat.ttl()),
+            None => return,
+        };
+        let message_id = new_message_id();
+        let payload = Payload::RoomText {
+            room: room_id,
+            id: message_id,
+            text: Cow::Borrowed(&input),
+            ttl: ttl.map(|ttl| ttl.as_secs()),
+            reply_to,
+        };
+        let mut missed = Vec::new();
+        for peer in peers {
+            if !context.is_connected(&peer) {
+                missed.push(peer.to_string());
+                continue;
+            }
+            if let Err(error) = protocol::send(engine, context, &peer, &payload, logger).await {
+                logger.log_error(&format!(
+                    "Error sending room message to {}: {}",
+                    peer, error
+                ));
+                missed.push(peer.to_string());
+            }
+        }
+        if !missed.is_empty() {
+            logger.log_error(&format!(
+                "Message not delivered to {}; not connected",
+                missed.join(", ")
+            ));
+        }
+        let message = ChatMessage::new(&context.id, &context.id, std::mem::take(&mut *input));
+        if let Some(room) = context.rooms.get_mut(&room_id) {
+            room.chat
+                .add_entry(message, Some(message_id), MessageState::Sent)
+                .reply_to = reply_to;
+        }
+    }
+
+    /// The message to change: the one picked with the message cursor, or if none was,
+    /// our last message. Only our own messages can be changed.
+    fn our_message(
+        chat_id: &ChatId,
+        selected: Option<MessageId>,
+        context: &AppContext,
+    ) -> Result<MessageId, &'static str> {
+        let chat = context.chat(chat_id).ok_or("No current chat")?;
+        match selected {
+            Some(message_id) => match chat.get(message_id) {
+                Some(entry)
+                    if entry.message.sender == context.id
+                        && entry.transfer.is_none()
+                        && !entry.deleted =>
+                {
+                    Ok(message_id)
+                }
+                Some(_) => Err("You can only change your own messages"),
+                None => Err("That message has gone"),
+            },
+            None => chat
+                .last_sent(&context.id)
+                .and_then(|entry| entry.id)
+                .ok_or("No message to change"),
+        }
+    }
+
+    /// Edit our last message in a chat
+    async fn edit_last(
+        chat_id: &ChatId,
+        text: String,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        match Self::our_message(chat_id, None, context) {
+            Ok(message_id) => {
+                Self::edit_message(chat_id, message_id, Some(text), context, engine, logger).await
+            }
+            Err(error) => logger.log_error(error),
+        }
+    }
+
+    /// Delete the message picked with the message cursor, or our last message, for
+    /// everyone. With `local`, the picked message, or the last message in the chat
+    /// whoever sent it, is dropped from our own copy instead.
+    async fn delete(
+        chat_id: &ChatId,
+        selected: Option<MessageId>,
+        local: bool,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if local {
+            let removed = context.chat_mut(chat_id).and_then(|chat| match selected {
+                Some(message_id) => chat.remove(message_id),
+                None => chat.remove_last(),
+            });
+            // A queued message that's deleted shouldn't be delivered later
+            if let (ChatId::Peer(id), Some(entry)) = (chat_id, removed) {
+                if let (MessageState::Queued, Some(message_id)) = (entry.state, entry.id) {
+                    if context.outbox.remove(id, message_id) {
+                        if let Err(error) = context.outbox.save() {
+                            logger.log_error(&format!("Error saving queued messages: {}", error));
+                        }
+                    }
+                }
+            }
+            return;
+        }
+        match Self::our_message(chat_id, selected, context) {
+            Ok(message_id) => {
+                Self::edit_message(chat_id, message_id, None, context, engine, logger).await
+            }
+            Err(error) => logger.log_error(error),
+        }
+    }
+
+    /// Change the text of one of our messages, or delete it if there's no text, both
+    /// here and for whoever it went to. Messages that haven't been sent yet are just
+    /// changed in the outbox.
+    async fn edit_message(
+        chat_id: &ChatId,
+        message_id: MessageId,
+        text: Option<String>,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let state = context
+            .chat(chat_id)
+            .and_then(|chat| chat.iter().find(|entry| entry.id == Some(message_id)))
+            .map(|entry| entry.state);
+        let (recipients, room) = match chat_id {
+            ChatId::Peer(id) if state == Some(MessageState::Queued) => {
+                let changed = match &text {
+                    Some(text) => context.outbox.edit(id, message_id, text),
+                    None => context.outbox.remove(id, message_id),
+                };
+                if changed {
+                    if let Err(error) = context.outbox.save() {
+                        logger.log_error(&format!("Error saving queued messages: {}", error));
+                    }
+                }
+                (Vec::new(), None)
+            }
+            ChatId::Peer(id) if !context.is_connected(id) => {
+                logger.log_error(&format!(
+                    "Not connected to {}; a message that's been sent can only be changed while connected",
+                    id
+                ));
+                return;
+            }
+            ChatId::Peer(id) => (vec![id.clone()], None),
+            ChatId::Room(room_id) => match context.rooms.get(room_id) {
+                Some(room) => (room.peers(&context.id), Some(*room_id)),
+                None => return,
+            },
+        };
+
+        let payload = match &text {
+            Some(text) => Payload::Edit {
+                id: message_id,
+                text: Cow::Borrowed(text),
+                room,
+            },
+            None => Payload::Delete {
+                id: message_id,
+                room,
+            },
+        };
+        for peer in recipients.iter().filter(|peer| context.is_connected(peer)) {
+            if let Err(error) = protocol::send(engine, context, peer, &payload, logger).await {
+                logger.log_error(&format!("Error sending change to {}: {}", peer, error));
+            }
+        }
+
+        let us = context.id.clone();
+        if let Some(chat) = context.chat_mut(chat_id) {
+            match text {
+                Some(text) => chat.edit(&us, message_id, text),
+                None => chat.delete(&us, message_id),
+            };
+        }
+    }
+
+    /// Set how long messages in a chat last, and tell whoever's in it, so the timer is
+    /// the same on both sides. It applies to messages from then on.
+    async fn set_ttl(
+        chat_id: &ChatId,
+        ttl: Option<Duration>,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let (peers, room) = match chat_id {
+            ChatId::Peer(id) if !context.is_connected(id) => {
+                logger.log_error(&format!(
+                    "Not connected to {}; the timer can only be changed while connected",
+                    id
+                ));
+                return;
+            }
+            ChatId::Peer(id) => (vec![id.clone()], None),
+            ChatId::Room(room_id) => match context.rooms.get(room_id) {
+                Some(room) => (room.peers(&context.id), Some(*room_id)),
+                None => return,
+            },
+        };
+        let payload = Payload::Ttl {
+            seconds: ttl.map(|ttl| ttl.as_secs()),
+            room,
+        };
+        for peer in peers.iter().filter(|peer| context.is_connected(peer)) {
+            if let Err(error) = protocol::send(engine, context, peer, &payload, logger).await {
+                logger.log_error(&format!("Error sending timer to {}: {}", peer, error));
+                // The peer has to have the same timer, but a room can go ahead without
+                // one member
+                if room.is_none() {
+                    return;
+                }
+            }
+        }
+        if let Some(chat) = context.chat_mut(chat_id) {
+            // The peer says when they've changed their timer; a room doesn't wait
+            match room {
+                Some(_) => chat.set_ttl(ttl),
+                None => chat.request_ttl(ttl),
+            }
+        }
+        match (ttl, room) {
+            (Some(ttl), Some(_)) => logger.log_info(&format!(
+                "Messages in this chat now disappear after {}",
+                format_duration(ttl)
+            )),
+            (None, Some(_)) => logger.log_info("Messages in this chat no longer disappear"),
+            (Some(ttl), None) => logger.log_info(&format!(
+                "Asked for messages in this chat to disappear after {}",
+                format_duration(ttl)
+            )),
+            (None, None) => logger.log_info("Asked for messages in this chat to stop disappearing"),
+        }
+    }
+
+    /// Show the safety number for a chat, or mark the contact as verified or not
+    fn verify(
+        id: &TorServiceId,
+        action: VerifyAction,
+        context: &mut AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        match action {
+            VerifyAction::Show => match contacts::safety_number(&context.id, id) {
+                Ok(number) => {
+                    logger.log_info(&format!("Safety number with {}: {}", id, number));
+                    logger.log_info(
+                        "Compare it with theirs in person or over another channel you trust; if they match, type '/verify confirm'",
+                    );
+                }
+                Err(error) => logger.log_error(&format!("Error making safety number: {}", error)),
+            },
+            VerifyAction::Confirm | VerifyAction::Clear => {
+                let verified = action == VerifyAction::Confirm;
+                if verified {
+                    if let Some(nickname) = context.contacts.nickname(id) {
+                        Self::warn_nickname_taken(nickname, id, context, logger);
+                    }
+                }
+                context.contacts.set_verified(id, verified);
+                if let Err(error) = context.contacts.save() {
+                    logger.log_error(&format!("Error saving contacts: {}", error));
+                }
+                if verified {
+                    logger.log_info(&format!("Marked {} as verified", id));
+                } else {
+                    logger.log_info(&format!("{} is no longer marked as verified", id));
+                }
+            }
+        }
+    }
+
+    /// Give a contact a nickname, or take it away
+    fn set_nickname(
+        id: &TorServiceId,
+        nickname: Option<String>,
+        context: &mut AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        if let Some(nickname) = &nickname {
+            Self::warn_nickname_taken(nickname, id, context, logger);
+        }
+        match &nickname {
+            Some(nickname) => logger.log_info(&format!("{} is now called '{}'", id, nickname)),
+            None => logger.log_info(&format!("{} no longer has a nickname", id)),
+        }
+        context.contacts.set_nickname(id, nickname);
+        if let Err(error) = context.contacts.save() {
+            logger.log_error(&format!("Error saving contacts: {}", error));
+        }
+    }
+
+    /// Warn if a verified contact already has `nickname`, since `id` is a different key
+    /// and could be someone passing themselves off as that contact
+    fn warn_nickname_taken(
+        nickname: &str,
+        id: &TorServiceId,
+        context: &AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        if let Some(verified) = context.contacts.verified_with_nickname(nickname, id) {
+            logger.log_error(&format!(
+                "WARNING: '{}' is the nickname of verified contact {}, but {} is a DIFFERENT KEY. It may be someone pretending to be them; check the safety number with '/verify' before trusting it",
+                nickname, verified, id
+            ));
+        }
+    }
+
+    /// Write the messages in a chat to a file
+    fn export_chat(
+        chat_id: &ChatId,
+        path: &Path,
+        format: Option<ExportFormat>,
+        context: &AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        let title = match chat_id {
+            ChatId::Peer(id) => format!("Chat with {}", id),
+            ChatId::Room(room_id) => match context.rooms.get(room_id) {
+                Some(room) => format!("Room '{}'", room.name),
+                None => return,
+            },
+        };
+        let messages = match context.chat(chat_id) {
+            Some(chat) => export::chat_messages(chat),
+            None => return,
+        };
+        let format = format.unwrap_or_else(|| ExportFormat::for_path(path));
+        match export::export(path, format, &title, &messages) {
+            Ok(()) => logger.log_warning(&export::warning(messages.len(), path)),
+            Err(error) => logger.log_error(&format!("Error exporting chat: {}", error)),
+        }
+    }
+
+    /// Invite a peer we're connected to into a room. They're told who else is in it, and
+    /// connect to them if they accept. The members we're connected to are told they were
+    /// invited, so they let them in.
+    async fn invite(
+        room_id: RoomId,
+        address: &OnionAddress,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let id = match TorServiceId::from_str(&address.service_id) {
+            Ok(id) => id,
+            Err(error) => {
+                logger.log_error(&format!("Bad address '{}': {}", address, error));
+                return;
+            }
+        };
+        if !context.is_connected(&id) {
+            logger.log_error(&format!(
+                "Not connected to {}; connect to them before inviting them",
+                id
+            ));
+            return;
+        }
+        let room = match context.rooms.get_mut(&room_id) {
+            Some(room) => room,
+            None => return,
+        };
+        if room.is_member(&id) {
+            logger.log_error(&format!("{} is already in '{}'", id, room.name));
+            return;
+        }
+        let name = room.name.clone();
+        let members = room.peers(&context.id);
+        let payload = Payload::RoomInvite {
+            room: room_id,
+            name: name.clone(),
+            members: room.wire_members(),
+        };
+        if let Err(error) = protocol::send(engine, context, &id, &payload, logger).await {
+            logger.log_error(&format!("Error inviting {}: {}", id, error));
+            return;
+        }
+        if let Some(room) = context.rooms.get_mut(&room_id) {
+            room.invite(&id);
+        }
+        let invited = Payload::RoomInvited {
+            room: room_id,
+            id: id.as_str().to_string(),
+        };
+        for member in members.iter().filter(|member| context.is_connected(member)) {
+            if let Err(error) = protocol::send(engine, context, member, &invited, logger).await {
+                logger.log_error(&format!(
+                    "Error telling {} about the invitation: {}",
+                    member, error
+                ));
+            }
+        }
+        logger.log_info(&format!("Invited {} to '{}'", id, name));
+    }
+
+    /// Leave a room, letting the members we're connected to know
+    async fn leave_room(
+        room_id: RoomId,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        let peers = match context.rooms.get(&room_id) {
+            Some(room) => room.peers(&context.id),
+            None => return,
+        };
+        let payload = Payload::RoomLeave { room: room_id };
+        for peer in peers.iter().filter(|peer| context.is_connected(peer)) {
+            if let Err(error) = protocol::send(engine, context, peer, &payload, logger).await {
+                logger.log_debug(&format!("Error telling {} we've left: {}", peer, error));
+            }
+        }
+        context.remove_room(room_id);
+    }
+
+    /// Offer a file to the peer. It's sent once they accept it.
+    async fn send_file(
+        id: &TorServiceId,
+        path: &Path,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if !context.is_connected(id) {
+            logger.log_error(&format!(
+                "Not connected to {}; files can only be sent while connected",
+                id
+            ));
+            return;
+        }
+        let transfer_id = match context.transfers.send_file(id, path) {
+            Ok(transfer_id) => transfer_id,
+            Err(error) => {
+                logger.log_error(&format!("Can't send {}: {}", path.display(), error));
+                return;
+            }
+        };
+        let (name, size, payload) = match context.transfers.get(transfer_id) {
+            Some(transfer) => (
+                transfer.name.clone(),
+                transfer.size,
+                Payload::FileOffer {
+                    transfer_id,
+                    name: transfer.name.clone(),
+                    size: transfer.size,
+                    hash: transfer.hash.clone(),
+                },
+            ),
+            None => return,
+        };
+        if let Err(error) = protocol::send(engine, context, id, &payload, logger).await {
+            logger.log_error(&format!("Error offering {} to {}: {}", name, id, error));
+            context.transfers.fail(transfer_id);
+            return;
+        }
+        let text = format!("[file] {} ({})", name, format_size(size));
+        let message = ChatMessage::new(&context.id, id, text);
+        if let Some(chat) = context.chats.get_mut(id) {
+            chat.add_transfer(message, transfer_id, MessageState::Sent);
+        }
+    }
+
+    fn reconnect(id: &TorServiceId, context: &mut AppContext, logger: &mut StandardLogger) {
+        if context.is_connected(id) {
+            logger.log_error(&format!("Already connected to {}", id));
+            return;
+        }
+        let address = match context.chat_states.get_mut(id) {
+            Some(state) => {
+                state.status = ConnectionStatus::Reconnecting;
+                state.next_reconnect = None;
+                state.reconnect_attempts = 0;
+                state.address.clone()
+            }
+            None => None,
+        };
+        match address {
+            Some(address) => {
+                logger.log_info(&format!("Reconnecting to {}", id));
+                context.queue_connect(&address, Some(id.clone()));
+            }
+            None => logger.log_error(&format!(
+                "Don't know the address for {}, since they connected to us; use 'connect <address>'",
+                id
+            )),
+        }
+    }
 }
 
 impl InputHandler for ChatInput {
@@ -46,6 +998,23 @@ impl InputHandler for ChatInput {
             state: _,
         }) = event
         {
+            match self.mode {
+                InputMode::Selecting(selected) => {
+                    self.handle_selecting_key(code, modifiers, selected, context, logger);
+                    return;
+                }
+                InputMode::Deleting(selected) => {
+                    self.handle_deleting_key(code, modifiers, selected, context, engine, logger)
+                        .await;
+                    return;
+                }
+                InputMode::Searching => {
+                    self.handle_searching_key(code, modifiers, context);
+                    return;
+                }
+                _ => {}
+            }
+            let mut changed = false;
             match code {
                 KeyCode::Char(character) => {
                     if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
@@ -57,59 +1026,79 @@ impl InputHandler for ChatInput {
                         }
                     } else if character == 'u' && modifiers.contains(KeyModifiers::CONTROL) {
                         self.input.clear_input_to_cursor();
+                        changed = true;
                     } else if character == 'h' && modifiers.contains(KeyModifiers::CONTROL) {
                         context.toggle_welcome_popup();
                         if context.show_welcome_popup {
                             context.show_command_popup = false;
                         }
+                    } else if character == 'g' && modifiers.contains(KeyModifiers::CONTROL) {
+                        context.show_members = !context.show_members;
+                    } else if character == 'f' && modifiers.contains(KeyModifiers::CONTROL) {
+                        // Start a search command, for whatever's been typed
+                        let input = self.input.get_input();
+                        if !input.starts_with("/search") {
+                            self.input.set_input(&format!("/search {}", input.as_str()));
+                        }
+                    } else if character == 'r' && modifiers.contains(KeyModifiers::CONTROL) {
+                        context.show_raw_text = !context.show_raw_text;
                     } else {
                         self.input.write(character);
+                        changed = true;
                     }
                 }
                 KeyCode::Esc => {
                     if context.show_welcome_popup {
                         context.show_welcome_popup = false;
+                    } else {
+                        self.reset_mode();
+                    }
+                }
+                KeyCode::Up if modifiers == KeyModifiers::CONTROL => {
+                    self.reset_mode();
+                    self.start_select(context);
+                }
+                // Up-arrow with nothing to edit does nothing, rather than complain
+                KeyCode::Up if self.input.is_empty() && self.mode == InputMode::Message => {
+                    let _ = self.start_edit(None, context);
+                }
+                KeyCode::Enter if matches!(self.mode, InputMode::Editing(_)) => {
+                    let mode = std::mem::replace(&mut self.mode, InputMode::Message);
+                    if let (InputMode::Editing(message_id), Some(text), Some(chat_id)) = (
+                        mode,
+                        self.input.reset_input(),
+                        context.chat_list.current().cloned(),
+                    ) {
+                        Self::edit_message(
+                            &chat_id,
+                            message_id,
+                            Some(text.to_string()),
+                            context,
+                            engine,
+                            logger,
+                        )
+                        .await;
                     }
                 }
                 KeyCode::Enter => {
                     if let Some(input) = self.input.reset_input() {
-                        match context.chat_list.current_index() {
-                            Some(_) => {
-                                let id = context.chat_list.current().unwrap().clone();
-                                match context.chats.get_mut(&id) {
-                                    Some(chat) => {
-                                        if let Some(command) = input.strip_prefix('/') {
-                                            match command {
-                                                "quit" => {
-                                                    let _ = engine.disconnect(&id, logger).await;
-                                                    context.chats.remove(&id);
-                                                    context.chat_list.remove(&id);
-                                                }
-                                                _ => logger.log_error(&format!(
-                                                    "Unknown command '{}'",
-                                                    &input[1..]
-                                                )),
-                                            }
-                                        } else {
-                                            let message =
-                                                ChatMessage::new(&context.id, &id, input.clone());
-                                            chat.add_message(message.clone());
-                                            if let Err(error) =
-                                                engine.send_message(message, logger).await
-                                            {
-                                                logger.log_error(&format!(
-                                                    "Error sending chat message: {}",
-                                                    error
-                                                ));
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        logger.log_error("No current chat");
-                                    }
-                                }
+                        self.last_typing_notification = None;
+                        let reply_to = match std::mem::replace(&mut self.mode, InputMode::Message) {
+                            InputMode::Replying(message_id) => Some(message_id),
+                            _ => None,
+                        };
+                        match context.chat_list.current().cloned() {
+                            Some(ChatId::Peer(id)) if context.chats.contains_key(&id) => {
+                                self.handle_line(&id, input, reply_to, context, engine, logger)
+                                    .await;
                             }
-                            None => {
+                            Some(ChatId::Room(room_id)) => {
+                                self.handle_room_line(
+                                    room_id, input, reply_to, context, engine, logger,
+                                )
+                                .await;
+                            }
+                            _ => {
                                 logger.log_error("No current chat");
                             }
                         }
@@ -117,12 +1106,15 @@ impl InputHandler for ChatInput {
                 }
                 KeyCode::Delete => {
                     self.input.remove();
+                    changed = true;
                 }
                 KeyCode::Backspace => {
                     self.input.remove_previous();
+                    changed = true;
                 }
                 KeyCode::Left => {
                     if modifiers == KeyModifiers::CONTROL {
+                        self.reset_mode();
                         context.chat_list.prev_chat();
                     } else {
                         self.input.move_cursor(CursorMovement::Left);
@@ -130,6 +1122,7 @@ impl InputHandler for ChatInput {
                 }
                 KeyCode::Right => {
                     if modifiers == KeyModifiers::CONTROL {
+                        self.reset_mode();
                         context.chat_list.next_chat();
                     } else {
                         self.input.move_cursor(CursorMovement::Right);
@@ -143,6 +1136,9 @@ impl InputHandler for ChatInput {
                 }
                 _ => {}
             }
+            if changed {
+                self.notify_typing(context, engine, logger).await;
+            }
         }
     }
 }
@@ -1,62 +1,280 @@
 use crate::{
     app::InputHandler,
     app_context::AppContext,
-    commands::Command,
+    client_auth,
+    commands::{AuthAction, Command},
+    export::{self, ExportFormat},
     input::{CursorMovement, Input},
+    storage,
 };
+use anyhow::{anyhow, Result};
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use std::str::FromStr;
+use tor_client_lib::TorServiceId;
 use voynich::{
     engine::Engine,
     logger::{Logger, StandardLogger},
 };
+use zeroize::Zeroizing;
+
+/// Where we are in asking for a new passphrase
+#[derive(Debug)]
+enum PassphraseEntry {
+    New,
+    /// Asking for it again, to check it was typed right the first time
+    Repeat(Zeroizing<String>),
+}
 
 #[derive(Debug)]
 pub struct CommandInput {
     input: Input,
+    error: Option<String>,
+    /// Set while the command window is being used to type a new passphrase
+    passphrase_entry: Option<PassphraseEntry>,
 }
 
 impl CommandInput {
     pub fn new() -> Self {
         Self {
             input: Input::new(Some(":> ")),
+            error: None,
+            passphrase_entry: None,
         }
     }
 
-    pub fn get_input(&self) -> String {
-        self.input.get_input()
+    pub fn wipe(&mut self) {
+        self.input.wipe();
+        self.passphrase_entry = None;
+    }
+
+    /// The title for the command window
+    pub fn title(&self) -> &str {
+        match self.passphrase_entry {
+            Some(PassphraseEntry::New) => "New Passphrase",
+            Some(PassphraseEntry::Repeat(_)) => "Repeat Passphrase",
+            None => "Command Input",
+        }
+    }
+
+    /// The error from the last command, shown in the command popup
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn get_input(&self) -> Zeroizing<String> {
+        match self.passphrase_entry {
+            Some(_) => Zeroizing::new(self.input.get_masked_input()),
+            None => self.input.get_input(),
+        }
     }
 
     pub fn cursor_location(&self, width: usize) -> (u16, u16) {
         self.input.cursor_location(width)
     }
 
-    pub async fn handle_command(
+    pub fn handle_command(
         &mut self,
         context: &mut AppContext,
         logger: &mut StandardLogger,
         command: Command,
-        engine: &mut Engine,
-    ) {
+    ) -> Result<()> {
         match command {
-            Command::Connect { address } => {
-                if let Err(error) = engine.connect(&address).await {
-                    logger.log_error(&format!("Connect error: {}", error));
+            Command::Connect {
+                address,
+                client_auth_key,
+            } => {
+                if address.service_id == context.id.as_str() {
+                    return Err(anyhow!("{} is our own onion address", address));
+                }
+                if let Some(key) = client_auth_key {
+                    context
+                        .client_auth_keys
+                        .push((address.service_id.clone(), key));
+                }
+                let address = address.to_connect_string(context.service_port())?;
+                logger.log_info(&format!("Connecting to {}", address));
+                context.queue_connect(&address, None);
+            }
+            Command::Chat { address } => {
+                if address.service_id == context.id.as_str() {
+                    return Err(anyhow!("{} is our own onion address", address));
+                }
+                let id = TorServiceId::from_str(&address.service_id)?;
+                if context.chats.contains_key(&id) {
+                    return Err(anyhow!("There's already a chat with {}", id));
+                }
+                context.open_chat(&id, address.to_connect_string(context.service_port()).ok());
+                logger.log_info(&format!(
+                    "Opened a chat with {}; messages will be delivered once you're connected",
+                    id
+                ));
+            }
+            Command::Cancel { address } => {
+                let service_id = address.as_ref().map(|address| address.service_id.as_str());
+                match context.cancel_connects(service_id) {
+                    0 => logger.log_error("No matching connection attempts to cancel"),
+                    count => logger.log_info(&format!(
+                        "Cancelled {} connection attempt{}",
+                        count,
+                        if count == 1 { "" } else { "s" }
+                    )),
                 }
             }
+            Command::Room { name } => {
+                context.create_room(&name);
+                logger.log_info(&format!(
+                    "Created room '{}'; type '/invite <onion-address>' in it to add people",
+                    name
+                ));
+            }
+            Command::Export { path, format } => {
+                let messages = export::log_messages(logger);
+                let format = format.unwrap_or_else(|| ExportFormat::for_path(&path));
+                export::export(&path, format, "System messages", &messages)?;
+                logger.log_warning(&export::warning(messages.len(), &path));
+            }
+            Command::Block { address: None } => {
+                let blocked = context.contacts.blocked();
+                if blocked.is_empty() {
+                    logger.log_info("No one is blocked");
+                } else {
+                    logger.log_info(&format!("Blocked: {}", blocked.join(", ")));
+                }
+            }
+            Command::Block {
+                address: Some(address),
+            } => {
+                if address.service_id == context.id.as_str() {
+                    return Err(anyhow!("{} is our own onion address", address));
+                }
+                let id = TorServiceId::from_str(&address.service_id)?;
+                context.contacts.set_blocked(&id, true)?;
+                logger.log_info(&format!(
+                    "Blocked {}; connection attempts from them will be dropped",
+                    id
+                ));
+            }
+            Command::Unblock { address } => {
+                let id = TorServiceId::from_str(&address.service_id)?;
+                if !context.contacts.is_blocked(&id) {
+                    return Err(anyhow!("{} isn't blocked", id));
+                }
+                context.contacts.set_blocked(&id, false)?;
+                logger.log_info(&format!("Unblocked {}", id));
+            }
+            Command::Auth { action } => Self::handle_auth(action, context, logger)?,
+            Command::Passphrase { remove: false } => {
+                self.passphrase_entry = Some(PassphraseEntry::New);
+            }
+            Command::Passphrase { remove: true } => {
+                storage::set_passphrase(None)?;
+                logger.log_warning("Passphrase removed; your saved files are no longer encrypted");
+            }
+            Command::Lock => {
+                if !storage::is_unlocked() {
+                    return Err(anyhow!(
+                        "Set a passphrase with 'passphrase change' first; it's what unlocks the screen"
+                    ));
+                }
+                context.lock();
+            }
+            Command::Panic => {
+                context.panicking = true;
+            }
             Command::Quit => {
                 context.should_quit = true;
             }
         }
+        Ok(())
     }
-}
 
-impl InputHandler for CommandInput {
-    async fn handle_input_event(
+    /// Manage the clients allowed to connect to our onion service. Tor only takes them
+    /// when the service is set up, so changes apply from the next start.
+    fn handle_auth(
+        action: AuthAction,
+        context: &mut AppContext,
+        logger: &mut StandardLogger,
+    ) -> Result<()> {
+        match action {
+            AuthAction::Add { name, public_key } => {
+                context.authorized_clients.add(&name, &public_key)?;
+                logger.log_info(&format!(
+                    "Authorized client '{}'; this applies to persistent onion services from the next start, after which only authorized clients can connect",
+                    name
+                ));
+            }
+            AuthAction::Remove { name } => {
+                if !context.authorized_clients.remove(&name)? {
+                    return Err(anyhow!("There's no authorized client '{}'", name));
+                }
+                logger.log_info(&format!(
+                    "Removed client '{}'; this applies from the next start",
+                    name
+                ));
+            }
+            AuthAction::List => {
+                if context.authorized_clients.is_empty() {
+                    logger.log_info("No authorized clients; anyone with the address can connect");
+                }
+                for (name, public_key) in context.authorized_clients.iter() {
+                    logger.log_info(&format!("Authorized client '{}': {}", name, public_key));
+                }
+            }
+            AuthAction::Keygen => {
+                context.client_keypair = Some(client_auth::generate_keypair());
+                context.show_command_popup = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a passphrase typed into the command window, the first time or again to
+    /// check it
+    fn handle_passphrase(
+        &mut self,
+        passphrase: Zeroizing<String>,
+        context: &mut AppContext,
+        logger: &mut StandardLogger,
+    ) {
+        match self.passphrase_entry.take() {
+            Some(PassphraseEntry::New) if passphrase.is_empty() => {
+                self.error = Some("The passphrase can't be empty".to_string());
+                self.passphrase_entry = Some(PassphraseEntry::New);
+            }
+            Some(PassphraseEntry::New) => {
+                self.error = None;
+                self.passphrase_entry = Some(PassphraseEntry::Repeat(passphrase));
+            }
+            Some(PassphraseEntry::Repeat(first)) if *first != *passphrase => {
+                self.error =
+                    Some("The passphrases don't match; type the new one again".to_string());
+                self.passphrase_entry = Some(PassphraseEntry::New);
+            }
+            Some(PassphraseEntry::Repeat(_)) => match storage::set_passphrase(Some(&passphrase)) {
+                Ok(()) => {
+                    self.error = None;
+                    context.show_command_popup = false;
+                    logger.log_info("Passphrase set; your saved files are encrypted with it");
+                }
+                Err(error) => self.error = Some(format!("Error setting passphrase: {}", error)),
+            },
+            None => {}
+        }
+    }
+
+    /// Stop asking for a passphrase, and forget what's been typed
+    fn cancel_passphrase(&mut self) {
+        if self.passphrase_entry.take().is_some() {
+            self.input.reset_input();
+        }
+    }
+
+    /// Handle an input event. None of the commands need the engine, so this can be
+    /// used while the engine is busy making a connection.
+    pub fn handle_event(
         &mut self,
         event: Event,
         context: &mut AppContext,
-        engine: &mut Engine,
         logger: &mut StandardLogger,
     ) {
         if let Event::Key(KeyEvent {
@@ -72,6 +290,8 @@ impl InputHandler for CommandInput {
                         context.should_quit = true;
                     } else if character == 'k' && modifiers.contains(KeyModifiers::CONTROL) {
                         context.toggle_command_popup();
+                        self.error = None;
+                        self.cancel_passphrase();
                         if context.show_command_popup {
                             context.show_welcome_popup = false;
                         }
@@ -88,16 +308,26 @@ impl InputHandler for CommandInput {
                 }
                 KeyCode::Esc => {
                     context.show_command_popup = false;
+                    self.error = None;
+                    self.cancel_passphrase();
                 }
                 KeyCode::Enter => {
-                    if let Some(input) = self.input.reset_input() {
-                        context.toggle_command_popup();
-                        match Command::from_str(&input) {
-                            Ok(command) => {
-                                self.handle_command(context, logger, command, engine).await;
+                    if self.passphrase_entry.is_some() {
+                        let passphrase = self.input.reset_input().unwrap_or_default();
+                        self.handle_passphrase(passphrase, context, logger);
+                    } else if let Some(input) = self.input.reset_input() {
+                        match Command::from_str(&input)
+                            .and_then(|command| self.handle_command(context, logger, command))
+                        {
+                            Ok(()) => {
+                                self.error = None;
+                                // Asking for a passphrase keeps the window up
+                                context.show_command_popup = self.passphrase_entry.is_some();
                             }
                             Err(error) => {
-                                logger.log_error(&format!("Error parsing command: {}", error));
+                                // Leave the popup up so the command can be fixed
+                                self.input.set_input(&input);
+                                self.error = Some(error.to_string());
                             }
                         }
                     }
@@ -125,3 +355,15 @@ impl InputHandler for CommandInput {
         }
     }
 }
+
+impl InputHandler for CommandInput {
+    async fn handle_input_event(
+        &mut self,
+        event: Event,
+        context: &mut AppContext,
+        _engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        self.handle_event(event, context, logger);
+    }
+}
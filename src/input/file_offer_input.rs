@@ -0,0 +1,109 @@
+use crate::{
+    app::InputHandler,
+    app_context::AppContext,
+    protocol::{self, Payload},
+    transfer::{format_size, TransferStatus},
+};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use voynich::{
+    engine::Engine,
+    logger::{Logger, StandardLogger},
+};
+
+#[derive(Debug)]
+pub struct FileOfferInput {}
+
+impl FileOfferInput {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl InputHandler for FileOfferInput {
+    async fn handle_input_event(
+        &mut self,
+        event: Event,
+        context: &mut AppContext,
+        engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: _,
+            state: _,
+        }) = event
+        {
+            let (transfer_id, accept_selected) = match context.file_offers.front() {
+                Some(offer) => (offer.transfer_id, offer.accept_selected),
+                None => return,
+            };
+            match code {
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    context.should_quit = true;
+                }
+                KeyCode::Esc => {
+                    context.file_offers.front_mut().unwrap().accept_selected = false;
+                }
+                KeyCode::Enter => {
+                    context.file_offers.pop_front();
+                    let (peer, name, size) = match context.transfers.get(transfer_id) {
+                        Some(transfer) => {
+                            (transfer.peer.clone(), transfer.name.clone(), transfer.size)
+                        }
+                        None => return,
+                    };
+                    let payload = if accept_selected {
+                        match context.transfers.accept(transfer_id) {
+                            Ok(offset) => {
+                                if offset > 0 {
+                                    logger.log_info(&format!(
+                                        "Resuming {} from {}, {} of {} already received",
+                                        name,
+                                        peer,
+                                        format_size(offset),
+                                        format_size(size)
+                                    ));
+                                } else {
+                                    logger.log_info(&format!("Receiving {} from {}", name, peer));
+                                }
+                                Payload::FileAccept {
+                                    transfer_id,
+                                    offset,
+                                }
+                            }
+                            Err(error) => {
+                                logger.log_error(&format!("Can't receive {}: {}", name, error));
+                                context.transfers.fail(transfer_id);
+                                Payload::FileReject {
+                                    transfer_id,
+                                    reason: Some(error.to_string()),
+                                }
+                            }
+                        }
+                    } else {
+                        context
+                            .transfers
+                            .set_status(&peer, transfer_id, TransferStatus::Rejected);
+                        Payload::FileReject {
+                            transfer_id,
+                            reason: None,
+                        }
+                    };
+                    if let Err(error) =
+                        protocol::send(engine, context, &peer, &payload, logger).await
+                    {
+                        logger.log_error(&format!(
+                            "Error answering file offer from {}: {}",
+                            peer, error
+                        ));
+                    }
+                }
+                KeyCode::Left | KeyCode::Right | KeyCode::Tab => {
+                    context.file_offers.front_mut().unwrap().accept_selected = !accept_selected;
+                }
+                _ => {}
+            }
+        }
+    }
+}
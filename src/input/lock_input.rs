@@ -0,0 +1,96 @@
+use crate::{
+    app::InputHandler,
+    app_context::AppContext,
+    input::{CursorMovement, Input},
+    storage,
+};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use voynich::{
+    engine::Engine,
+    logger::{Logger, StandardLogger},
+};
+
+/// Takes the passphrase on the lock screen
+#[derive(Debug)]
+pub struct LockInput {
+    input: Input,
+    error: Option<String>,
+}
+
+impl LockInput {
+    pub fn new() -> Self {
+        Self {
+            input: Input::new(None),
+            error: None,
+        }
+    }
+
+    pub fn wipe(&mut self) {
+        self.input.wipe();
+    }
+
+    /// What's been typed, hidden
+    pub fn get_masked_input(&self) -> String {
+        self.input.get_masked_input()
+    }
+
+    /// Why the last passphrase didn't unlock the screen
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+impl InputHandler for LockInput {
+    async fn handle_input_event(
+        &mut self,
+        event: Event,
+        context: &mut AppContext,
+        _engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: _,
+            state: _,
+        }) = event
+        {
+            match code {
+                KeyCode::Char(character) => {
+                    if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
+                        context.should_quit = true;
+                    } else if character == 'u' && modifiers.contains(KeyModifiers::CONTROL) {
+                        self.input.clear_input_to_cursor();
+                    } else {
+                        self.input.write(character);
+                    }
+                }
+                KeyCode::Enter => {
+                    let passphrase = self.input.reset_input().unwrap_or_default();
+                    match storage::check_passphrase(&passphrase) {
+                        Ok(()) => {
+                            self.error = None;
+                            for notification in context.unlock() {
+                                logger.log_info(&format!("While locked: {}", notification));
+                            }
+                        }
+                        Err(error) => self.error = Some(error.to_string()),
+                    }
+                }
+                KeyCode::Backspace => {
+                    self.input.remove_previous();
+                }
+                KeyCode::Delete => {
+                    self.input.remove();
+                }
+                KeyCode::Left => {
+                    self.input.move_cursor(CursorMovement::Left);
+                }
+                KeyCode::Right => {
+                    self.input.move_cursor(CursorMovement::Right);
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{app::InputHandler, app_context::AppContext};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use voynich::{
+    engine::Engine,
+    logger::{Logger, StandardLogger},
+};
+
+#[derive(Debug)]
+pub struct RoomInviteInput {}
+
+impl RoomInviteInput {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl InputHandler for RoomInviteInput {
+    async fn handle_input_event(
+        &mut self,
+        event: Event,
+        context: &mut AppContext,
+        _engine: &mut Engine,
+        logger: &mut StandardLogger,
+    ) {
+        if let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: _,
+            state: _,
+        }) = event
+        {
+            let accept_selected = match context.room_invites.front() {
+                Some(invite) => invite.accept_selected,
+                None => return,
+            };
+            match code {
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    context.should_quit = true;
+                }
+                KeyCode::Esc => {
+                    context.room_invites.front_mut().unwrap().accept_selected = false;
+                }
+                KeyCode::Enter => {
+                    let invite = context.room_invites.pop_front().unwrap();
+                    if !accept_selected {
+                        logger.log_info(&format!(
+                            "Turned down {}'s invitation to '{}'",
+                            invite.inviter, invite.name
+                        ));
+                        return;
+                    }
+                    context.join_room(invite.room, &invite.name, invite.members.clone());
+                    logger.log_info(&format!("Joined '{}'", invite.name));
+
+                    // Tell the members we're connected to that we're in, and connect to the rest
+                    let us = context.id.clone();
+                    for member in invite.members.iter().filter(|member| member.id != us) {
+                        if context.chats.contains_key(&member.id) {
+                            if context.is_connected(&member.id) {
+                                context.room_announcements.push(member.id.clone());
+                            }
+                        } else if let Some(address) = &member.address {
+                            logger.log_info(&format!(
+                                "Connecting to {}, who's in '{}'",
+                                member.id, invite.name
+                            ));
+                            context.queue_connect(address, None);
+                        }
+                    }
+                }
+                KeyCode::Left | KeyCode::Right | KeyCode::Tab => {
+                    context.room_invites.front_mut().unwrap().accept_selected = !accept_selected;
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,20 +1,60 @@
-use crate::{app::App, cli::Cli};
+use crate::{
+    app::App,
+    cli::{Cli, OnionServiceType},
+    client_auth::AuthorizedClients,
+    config::TermConfig,
+    term::Term,
+    tor_control::{create_onion_service, TorControl},
+    tor_process::{BootstrapStatus, TorProcess},
+    widgets::bootstrap_screen::BootstrapScreen,
+};
+use anyhow::{anyhow, Result};
 use clap::Parser;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use futures::StreamExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use voynich::config::TorAuthConfig;
 use voynich::logger::{Level, Logger, StandardLogger};
-use voynich::{
-    connect_to_tor, create_onion_service, get_config, test_onion_service_connection, Engine,
-};
+use voynich::{get_config, test_onion_service_connection, Engine};
+use zeroize::Zeroizing;
 
 mod app;
 mod app_context;
+mod chat;
 mod cli;
+mod client_auth;
 mod commands;
+mod config;
+mod contacts;
+mod encryption;
+mod export;
 mod input;
+mod markup;
+mod onion_address;
+mod outbox;
+mod protocol;
+mod rate_limit;
+mod room;
 mod root;
+mod search;
+mod secret;
+mod storage;
 mod term;
 mod theme;
+mod tor_control;
+mod tor_process;
+mod tor_socket;
+mod transfer;
 mod widgets;
 
+/// How many times to ask for the passphrase before giving up
+const PASSPHRASE_ATTEMPTS: u32 = 3;
+
+/// How long a tor we start has to connect to the network
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(180);
+
 #[tokio::main]
 async fn main() {
     // Parse the CLI
@@ -27,27 +67,109 @@ async fn main() {
         }
     };
 
+    let term_config = match TermConfig::load() {
+        Ok(term_config) => term_config.update(&cli),
+        Err(error) => {
+            eprintln!("Error reading configuration: {}", error);
+            return;
+        }
+    };
+
+    // Unlock our files, if they're encrypted
+    if let Err(error) = unlock_storage() {
+        eprintln!("Error unlocking saved files: {}", error);
+        return;
+    }
+
     // Logging
     let mut logger = StandardLogger::new(500);
     if config.system.debug {
         logger.set_log_level(Level::Debug);
     }
 
-    // Get a connection to Tor
-    let mut control_connection = match connect_to_tor(
-        config.tor.control_address,
-        config.tor.authentication,
-        config.tor.hashed_password,
-        config.tor.cookie,
-    )
-    .await
-    {
+    // Start our own tor, if there's no system Tor to use
+    let tor_process = if term_config.spawn_tor {
+        match start_tor(&term_config.tor_binary).await {
+            Ok(tor_process) => {
+                logger.log_info("Started a tor of our own; it stops when voynich-term does");
+                Some(tor_process)
+            }
+            Err(error) => {
+                eprintln!("Error starting tor: {}", error);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    // Our own tor uses cookie authentication, with the cookie in its data directory
+    let (authentication, hashed_password, cookie) = match &tor_process {
+        Some(_) => (Some(TorAuthConfig::SafeCookie), None, None),
+        None => (
+            config.tor.authentication,
+            config.tor.hashed_password,
+            config.tor.cookie,
+        ),
+    };
+
+    // Get a connection to Tor, through its control socket if it's set up with one
+    let control_connection = match (&tor_process, &term_config.tor_control_socket) {
+        (Some(tor_process), _) => match tor_process.control_address() {
+            Ok(address) => TorControl::connect(address).await,
+            Err(error) => Err(error),
+        },
+        (None, Some(path)) => TorControl::connect_unix(path).await,
+        (None, None) => TorControl::connect(config.tor.control_address).await,
+    };
+    let mut control_connection = match control_connection {
         Ok(connection) => connection,
         Err(error) => {
             eprintln!("Error connecting to Tor control connection: {}", error);
             return;
         }
     };
+    if let Err(error) = control_connection
+        .authenticate(authentication, hashed_password, cookie)
+        .await
+    {
+        eprintln!("Error authenticating to Tor control connection: {}", error);
+        return;
+    }
+
+    // Give Tor the keys for the protected services we're going to connect to
+    for key in &cli.client_auth_key {
+        if let Err(error) = control_connection
+            .add_client_auth(&key.service_id, &key.private_key)
+            .await
+        {
+            eprintln!(
+                "Error adding client authorization for {}: {}",
+                key.service_id, error
+            );
+            return;
+        }
+    }
+
+    // The proxy every connection to a peer goes through. Our own tor picked its port,
+    // so it's asked where it is; a Unix socket is bridged to TCP.
+    let proxy_address = match (&tor_process, &term_config.tor_proxy_socket) {
+        (Some(_), _) => match control_connection.socks_address().await {
+            Ok(address) => address,
+            Err(error) => {
+                eprintln!("Error finding the proxy of our tor: {}", error);
+                return;
+            }
+        },
+        (None, Some(path)) => match tor_socket::bridge(path).await {
+            Ok(address) => address,
+            Err(error) => {
+                eprintln!("Error connecting to Tor proxy socket: {}", error);
+                return;
+            }
+        },
+        (None, None) => config.tor.proxy_address,
+    };
 
     let onion_type = match cli.get_onion_type() {
         Ok(onion_type) => onion_type,
@@ -57,12 +179,28 @@ async fn main() {
         }
     };
 
+    // Only let authorized clients in, if there are any. Transient services are new each
+    // time, so nobody could have been authorized for them.
+    let transient_service = matches!(cli.onion_type, OnionServiceType::Transient);
+    let clients = if transient_service {
+        AuthorizedClients::default()
+    } else {
+        match AuthorizedClients::load() {
+            Ok(clients) => clients,
+            Err(error) => {
+                eprintln!("Error loading authorized clients: {}", error);
+                return;
+            }
+        }
+    };
+
     // Create our onion service
     let (mut onion_service, onion_service_address, mut listener) = match create_onion_service(
         &mut control_connection,
         onion_type,
         cli.service_port,
         cli.listen_address,
+        &clients,
     )
     .await
     {
@@ -77,26 +215,23 @@ async fn main() {
 
     // Test our onion service
     if config.system.connection_test {
-        listener = match test_onion_service_connection(
-            listener,
-            &config.tor.proxy_address,
-            &onion_service_address,
-        )
-        .await
-        {
-            Ok(listener) => listener,
-            Err(error) => {
-                eprintln!("Error testing onion service connection: {}", error);
-                return;
+        listener =
+            match test_onion_service_connection(listener, &proxy_address, &onion_service_address)
+                .await
+            {
+                Ok(listener) => listener,
+                Err(error) => {
+                    eprintln!("Error testing onion service connection: {}", error);
+                    return;
+                }
             }
-        }
     };
 
     // Set up the engine
     let mut engine = match Engine::new(
         &mut onion_service,
         onion_service_address,
-        config.tor.proxy_address,
+        proxy_address,
         config.system.debug,
     )
     .await
@@ -109,5 +244,87 @@ async fn main() {
     };
 
     // Start 'er up
-    let _ = App::run(&mut engine, &listener, &mut logger).await;
+    if let Err(error) = App::run(
+        &mut engine,
+        &listener,
+        &mut control_connection,
+        transient_service,
+        term_config,
+        &mut logger,
+    )
+    .await
+    {
+        eprintln!("Error: {}", error);
+    }
+
+    if let Some(tor_process) = tor_process {
+        if let Err(error) = tor_process.stop().await {
+            eprintln!("Error stopping tor: {}", error);
+        }
+    }
+}
+
+/// Start a tor of our own, and wait for it to connect to the network, showing how it's
+/// going
+async fn start_tor(binary: &Path) -> Result<TorProcess> {
+    let mut tor_process = TorProcess::spawn(binary)?;
+    let mut term = Term::start()?;
+    let deadline = Instant::now() + BOOTSTRAP_TIMEOUT;
+    let mut status = BootstrapStatus::default();
+    while !status.is_done() {
+        term.draw(|frame| frame.render_widget(BootstrapScreen::new(&status), frame.size()))?;
+        tokio::select! {
+            next = tor_process.next_status() => status = next?,
+            Some(Ok(event)) = term.next() => {
+                if is_give_up_key(&event) {
+                    return Err(anyhow!("Gave up waiting for tor"));
+                }
+            }
+            _ = sleep_until(deadline) => {
+                return Err(anyhow!(
+                    "tor didn't connect to the network in {} seconds; it got to {}%: {}",
+                    BOOTSTRAP_TIMEOUT.as_secs(),
+                    status.percent,
+                    status.summary
+                ));
+            }
+        }
+    }
+    Ok(tor_process)
+}
+
+/// Esc or ctrl-c, to stop waiting for tor
+fn is_give_up_key(event: &Event) -> bool {
+    match event {
+        Event::Key(KeyEvent {
+            code: KeyCode::Esc, ..
+        }) => true,
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers,
+            ..
+        }) => modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+/// Ask for the passphrase our files are encrypted with, if there is one
+fn unlock_storage() -> Result<()> {
+    if !storage::has_passphrase()? {
+        return Ok(());
+    }
+    let mut attempts = 0;
+    loop {
+        let passphrase = Zeroizing::new(rpassword::prompt_password("Passphrase: ")?);
+        match storage::unlock(&passphrase) {
+            Ok(()) => return Ok(()),
+            Err(error) => {
+                attempts += 1;
+                if attempts == PASSPHRASE_ATTEMPTS {
+                    return Err(error);
+                }
+                eprintln!("{}", error);
+            }
+        }
+    }
 }
@@ -0,0 +1,295 @@
+use ratatui::prelude::*;
+use zeroize::Zeroizing;
+
+use crate::theme::THEME;
+
+const CODE_FENCE: &str = "```";
+
+/// Render the text of a chat message, styling the lightweight markup in it: *bold*,
+/// _italic_, `code`, ```code blocks```, > quotes and URLs. Anything else is shown as
+/// it was written. Gives the spans for each line of the message, and always at least
+/// one line. Spans borrow from the text where they can, so it isn't copied each time
+/// it's drawn.
+pub fn render(text: &str) -> Vec<Vec<Span<'_>>> {
+    let mut lines = Vec::new();
+    let mut in_code_block = false;
+    for line in text.split('\n') {
+        let trimmed = line.trim();
+        if let Some(rest) = trimmed.strip_prefix(CODE_FENCE) {
+            // A whole block on one line, like ```let x = 1;```
+            if let Some(code) = rest.strip_suffix(CODE_FENCE).filter(|_| !in_code_block) {
+                lines.push(vec![Span::styled(code, THEME.chat_message.code)]);
+            } else {
+                // Whatever follows the opening fence names the language, so it's dropped
+                in_code_block = !in_code_block;
+            }
+        } else if in_code_block {
+            lines.push(vec![Span::styled(line, THEME.chat_message.code)]);
+        } else if let Some(quote) = line.strip_prefix('>') {
+            let mut spans = vec![Span::styled("│ ", THEME.chat_message.quote)];
+            spans.extend(render_inline(
+                quote.strip_prefix(' ').unwrap_or(quote),
+                THEME.chat_message.quote,
+            ));
+            lines.push(spans);
+        } else {
+            lines.push(render_inline(line, Style::default()));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(Vec::new());
+    }
+    lines
+}
+
+/// Render the markup within a line. Every span is a slice of `text`.
+fn render_inline(text: &str, style: Style) -> Vec<Span<'_>> {
+    // Most lines have no markup, and can be shown as they are
+    if !text.contains(['*', '_', '`']) && !text.contains("http") {
+        return match text {
+            "" => Vec::new(),
+            text => vec![Span::styled(text, style)],
+        };
+    }
+    let chars = Zeroizing::new(text.chars().collect::<Vec<_>>());
+    // Where each character starts in `text`, and where the last one ends
+    let offsets = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([text.len()])
+        .collect::<Vec<_>>();
+    let slice = |start: usize, end: usize| &text[offsets[start]..offsets[end]];
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut index = 0;
+    while index < chars.len() {
+        let character = chars[index];
+        let next = if let Some(end) = url_end(&chars, index) {
+            push_plain(&mut spans, slice(plain_start, index), style);
+            spans.push(Span::styled(
+                slice(index, end),
+                style.patch(THEME.chat_message.link),
+            ));
+            end
+        } else if let Some(end) = (character == '`')
+            .then(|| closing(&chars, index, '`'))
+            .flatten()
+        {
+            // Nothing inside a code span is markup
+            push_plain(&mut spans, slice(plain_start, index), style);
+            spans.push(Span::styled(
+                slice(index + 1, end),
+                style.patch(THEME.chat_message.code),
+            ));
+            end + 1
+        } else if let Some(end) = emphasis_end(&chars, index) {
+            push_plain(&mut spans, slice(plain_start, index), style);
+            let modifier = match character {
+                '*' => Modifier::BOLD,
+                _ => Modifier::ITALIC,
+            };
+            spans.extend(render_inline(
+                slice(index + 1, end),
+                style.add_modifier(modifier),
+            ));
+            end + 1
+        } else {
+            index += 1;
+            continue;
+        };
+        plain_start = next;
+        index = next;
+    }
+    push_plain(&mut spans, slice(plain_start, chars.len()), style);
+    spans
+}
+
+/// Add a span of plain text, unless there's none
+fn push_plain<'a>(spans: &mut Vec<Span<'a>>, text: &'a str, style: Style) {
+    if !text.is_empty() {
+        spans.push(Span::styled(text, style));
+    }
+}
+
+/// The index of the next `delimiter` after `start`, if there's something between them
+fn closing(chars: &[char], start: usize, delimiter: char) -> Option<usize> {
+    chars[start + 1..]
+        .iter()
+        .position(|&character| character == delimiter)
+        .filter(|&offset| offset > 0)
+        .map(|offset| start + 1 + offset)
+}
+
+/// If there's *emphasis* or _emphasis_ starting at `start`, the index of its closing
+/// delimiter. Delimiters have to be at the edges of words, so snake_case and 2*3*4
+/// aren't taken as markup.
+fn emphasis_end(chars: &[char], start: usize) -> Option<usize> {
+    let delimiter = chars[start];
+    if delimiter != '*' && delimiter != '_' {
+        return None;
+    }
+    let is_word = |index: usize| chars.get(index).is_some_and(|c| c.is_alphanumeric());
+    if start > 0 && is_word(start - 1) {
+        return None;
+    }
+    if chars.get(start + 1).is_none_or(|c| c.is_whitespace()) {
+        return None;
+    }
+    (start + 2..chars.len()).find(|&index| {
+        chars[index] == delimiter && !chars[index - 1].is_whitespace() && !is_word(index + 1)
+    })
+}
+
+/// If a URL starts at `start`, the index just past its end. Trailing punctuation is
+/// taken to belong to the sentence rather than the URL.
+fn url_end(chars: &[char], start: usize) -> Option<usize> {
+    let starts_with = |prefix: &str| {
+        prefix
+            .chars()
+            .enumerate()
+            .all(|(offset, character)| chars.get(start + offset) == Some(&character))
+    };
+    if !starts_with("https://") && !starts_with("http://") {
+        return None;
+    }
+    if start > 0 && chars[start - 1].is_alphanumeric() {
+        return None;
+    }
+    let mut end = chars[start..]
+        .iter()
+        .position(|character| character.is_whitespace())
+        .map_or(chars.len(), |offset| start + offset);
+    while end > start && matches!(chars[end - 1], '.' | ',' | ';' | ':' | '!' | '?' | ')') {
+        end -= 1;
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn plain() -> Style {
+        Style::default()
+    }
+
+    fn bold() -> Style {
+        plain().add_modifier(Modifier::BOLD)
+    }
+
+    fn italic() -> Style {
+        plain().add_modifier(Modifier::ITALIC)
+    }
+
+    fn code() -> Style {
+        plain().patch(THEME.chat_message.code)
+    }
+
+    /// The text and style of each span in each line
+    fn rendered(text: &str) -> Vec<Vec<(String, Style)>> {
+        render(text)
+            .into_iter()
+            .map(|line| {
+                line.into_iter()
+                    .map(|span| (span.content.into_owned(), span.style))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn spans(expected: &[(&str, Style)]) -> Vec<Vec<(String, Style)>> {
+        vec![expected
+            .iter()
+            .map(|(text, style)| (text.to_string(), *style))
+            .collect()]
+    }
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        assert_eq!(rendered("just words"), spans(&[("just words", plain())]));
+        assert_eq!(rendered(""), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn emphasis_nests() {
+        assert_eq!(
+            rendered("*bold _both_ bold*"),
+            spans(&[
+                ("bold ", bold()),
+                ("both", bold().add_modifier(Modifier::ITALIC)),
+                (" bold", bold()),
+            ])
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_are_shown_as_written() {
+        assert_eq!(rendered("*not bold"), spans(&[("*not bold", plain())]));
+        assert_eq!(rendered("_not italic"), spans(&[("_not italic", plain())]));
+        assert_eq!(rendered("a `b"), spans(&[("a `b", plain())]));
+        assert_eq!(rendered("* spaced *"), spans(&[("* spaced *", plain())]));
+    }
+
+    #[test]
+    fn markers_inside_words_are_not_markup() {
+        assert_eq!(
+            rendered("snake_case_name"),
+            spans(&[("snake_case_name", plain())])
+        );
+        assert_eq!(rendered("2*3*4"), spans(&[("2*3*4", plain())]));
+    }
+
+    #[test]
+    fn multi_byte_characters() {
+        assert_eq!(
+            rendered("*héllo* 世界 _ñ_"),
+            spans(&[("héllo", bold()), (" 世界 ", plain()), ("ñ", italic())])
+        );
+    }
+
+    #[test]
+    fn code_spans_hold_no_markup() {
+        assert_eq!(
+            rendered("run `*not bold*` now"),
+            spans(&[("run ", plain()), ("*not bold*", code()), (" now", plain())])
+        );
+        assert_eq!(rendered("``"), spans(&[("``", plain())]));
+    }
+
+    #[test]
+    fn code_blocks_hold_no_markup() {
+        assert_eq!(
+            rendered("```rust\nlet *x* = 1;\n```\n_after_"),
+            vec![
+                vec![("let *x* = 1;".to_string(), code())],
+                vec![("after".to_string(), italic())],
+            ]
+        );
+        assert_eq!(rendered("```a_b```"), spans(&[("a_b", code())]));
+    }
+
+    #[test]
+    fn spans_borrow_the_text() {
+        let lines = render("> *see* `this` https://example.com _now_");
+        assert!(lines
+            .iter()
+            .flatten()
+            .all(|span| matches!(span.content, Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn urls_leave_trailing_punctuation() {
+        assert_eq!(
+            rendered("see https://example.com/a_b_c."),
+            spans(&[
+                ("see ", plain()),
+                (
+                    "https://example.com/a_b_c",
+                    plain().patch(THEME.chat_message.link)
+                ),
+                (".", plain()),
+            ])
+        );
+    }
+}
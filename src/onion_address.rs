@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Result};
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Length of a v3 onion service ID, base32-encoded
+const SERVICE_ID_LENGTH: usize = 56;
+
+/// Length of a v2 onion service ID, which Tor no longer supports
+const V2_SERVICE_ID_LENGTH: usize = 16;
+
+const VERSION: u8 = 3;
+const CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+
+/// A v3 onion address, in the form `<service-id>[.onion][:<port>]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnionAddress {
+    /// The service ID, in lower case, without the ".onion"
+    pub service_id: String,
+    pub port: Option<u16>,
+}
+
+impl OnionAddress {
+    /// The address in the form the engine expects, using `default_port` if we don't have one
+    pub fn to_connect_string(&self, default_port: Option<u16>) -> Result<String> {
+        match self.port.or(default_port) {
+            Some(port) => Ok(format!("{}.onion:{}", self.service_id, port)),
+            None => Err(anyhow!("No port given for {}.onion", self.service_id)),
+        }
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match string.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(0) | Err(_) => return Err(anyhow!("Invalid port '{}'", port)),
+                Ok(port) => (host, Some(port)),
+            },
+            None => (string, None),
+        };
+
+        let host = host.to_ascii_lowercase();
+        let service_id = host.strip_suffix(".onion").unwrap_or(&host);
+        validate_service_id(service_id)?;
+
+        Ok(Self {
+            service_id: service_id.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}.onion:{}", self.service_id, port),
+            None => write!(f, "{}.onion", self.service_id),
+        }
+    }
+}
+
+/// The ed25519 public key of the onion service with the given v3 service ID
+pub fn public_key(service_id: &str) -> Result<[u8; 32]> {
+    validate_service_id(service_id)?;
+    let bytes = base32::decode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &service_id.to_ascii_uppercase(),
+    )
+    .ok_or_else(|| anyhow!("Onion address isn't valid base32"))?;
+    let mut public_key = [0; 32];
+    public_key.copy_from_slice(&bytes[..32]);
+    Ok(public_key)
+}
+
+/// Check a v3 service ID, which is the base32 encoding of
+/// `public key (32 bytes) | checksum (2 bytes) | version (1 byte)`
+fn validate_service_id(service_id: &str) -> Result<()> {
+    if service_id.is_empty() {
+        return Err(anyhow!("Missing onion address"));
+    }
+    if service_id.len() == V2_SERVICE_ID_LENGTH {
+        return Err(anyhow!(
+            "'{}' is a v2 onion address, which Tor no longer supports",
+            service_id
+        ));
+    }
+    if service_id.len() != SERVICE_ID_LENGTH {
+        return Err(anyhow!(
+            "Onion address must be {} characters long, not counting '.onion' (got {})",
+            SERVICE_ID_LENGTH,
+            service_id.len()
+        ));
+    }
+    if let Some(character) = service_id
+        .chars()
+        .find(|c| !matches!(c, 'a'..='z' | '2'..='7'))
+    {
+        return Err(anyhow!(
+            "Invalid character '{}' in onion address; only a-z and 2-7 are allowed",
+            character
+        ));
+    }
+
+    let bytes = base32::decode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &service_id.to_ascii_uppercase(),
+    )
+    .ok_or_else(|| anyhow!("Onion address isn't valid base32"))?;
+    let (public_key, rest) = bytes.split_at(32);
+    let (checksum, version) = rest.split_at(2);
+
+    if version[0] != VERSION {
+        return Err(anyhow!(
+            "Unsupported onion address version {} (expected {})",
+            version[0],
+            VERSION
+        ));
+    }
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(CHECKSUM_PREFIX);
+    hasher.update(public_key);
+    hasher.update(version);
+    if hasher.finalize()[..2] != *checksum {
+        return Err(anyhow!(
+            "Onion address checksum doesn't match; check it for typos"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Tor Project's onion service
+    const TOR_PROJECT: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+
+    /// A service ID for `public_key` with the given version, and a checksum that's right
+    /// for it
+    fn encode(public_key: &[u8; 32], version: u8) -> String {
+        let mut hasher = Sha3_256::new();
+        hasher.update(CHECKSUM_PREFIX);
+        hasher.update(public_key);
+        hasher.update([version]);
+        let mut bytes = public_key.to_vec();
+        bytes.extend_from_slice(&hasher.finalize()[..2]);
+        bytes.push(version);
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_ascii_lowercase()
+    }
+
+    #[test]
+    fn known_good_address_parses() {
+        let address = OnionAddress::from_str(&format!("{}.onion:443", TOR_PROJECT)).unwrap();
+        assert_eq!(address.service_id, TOR_PROJECT);
+        assert_eq!(address.port, Some(443));
+
+        let address = OnionAddress::from_str(&TOR_PROJECT.to_ascii_uppercase()).unwrap();
+        assert_eq!(address.service_id, TOR_PROJECT);
+        assert_eq!(address.port, None);
+        assert_eq!(
+            address.to_connect_string(Some(2000)).unwrap(),
+            format!("{}.onion:2000", TOR_PROJECT)
+        );
+    }
+
+    #[test]
+    fn public_key_round_trips() {
+        let public_key = public_key(TOR_PROJECT).unwrap();
+        assert_eq!(encode(&public_key, VERSION), TOR_PROJECT);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        // One character changed in the public key
+        let typo = TOR_PROJECT.replacen('g', "h", 1);
+        let error = OnionAddress::from_str(&typo).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{}", error);
+    }
+
+    #[test]
+    fn bad_version_is_rejected() {
+        let v4 = encode(&public_key(TOR_PROJECT).unwrap(), 4);
+        let error = OnionAddress::from_str(&v4).unwrap_err();
+        assert!(error.to_string().contains("version 4"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let error = OnionAddress::from_str(&TOR_PROJECT[1..]).unwrap_err();
+        assert!(error.to_string().contains("56 characters"), "{}", error);
+        let error = OnionAddress::from_str("expyuzz4wqqyqhjn.onion").unwrap_err();
+        assert!(error.to_string().contains("v2"), "{}", error);
+        assert!(OnionAddress::from_str("").is_err());
+    }
+
+    #[test]
+    fn bad_ports_are_rejected() {
+        assert!(OnionAddress::from_str(&format!("{}:0", TOR_PROJECT)).is_err());
+        assert!(OnionAddress::from_str(&format!("{}:http", TOR_PROJECT)).is_err());
+    }
+}
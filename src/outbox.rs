@@ -0,0 +1,159 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tor_client_lib::TorServiceId;
+use zeroize::Zeroize;
+
+use crate::{
+    protocol::{new_message_id, MessageId},
+    storage,
+};
+
+const OUTBOX_FILE: &str = "outbox.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueuedMessage {
+    #[serde(default = "new_message_id")]
+    pub id: MessageId,
+    pub text: String,
+    pub date: DateTime<Local>,
+    /// When the message disappears, if the chat had a timer when it was written. It's
+    /// dropped from the outbox then, even if it was never sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Local>>,
+    /// The message this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+}
+
+/// Messages waiting to be sent to one peer
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct QueuedPeer {
+    /// Address to reach the peer at, if we know it
+    pub address: Option<String>,
+    pub messages: Vec<QueuedMessage>,
+}
+
+/// Messages written to peers we're not connected to. They're saved to disk, and
+/// delivered once there's a connection to the peer, whichever side makes it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Outbox {
+    peers: BTreeMap<String, QueuedPeer>,
+}
+
+impl Outbox {
+    pub fn load() -> Result<Self> {
+        storage::load(OUTBOX_FILE)
+    }
+
+    /// Zero the text of every queued message and drop them all, leaving the file alone
+    pub fn wipe(&mut self) {
+        for peer in self.peers.values_mut() {
+            for message in peer.messages.iter_mut() {
+                message.text.zeroize();
+            }
+        }
+        self.peers.clear();
+    }
+
+    pub fn save(&self) -> Result<()> {
+        storage::save(OUTBOX_FILE, self)
+    }
+
+    pub fn push(
+        &mut self,
+        id: &TorServiceId,
+        address: Option<&str>,
+        message_id: MessageId,
+        text: &str,
+        expires: Option<DateTime<Local>>,
+        reply_to: Option<MessageId>,
+    ) {
+        let peer = self.peers.entry(id.as_str().to_string()).or_default();
+        if address.is_some() {
+            peer.address = address.map(str::to_string);
+        }
+        peer.messages.push(QueuedMessage {
+            id: message_id,
+            text: text.to_string(),
+            date: Local::now(),
+            expires,
+            reply_to,
+        });
+    }
+
+    pub fn has_messages(&self, id: &TorServiceId) -> bool {
+        self.peers
+            .get(id.as_str())
+            .is_some_and(|peer| !peer.messages.is_empty())
+    }
+
+    pub fn messages(&self, id: &TorServiceId) -> &[QueuedMessage] {
+        self.peers
+            .get(id.as_str())
+            .map_or(&[], |peer| peer.messages.as_slice())
+    }
+
+    /// Remove the first `count` messages for a peer, once they've been delivered
+    pub fn remove_delivered(&mut self, id: &TorServiceId, count: usize) {
+        if let Some(peer) = self.peers.get_mut(id.as_str()) {
+            peer.messages.drain(..count.min(peer.messages.len()));
+            if peer.messages.is_empty() {
+                self.peers.remove(id.as_str());
+            }
+        }
+    }
+
+    /// Change the text of a message that hasn't been sent yet
+    pub fn edit(&mut self, id: &TorServiceId, message_id: MessageId, text: &str) -> bool {
+        match self.peers.get_mut(id.as_str()).and_then(|peer| {
+            peer.messages
+                .iter_mut()
+                .find(|message| message.id == message_id)
+        }) {
+            Some(message) => {
+                message.text = text.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop a message that hasn't been sent yet
+    pub fn remove(&mut self, id: &TorServiceId, message_id: MessageId) -> bool {
+        let peer = match self.peers.get_mut(id.as_str()) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        let count = peer.messages.len();
+        peer.messages.retain(|message| message.id != message_id);
+        let removed = peer.messages.len() != count;
+        if peer.messages.is_empty() {
+            self.peers.remove(id.as_str());
+        }
+        removed
+    }
+
+    /// Drop the messages that have expired. Returns how many there were.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Local::now();
+        let mut count = 0;
+        self.peers.retain(|_, peer| {
+            let before = peer.messages.len();
+            peer.messages
+                .retain(|message| message.expires.is_none_or(|expires| expires > now));
+            count += before - peer.messages.len();
+            !peer.messages.is_empty()
+        });
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &QueuedPeer)> {
+        self.peers.iter()
+    }
+}
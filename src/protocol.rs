@@ -0,0 +1,221 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io;
+use tor_client_lib::TorServiceId;
+use voynich::{chat::ChatMessage, engine::Engine, logger::StandardLogger};
+use zeroize::Zeroizing;
+
+use crate::{app_context::AppContext, room::RoomId, transfer::TransferId};
+
+/// Marks the text of a chat message as a voynich-term payload rather than plain text.
+/// Peers running other voynich clients would see payloads as text, so they're only
+/// sent our hello; after that they just get the text of our messages.
+const PAYLOAD_PREFIX: &str = "\u{1}voynich-term:";
+
+/// The version of the protocol we speak, sent in our hello
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub type MessageId = u64;
+
+pub fn new_message_id() -> MessageId {
+    rand::random()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+/// A room member, as they're listed in an invitation
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomMember {
+    pub id: String,
+    /// Their onion service address, if we know it, so the invitee can connect to them
+    pub address: Option<String>,
+}
+
+/// What voynich-term peers send each other. The text of messages is borrowed when
+/// we're sending them, so it isn't copied; decoded payloads own theirs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload<'a> {
+    /// Sent to each peer as soon as we're connected. Nothing but plain text goes to a
+    /// peer until their hello arrives.
+    Hello { version: u32 },
+    /// A chat message
+    Text {
+        id: MessageId,
+        text: Cow<'a, str>,
+        /// Seconds until the message disappears, if it's a disappearing message
+        #[serde(default)]
+        ttl: Option<u64>,
+        /// The earlier message this one replies to
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+    },
+    /// Acknowledges that messages were received, or read
+    Receipt {
+        kind: ReceiptKind,
+        ids: Vec<MessageId>,
+    },
+    /// The sender is typing a message
+    Typing,
+    /// Offers to send a file
+    FileOffer {
+        transfer_id: TransferId,
+        name: String,
+        size: u64,
+        /// SHA3-256 of the file, hex-encoded
+        hash: String,
+    },
+    /// Accepts a file, asking for it from `offset` on, in case we have part of it already
+    FileAccept {
+        transfer_id: TransferId,
+        offset: u64,
+    },
+    /// Turns down a file
+    FileReject {
+        transfer_id: TransferId,
+        reason: Option<String>,
+    },
+    /// A piece of a file, base64-encoded
+    FileChunk {
+        transfer_id: TransferId,
+        offset: u64,
+        data: String,
+    },
+    /// The whole file has arrived; `ok` says whether it matched its hash
+    FileDone { transfer_id: TransferId, ok: bool },
+    /// The sender has changed the text of one of their messages
+    Edit {
+        id: MessageId,
+        text: Cow<'a, str>,
+        /// Set if the message was sent to a room
+        #[serde(default)]
+        room: Option<RoomId>,
+    },
+    /// The sender has deleted one of their messages
+    Delete {
+        id: MessageId,
+        #[serde(default)]
+        room: Option<RoomId>,
+    },
+    /// Invites the recipient to a room, and tells them who's in it
+    RoomInvite {
+        room: RoomId,
+        name: String,
+        members: Vec<RoomMember>,
+    },
+    /// The sender has invited someone to a room. Members only let in peers that a
+    /// member has invited.
+    RoomInvited { room: RoomId, id: String },
+    /// The sender is in a room. Members send this to each other as they connect.
+    RoomJoin {
+        room: RoomId,
+        address: Option<String>,
+    },
+    /// The sender has left a room
+    RoomLeave { room: RoomId },
+    /// A message to a room, sent to each member
+    RoomText {
+        room: RoomId,
+        id: MessageId,
+        text: Cow<'a, str>,
+        #[serde(default)]
+        ttl: Option<u64>,
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+    },
+    /// The sender has set how long messages in the chat last, in seconds. Both sides
+    /// use the same timer; None turns disappearing messages off.
+    Ttl {
+        seconds: Option<u64>,
+        #[serde(default)]
+        room: Option<RoomId>,
+    },
+    /// Answers a Ttl outside a room: the sender has set their timer to `seconds` too
+    TtlAck { seconds: Option<u64> },
+}
+
+impl Payload<'_> {
+    /// Encode the payload as the text of a chat message. Its length is worked out
+    /// first, so the buffer never grows and leaves copies of it behind.
+    pub fn encode(&self) -> Zeroizing<String> {
+        let mut length = Length(PAYLOAD_PREFIX.len());
+        serde_json::to_writer(&mut length, self).expect("payload serialization can't fail");
+        let mut encoded = Zeroizing::new(Vec::with_capacity(length.0));
+        encoded.extend_from_slice(PAYLOAD_PREFIX.as_bytes());
+        serde_json::to_writer(&mut *encoded, self).expect("payload serialization can't fail");
+        Zeroizing::new(
+            String::from_utf8(std::mem::take(&mut *encoded)).expect("JSON is always UTF-8"),
+        )
+    }
+
+    /// Decode the text of a chat message. Gives None for plain text, from clients that
+    /// don't speak this protocol.
+    pub fn decode(text: &str) -> Result<Option<Payload<'static>>> {
+        match text.strip_prefix(PAYLOAD_PREFIX) {
+            Some(payload) => Ok(Some(serde_json::from_str(payload)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Send a payload to a peer. A peer that hasn't said hello only gets the text of a chat
+/// message; anything else is an error.
+pub async fn send(
+    engine: &mut Engine,
+    context: &AppContext,
+    recipient: &TorServiceId,
+    payload: &Payload<'_>,
+    logger: &mut StandardLogger,
+) -> Result<()> {
+    let text = match payload {
+        Payload::Hello { .. } => payload.encode(),
+        _ if context.speaks_protocol(recipient) => payload.encode(),
+        Payload::Text { text, .. } => Zeroizing::new(text.to_string()),
+        _ => bail!("{} is using a client that doesn't support that", recipient),
+    };
+    // voynich keeps the message it's given, so it gets a copy, and ours is zeroed
+    let message = ChatMessage::new(&context.id, recipient, text.to_string());
+    engine.send_message(message, logger).await?;
+    Ok(())
+}
+
+/// Counts what's written to it, to size a buffer before writing to that
+struct Length(usize);
+
+impl io::Write for Length {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_fills_the_buffer_exactly() {
+        let payload = Payload::Text {
+            id: 7,
+            text: Cow::Borrowed("say \"hi\" ✓"),
+            ttl: None,
+            reply_to: Some(3),
+        };
+        let encoded = payload.encode();
+        assert_eq!(encoded.len(), encoded.capacity());
+        match Payload::decode(&encoded).unwrap() {
+            Some(Payload::Text { id, text, .. }) => assert_eq!((id, &*text), (7, "say \"hi\" ✓")),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tor_client_lib::TorServiceId;
+
+/// How far back connection attempts are counted
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How often to tell the user about attempts we've turned away
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers we keep counts for. Past this, attempts from peers we aren't tracking are
+/// rejected, and counted together in the summary.
+const MAX_TRACKED_PEERS: usize = 1024;
+
+/// Limits how often peers we don't know can try to connect, so they can't flood us with
+/// connection popups. There's a limit for each peer, and one for all of them together,
+/// since anyone can make new onion addresses. Attempts over either limit are rejected,
+/// and counted for a summary.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    /// Attempts allowed per peer per minute; 0 means no limit
+    limit: u32,
+    /// Attempts allowed from all peers together per minute; 0 means no limit
+    total_limit: u32,
+    attempts: HashMap<TorServiceId, VecDeque<Instant>>,
+    /// Every attempt allowed in the last minute, whoever it was from
+    recent: VecDeque<Instant>,
+    suppressed: HashMap<TorServiceId, u32>,
+    /// Attempts rejected from peers there was no room to count separately
+    suppressed_untracked: u32,
+    last_summary: Instant,
+}
+
+/// The connection attempts rejected since the last summary
+#[derive(Debug)]
+pub struct RejectionSummary {
+    /// How many attempts were rejected from each peer, most first
+    pub peers: Vec<(TorServiceId, u32)>,
+    /// How many were rejected from peers that weren't counted separately
+    pub untracked: u32,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: u32, total_limit: u32) -> Self {
+        Self {
+            limit,
+            total_limit,
+            attempts: HashMap::new(),
+            recent: VecDeque::new(),
+            suppressed: HashMap::new(),
+            suppressed_untracked: 0,
+            last_summary: Instant::now(),
+        }
+    }
+
+    /// Count an attempt to connect from `id`, and say whether it's within the limits
+    pub fn allow(&mut self, id: &TorServiceId) -> bool {
+        let now = Instant::now();
+        expire(&mut self.recent, now);
+        let total_allowed = self.total_limit == 0 || self.recent.len() < self.total_limit as usize;
+        let peer_allowed = self.limit == 0 || self.peer_allowed(id, now);
+        if total_allowed && peer_allowed {
+            self.recent.push_back(now);
+            if self.limit != 0 {
+                self.attempts.entry(id.clone()).or_default().push_back(now);
+            }
+            true
+        } else {
+            if self.suppressed.contains_key(id) || self.suppressed.len() < MAX_TRACKED_PEERS {
+                *self.suppressed.entry(id.clone()).or_default() += 1;
+            } else {
+                self.suppressed_untracked += 1;
+            }
+            false
+        }
+    }
+
+    /// Whether `id` is under the limit for each peer. A peer we have no count for is
+    /// only allowed if there's room to count them.
+    fn peer_allowed(&mut self, id: &TorServiceId, now: Instant) -> bool {
+        if !self.attempts.contains_key(id) && self.attempts.len() >= MAX_TRACKED_PEERS {
+            self.forget_quiet_peers(now);
+        }
+        match self.attempts.get_mut(id) {
+            Some(attempts) => {
+                expire(attempts, now);
+                attempts.len() < self.limit as usize
+            }
+            None => self.attempts.len() < MAX_TRACKED_PEERS,
+        }
+    }
+
+    fn forget_quiet_peers(&mut self, now: Instant) {
+        self.attempts.retain(|_, attempts| {
+            attempts
+                .back()
+                .is_some_and(|&attempt| now.duration_since(attempt) < WINDOW)
+        });
+    }
+
+    /// The attempts rejected since the last summary, once it's time for another one.
+    /// Peers who've gone quiet are forgotten at the same time.
+    pub fn take_summary(&mut self) -> Option<RejectionSummary> {
+        if self.last_summary.elapsed() < SUMMARY_INTERVAL {
+            return None;
+        }
+        self.last_summary = Instant::now();
+        self.forget_quiet_peers(self.last_summary);
+        if self.suppressed.is_empty() && self.suppressed_untracked == 0 {
+            return None;
+        }
+        let mut peers = self.suppressed.drain().collect::<Vec<_>>();
+        peers.sort_by(|(_, first), (_, second)| second.cmp(first));
+        Some(RejectionSummary {
+            peers,
+            untracked: std::mem::take(&mut self.suppressed_untracked),
+        })
+    }
+}
+
+/// Drop the attempts that are too old to count
+fn expire(attempts: &mut VecDeque<Instant>, now: Instant) {
+    while attempts
+        .front()
+        .is_some_and(|&attempt| now.duration_since(attempt) >= WINDOW)
+    {
+        attempts.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_peer_has_a_limit() {
+        let mut limiter = ConnectionLimiter::new(2, 0);
+        let peer = TorServiceId::generate();
+        assert!(limiter.allow(&peer));
+        assert!(limiter.allow(&peer));
+        assert!(!limiter.allow(&peer));
+        assert!(limiter.allow(&TorServiceId::generate()));
+        assert_eq!(limiter.suppressed[&peer], 1);
+    }
+
+    #[test]
+    fn all_peers_share_a_limit() {
+        let mut limiter = ConnectionLimiter::new(3, 5);
+        for _ in 0..5 {
+            assert!(limiter.allow(&TorServiceId::generate()));
+        }
+        let peer = TorServiceId::generate();
+        assert!(!limiter.allow(&peer));
+        assert_eq!(limiter.suppressed[&peer], 1);
+    }
+
+    #[test]
+    fn tracked_peers_are_capped() {
+        let mut limiter = ConnectionLimiter::new(1, 0);
+        for _ in 0..MAX_TRACKED_PEERS {
+            assert!(limiter.allow(&TorServiceId::generate()));
+        }
+        assert!(!limiter.allow(&TorServiceId::generate()));
+        assert_eq!(limiter.attempts.len(), MAX_TRACKED_PEERS);
+    }
+}
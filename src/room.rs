@@ -0,0 +1,192 @@
+use std::str::FromStr;
+use tor_client_lib::TorServiceId;
+
+use crate::{chat::Chat, protocol::RoomMember};
+
+pub type RoomId = u64;
+
+/// Most joins we'll hold on to while waiting to hear the peer was invited
+const MAX_UNCONFIRMED_JOINS: usize = 16;
+
+pub fn new_room_id() -> RoomId {
+    rand::random()
+}
+
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub id: TorServiceId,
+    /// The member's onion service address, as they've told us, so we can pass it on
+    /// to the people we invite
+    pub address: Option<String>,
+}
+
+/// A chat with several peers. There's no server: each message goes to every member
+/// over our own connection with them, so we only reach the members we're connected to.
+#[derive(Debug)]
+pub struct Room {
+    pub name: String,
+    /// Everyone in the room, including us
+    pub members: Vec<Member>,
+    /// Peers a member has invited, who are let in when they join
+    invited: Vec<TorServiceId>,
+    /// Joins from peers we haven't yet heard were invited. The invitee can reach us
+    /// before the member who invited them does.
+    unconfirmed_joins: Vec<Member>,
+    pub chat: Chat,
+}
+
+impl Room {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            members: Vec::new(),
+            invited: Vec::new(),
+            unconfirmed_joins: Vec::new(),
+            chat: Chat::new(),
+        }
+    }
+
+    pub fn is_member(&self, id: &TorServiceId) -> bool {
+        self.members.iter().any(|member| member.id == *id)
+    }
+
+    /// Add a member, or fill in their address if we didn't know it. Returns true if
+    /// they weren't a member already.
+    pub fn add_member(&mut self, id: &TorServiceId, address: Option<String>) -> bool {
+        match self.members.iter_mut().find(|member| member.id == *id) {
+            Some(member) => {
+                if address.is_some() {
+                    member.address = address;
+                }
+                false
+            }
+            None => {
+                self.members.push(Member {
+                    id: id.clone(),
+                    address,
+                });
+                true
+            }
+        }
+    }
+
+    /// A member has invited a peer. Returns true if the peer had already asked to join,
+    /// in which case they're a member now.
+    pub fn invite(&mut self, id: &TorServiceId) -> bool {
+        if self.is_member(id) {
+            return false;
+        }
+        match self
+            .unconfirmed_joins
+            .iter()
+            .position(|member| member.id == *id)
+        {
+            Some(index) => {
+                let member = self.unconfirmed_joins.remove(index);
+                self.members.push(member);
+                true
+            }
+            None => {
+                if !self.invited.contains(id) {
+                    self.invited.push(id.clone());
+                }
+                false
+            }
+        }
+    }
+
+    /// A peer says they're in the room. Members can update their address; anyone else
+    /// is only let in if a member invited them. Returns true if they've just joined.
+    pub fn join(&mut self, id: &TorServiceId, address: Option<String>) -> bool {
+        if self.is_member(id) {
+            self.add_member(id, address);
+            return false;
+        }
+        match self.invited.iter().position(|invited| invited == id) {
+            Some(index) => {
+                self.invited.remove(index);
+                self.add_member(id, address)
+            }
+            None => {
+                self.unconfirmed_joins.retain(|member| member.id != *id);
+                if self.unconfirmed_joins.len() < MAX_UNCONFIRMED_JOINS {
+                    self.unconfirmed_joins.push(Member {
+                        id: id.clone(),
+                        address,
+                    });
+                }
+                false
+            }
+        }
+    }
+
+    pub fn remove_member(&mut self, id: &TorServiceId) -> bool {
+        let count = self.members.len();
+        self.members.retain(|member| member.id != *id);
+        self.members.len() != count
+    }
+
+    /// The members other than `us`
+    pub fn peers(&self, us: &TorServiceId) -> Vec<TorServiceId> {
+        self.members
+            .iter()
+            .filter(|member| member.id != *us)
+            .map(|member| member.id.clone())
+            .collect()
+    }
+
+    /// The member list, as it's sent to peers
+    pub fn wire_members(&self) -> Vec<RoomMember> {
+        self.members
+            .iter()
+            .map(|member| RoomMember {
+                id: member.id.as_str().to_string(),
+                address: member.address.clone(),
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<&RoomMember> for Member {
+    type Error = anyhow::Error;
+
+    fn try_from(member: &RoomMember) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: TorServiceId::from_str(&member.id)
+                .map_err(|error| anyhow::anyhow!("Bad room member '{}': {}", member.id, error))?,
+            address: member.address.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_invited_peers_can_join() {
+        let mut room = Room::new("test");
+        let stranger = TorServiceId::generate();
+        assert!(!room.join(&stranger, None));
+        assert!(!room.is_member(&stranger));
+
+        let invitee = TorServiceId::generate();
+        assert!(!room.invite(&invitee));
+        assert!(room.join(&invitee, Some("invitee.onion:2000".to_string())));
+        assert!(room.is_member(&invitee));
+        assert!(!room.join(&invitee, None));
+    }
+
+    #[test]
+    fn a_join_waits_for_its_invitation() {
+        let mut room = Room::new("test");
+        let invitee = TorServiceId::generate();
+        assert!(!room.join(&invitee, Some("invitee.onion:2000".to_string())));
+        assert!(room.invite(&invitee));
+        assert!(room.is_member(&invitee));
+        assert_eq!(
+            room.members[0].address.as_deref(),
+            Some("invitee.onion:2000")
+        );
+    }
+}
@@ -6,11 +6,27 @@ use voynich::logger::StandardLogger;
 
 use crate::{
     app_context::AppContext,
-    input::{chat_input::ChatInput, command_input::CommandInput},
+    chat::ChatId,
+    input::{
+        chat_input::{ChatInput, InputMode},
+        command_input::CommandInput,
+        lock_input::LockInput,
+    },
+    search::ChatSearch,
     widgets::{
-        allow_connection_popup::AllowConnectionPopup, chat_input::ChatInputWidget,
-        chat_panel::ChatPanel, chat_tabs::ChatTabs, command_popup::CommandPopup,
-        status_bar::StatusBar, system_messages_panel::SystemMessagesPanel, title_bar::TitleBar,
+        allow_connection_popup::AllowConnectionPopup,
+        chat_input::ChatInputWidget,
+        chat_panel::ChatPanel,
+        chat_tabs::ChatTabs,
+        client_key_popup::ClientKeyPopup,
+        command_popup::CommandPopup,
+        file_offer_popup::FileOfferPopup,
+        lock_screen::LockScreen,
+        members_panel::{MembersPanel, MEMBERS_PANEL_WIDTH},
+        room_invite_popup::RoomInvitePopup,
+        status_bar::StatusBar,
+        system_messages_panel::SystemMessagesPanel,
+        title_bar::TitleBar,
         welcome_popup::WelcomePopup,
     },
 };
@@ -20,6 +36,9 @@ pub struct Root<'a> {
     logger: &'a mut StandardLogger,
     command_popup: Option<CommandPopup<'a>>,
     chat_input: ChatInputWidget<'a>,
+    lock_input: &'a LockInput,
+    mode: InputMode,
+    search: Option<&'a ChatSearch>,
 }
 
 impl<'a> Root<'a> {
@@ -28,6 +47,7 @@ impl<'a> Root<'a> {
         logger: &'a mut StandardLogger,
         command_input: &'a CommandInput,
         chat_input: &'a ChatInput,
+        lock_input: &'a LockInput,
     ) -> Self {
         let command_popup = if context.show_command_popup {
             Some(CommandPopup::new(command_input))
@@ -40,21 +60,53 @@ impl<'a> Root<'a> {
             logger,
             command_popup,
             chat_input: ChatInputWidget::new(chat_input),
+            lock_input,
+            mode: chat_input.mode(),
+            search: chat_input.search(),
         }
     }
 }
 
 impl Widget for Root<'_> {
     fn render(mut self, area: Rect, buf: &mut Buffer) {
+        if self.context.locked {
+            LockScreen::new(self.lock_input, self.context.lock_notifications.len())
+                .render(area, buf);
+            return;
+        }
         match self.context.chat_list.current() {
             Some(id) => {
                 let chunks = self.get_layout(area);
 
                 TitleBar::new(&self.context.onion_service_address).render(chunks[0], buf);
                 SystemMessagesPanel::new(self.logger).render(chunks[1], buf);
-                ChatTabs::new(&self.context.chat_list).render(chunks[2], buf);
-                ChatPanel::new(id, self.context).render(chunks[3], buf);
-                StatusBar::new().render(chunks[4], buf);
+                ChatTabs::new(self.context).render(chunks[2], buf);
+                let selected = match self.mode {
+                    InputMode::Selecting(message_id)
+                    | InputMode::Replying(message_id)
+                    | InputMode::Editing(message_id)
+                    | InputMode::Deleting(message_id) => Some(message_id),
+                    _ => None,
+                };
+                match id {
+                    ChatId::Room(room_id) if self.context.show_members => {
+                        let room_chunks = Layout::default()
+                            .direction(Direction::Horizontal)
+                            .constraints([
+                                Constraint::Min(1),
+                                Constraint::Length(MEMBERS_PANEL_WIDTH),
+                            ])
+                            .split(chunks[3]);
+                        ChatPanel::new(id, self.context, selected, self.search)
+                            .render(room_chunks[0], buf);
+                        if let Some(room) = self.context.rooms.get(room_id) {
+                            MembersPanel::new(room, self.context).render(room_chunks[1], buf);
+                        }
+                    }
+                    _ => ChatPanel::new(id, self.context, selected, self.search)
+                        .render(chunks[3], buf),
+                }
+                StatusBar::new(self.context, self.mode, self.search).render(chunks[4], buf);
                 self.chat_input.render(chunks[5], buf);
             }
             None => {
@@ -62,6 +114,9 @@ impl Widget for Root<'_> {
 
                 TitleBar::new(&self.context.onion_service_address).render(chunks[0], buf);
                 SystemMessagesPanel::new(self.logger).render(chunks[1], buf);
+                if chunks.len() > 2 {
+                    ChatTabs::new(self.context).render(chunks[2], buf);
+                }
             }
         }
         if self.context.show_command_popup {
@@ -70,36 +125,59 @@ impl Widget for Root<'_> {
         if self.context.show_welcome_popup {
             WelcomePopup::new(&self.context.onion_service_address).render(area, buf);
         }
-        if self.context.connection_context.is_some() {
-            let connection_context = self.context.connection_context.as_ref().unwrap();
+        if let Some(keypair) = &self.context.client_keypair {
+            ClientKeyPopup::new(keypair).render(area, buf);
+        } else if let Some(connection_context) = &self.context.connection_context {
             AllowConnectionPopup::new(
                 &connection_context.connection_address.to_string(),
+                self.context
+                    .contacts
+                    .get(&connection_context.connection_address),
                 connection_context.accept_selected,
             )
             .render(area, buf);
+        } else if let Some(offer) = self.context.file_offers.front() {
+            if let Some(transfer) = self.context.transfers.get(offer.transfer_id) {
+                FileOfferPopup::new(
+                    &transfer.peer.to_string(),
+                    &transfer.name,
+                    transfer.size,
+                    offer.accept_selected,
+                )
+                .render(area, buf);
+            }
+        } else if let Some(invite) = self.context.room_invites.front() {
+            RoomInvitePopup::new(
+                &invite.inviter.to_string(),
+                &invite.name,
+                invite.members.len(),
+                invite.accept_selected,
+            )
+            .render(area, buf);
         }
     }
 }
 
-// split messages to fit the width of the ui panel
-pub fn split_each(input: String, width: usize) -> Vec<String> {
+// split messages to fit the width of the ui panel. The rows borrow from the input,
+// so what's typed isn't copied each time it's drawn.
+pub fn split_each(input: &str, width: usize) -> Vec<&str> {
     let mut splitted = Vec::with_capacity(input.width() / width);
-    let mut row = String::new();
+    let mut row_start = 0;
 
     let mut index = 0;
 
-    for current_char in input.chars() {
+    for (byte_index, current_char) in input.char_indices() {
         if (index != 0 && index == width) || index + current_char.width().unwrap_or(0) > width {
-            splitted.push(std::mem::take(&mut row));
+            splitted.push(&input[row_start..byte_index]);
+            row_start = byte_index;
             index = 0;
         }
 
-        row.push(current_char);
         index += current_char.width().unwrap_or(0);
     }
     // leftover
-    if !row.is_empty() {
-        splitted.push(std::mem::take(&mut row));
+    if row_start < input.len() {
+        splitted.push(&input[row_start..]);
     }
     splitted
 }
@@ -145,7 +223,9 @@ pub fn centered_rect(constraint_x: Constraint, constraint_y: Constraint, r: Rect
 
 impl Root<'_> {
     pub fn get_cursor_location(&mut self, area: Rect) -> Option<(u16, u16)> {
-        if self.context.show_command_popup {
+        if self.context.locked {
+            None
+        } else if self.context.show_command_popup {
             self.command_popup
                 .as_mut()
                 .unwrap()
@@ -177,6 +257,17 @@ impl Root<'_> {
                     .as_ref(),
                 )
                 .split(area),
+            None if !self.context.pending_connects.is_empty() => Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Length(1),
+                        Constraint::Min(1),
+                        Constraint::Length(3),
+                    ]
+                    .as_ref(),
+                )
+                .split(area),
             None => Layout::default()
                 .direction(Direction::Vertical)
                 .constraints([Constraint::Length(1), Constraint::Min(1)].as_ref())
@@ -0,0 +1,244 @@
+use anyhow::Result;
+use ratatui::prelude::*;
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+
+use crate::{
+    chat::{Chat, ChatEntry, ChatId},
+    protocol::MessageId,
+};
+
+/// What to look for in the messages of a chat
+#[derive(Clone, Debug)]
+pub struct Search {
+    /// The text or pattern as it was typed
+    pub query: String,
+    pattern: Regex,
+}
+
+impl Search {
+    /// Search for `query`, which is taken as a regular expression if `regex` is set,
+    /// and otherwise as plain text
+    pub fn new(query: &str, regex: bool, ignore_case: bool) -> Result<Self> {
+        let pattern = if regex {
+            query.to_string()
+        } else {
+            regex::escape(query)
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|error| anyhow::anyhow!("Bad pattern '{}': {}", query, error))?;
+        Ok(Self {
+            query: query.to_string(),
+            pattern,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.pattern.is_match(text)
+    }
+
+    fn entry_matches(&self, entry: &ChatEntry) -> bool {
+        !entry.deleted && self.is_match(&entry.message.message)
+    }
+
+    /// Split spans so the matches in them are picked out with `style`. Each span is
+    /// searched separately, so a match can't run across two differently styled spans.
+    /// Spans that borrow their text still do afterwards.
+    pub fn highlight<'a>(&self, spans: Vec<Span<'a>>, style: Style) -> Vec<Span<'a>> {
+        let mut highlighted = Vec::new();
+        for span in spans {
+            let piece = |start: usize, end: usize| -> Cow<'a, str> {
+                match &span.content {
+                    Cow::Borrowed(content) => {
+                        let content: &'a str = content;
+                        Cow::Borrowed(&content[start..end])
+                    }
+                    Cow::Owned(content) => Cow::Owned(content[start..end].to_string()),
+                }
+            };
+            let mut last = 0;
+            for found in self
+                .pattern
+                .find_iter(&span.content)
+                .filter(|found| !found.is_empty())
+            {
+                if found.start() > last {
+                    highlighted.push(Span::styled(piece(last, found.start()), span.style));
+                }
+                highlighted.push(Span::styled(
+                    piece(found.start(), found.end()),
+                    span.style.patch(style),
+                ));
+                last = found.end();
+            }
+            match last {
+                0 => highlighted.push(span),
+                last if last < span.content.len() => {
+                    highlighted.push(Span::styled(piece(last, span.content.len()), span.style))
+                }
+                _ => {}
+            }
+        }
+        highlighted
+    }
+}
+
+/// A search through one chat, and which of the messages that match is current
+#[derive(Clone, Debug)]
+pub struct ChatSearch {
+    pub search: Search,
+    pub chat_id: ChatId,
+    /// The message that's the current match. It's kept by ID, so it stays put when
+    /// earlier messages are dropped or disappear. Messages without an ID, from clients
+    /// that don't send them, are highlighted but can't be moved to.
+    pub current: Option<MessageId>,
+}
+
+impl ChatSearch {
+    /// Start a search at the most recent message that matches
+    pub fn new(search: Search, chat_id: ChatId, chat: &Chat) -> Self {
+        let mut chat_search = Self {
+            search,
+            chat_id,
+            current: None,
+        };
+        chat_search.current = chat_search.matches(chat).last().copied();
+        chat_search
+    }
+
+    /// The IDs of the messages that match, oldest first
+    pub fn matches(&self, chat: &Chat) -> Vec<MessageId> {
+        chat.iter()
+            .filter(|entry| self.search.entry_matches(entry))
+            .filter_map(|entry| entry.id)
+            .collect()
+    }
+
+    /// Move to the match before the current one, or with `forward`, the one after. It
+    /// stays put at either end. If the current match has gone, it starts again from the
+    /// most recent.
+    pub fn step(&mut self, chat: &Chat, forward: bool) {
+        let matches = self.matches(chat);
+        let position = self
+            .current
+            .and_then(|current| matches.iter().position(|&id| id == current));
+        let next = match position {
+            Some(position) if forward => matches.get(position + 1),
+            Some(position) => position
+                .checked_sub(1)
+                .and_then(|position| matches.get(position)),
+            None => matches.last(),
+        };
+        if let Some(&next) = next {
+            self.current = Some(next);
+        }
+    }
+
+    /// Which match is current, counting from 1, and how many there are
+    pub fn position(&self, chat: &Chat) -> (Option<usize>, usize) {
+        let matches = self.matches(chat);
+        let position = self
+            .current
+            .and_then(|current| matches.iter().position(|&id| id == current))
+            .map(|position| position + 1);
+        (position, matches.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::MessageState;
+    use tor_client_lib::TorServiceId;
+    use voynich::chat::ChatMessage;
+
+    fn highlighted(search: &Search, spans: Vec<Span<'static>>) -> Vec<(String, Style)> {
+        search
+            .highlight(spans, Style::default().bg(Color::Yellow))
+            .into_iter()
+            .map(|span| (span.content.into_owned(), span.style))
+            .collect()
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        let search = Search::new("a.b", false, false).unwrap();
+        assert!(search.is_match("a.b"));
+        assert!(!search.is_match("axb"));
+    }
+
+    #[test]
+    fn regex_and_ignore_case() {
+        let search = Search::new("h.llo", true, true).unwrap();
+        assert!(search.is_match("HELLO"));
+        assert!(Search::new("(", true, false).is_err());
+    }
+
+    #[test]
+    fn highlight_splits_spans_around_matches() {
+        let search = Search::new("cat", false, false).unwrap();
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let found = bold.patch(Style::default().bg(Color::Yellow));
+        assert_eq!(
+            highlighted(
+                &search,
+                vec![Span::styled("a cat and a cat", bold), Span::raw("dog")]
+            ),
+            vec![
+                ("a ".to_string(), bold),
+                ("cat".to_string(), found),
+                (" and a ".to_string(), bold),
+                ("cat".to_string(), found),
+                ("dog".to_string(), Style::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn highlight_keeps_borrowed_text_borrowed() {
+        let search = Search::new("é", false, false).unwrap();
+        let text = String::from("café au lait");
+        let spans = search.highlight(vec![Span::raw(text.as_str())], Style::default());
+        assert_eq!(
+            spans
+                .iter()
+                .map(|span| span.content.as_ref())
+                .collect::<Vec<_>>(),
+            vec!["caf", "é", " au lait"]
+        );
+        assert!(spans
+            .iter()
+            .all(|span| matches!(span.content, Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn current_match_survives_earlier_messages_going() {
+        let us = TorServiceId::generate();
+        let them = TorServiceId::generate();
+        let mut chat = Chat::new();
+        for (id, text) in [(1, "cat"), (2, "dog"), (3, "cat"), (4, "cat")] {
+            let message = ChatMessage::new(&us, &them, text.to_string());
+            chat.add_entry(message, Some(id), MessageState::Sent);
+        }
+        let mut search = ChatSearch::new(
+            Search::new("cat", false, false).unwrap(),
+            ChatId::Peer(them),
+            &chat,
+        );
+        search.step(&chat, false);
+        assert_eq!(search.current, Some(3));
+
+        chat.remove(1);
+        chat.remove(2);
+        assert_eq!(search.position(&chat), (Some(1), 2));
+        search.step(&chat, true);
+        assert_eq!(search.current, Some(4));
+
+        // With the current match gone, it starts again from the most recent
+        chat.remove(4);
+        search.step(&chat, false);
+        assert_eq!(search.current, Some(3));
+    }
+}
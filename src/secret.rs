@@ -0,0 +1,122 @@
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// How many characters a buffer has room for to start with, so it seldom has to grow
+const INITIAL_CAPACITY: usize = 256;
+
+/// A buffer of typed characters that doesn't leave copies of them behind. Characters
+/// that are removed are zeroed, growing copies into a new allocation and zeroes the old
+/// one, and everything is zeroed when it's dropped.
+#[derive(Default)]
+pub struct SecretChars {
+    chars: Vec<char>,
+}
+
+impl SecretChars {
+    pub fn new() -> Self {
+        Self {
+            chars: Vec::with_capacity(INITIAL_CAPACITY),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// How many bytes the characters take as UTF-8, so strings made from them can be
+    /// allocated once, without growing and leaving copies behind
+    pub fn utf8_len(&self) -> usize {
+        self.utf8_len_from(0)
+    }
+
+    fn utf8_len_from(&self, start: usize) -> usize {
+        self.chars[start..].iter().map(|c| c.len_utf8()).sum()
+    }
+
+    pub fn as_slice(&self) -> &[char] {
+        &self.chars
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, char> {
+        self.chars.iter()
+    }
+
+    pub fn insert(&mut self, index: usize, character: char) {
+        self.reserve(1);
+        self.chars.insert(index, character);
+    }
+
+    pub fn extend(&mut self, characters: impl Iterator<Item = char>) {
+        for character in characters {
+            self.insert(self.chars.len(), character);
+        }
+    }
+
+    /// Remove the character at `index`. The ones after it move down, and the slot left
+    /// at the end is zeroed.
+    pub fn remove(&mut self, index: usize) {
+        self.chars[index..].rotate_left(1);
+        self.truncate(self.chars.len() - 1);
+    }
+
+    /// Remove the characters in `start..end`, zeroing the slots they leave behind
+    pub fn remove_range(&mut self, start: usize, end: usize) {
+        self.chars[start..].rotate_left(end - start);
+        self.truncate(self.chars.len() - (end - start));
+    }
+
+    /// Take the characters from `start` on as a string, zeroing them here. The string is
+    /// zeroed in turn when it's dropped.
+    pub fn take_from(&mut self, start: usize) -> Zeroizing<String> {
+        let mut taken = Zeroizing::new(String::with_capacity(self.utf8_len_from(start)));
+        taken.extend(&self.chars[start..]);
+        self.truncate(start);
+        taken
+    }
+
+    pub fn truncate(&mut self, length: usize) {
+        if length < self.chars.len() {
+            self.chars[length..].zeroize();
+            self.chars.truncate(length);
+        }
+    }
+
+    /// Make room for `additional` more characters. A Vec left to grow by itself would
+    /// free its old allocation with the characters still in it.
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.chars.len() + additional;
+        if needed <= self.chars.capacity() {
+            return;
+        }
+        let capacity = needed.max(self.chars.capacity() * 2).max(INITIAL_CAPACITY);
+        let mut chars = Vec::with_capacity(capacity);
+        chars.extend_from_slice(&self.chars);
+        std::mem::swap(&mut self.chars, &mut chars);
+        chars.zeroize();
+    }
+}
+
+impl Drop for SecretChars {
+    fn drop(&mut self) {
+        self.chars.zeroize();
+    }
+}
+
+impl Clone for SecretChars {
+    fn clone(&self) -> Self {
+        let mut chars = Self::new();
+        chars.extend(self.iter().copied());
+        chars
+    }
+}
+
+/// What's been typed is left out, so it can't end up in a log
+impl fmt::Debug for SecretChars {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "SecretChars({} characters)", self.chars.len())
+    }
+}
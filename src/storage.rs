@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::{
+    config::config_dir,
+    encryption::{self, Key, WrappedKey},
+};
+
+/// Holds the wrapped key, when files are encrypted with a passphrase. It's never
+/// encrypted itself.
+const PASSPHRASE_FILE: &str = "passphrase.json";
+
+/// The key files are encrypted with, once the passphrase has been given
+static KEY: RwLock<Option<Key>> = RwLock::new(None);
+
+/// Directory where voynich-term keeps its own files
+pub fn data_dir() -> Result<PathBuf> {
+    Ok(config_dir()?.join("term"))
+}
+
+/// Load a value from a file in the data directory. A missing file gives the default.
+pub fn load<T: DeserializeOwned + Default>(name: &str) -> Result<T> {
+    let path = data_dir()?.join(name);
+    match read(name)? {
+        Some(contents) => {
+            serde_json::from_slice(&contents).with_context(|| format!("parsing {}", path.display()))
+        }
+        None => Ok(T::default()),
+    }
+}
+
+/// Save a value to a file in the data directory, readable only by us, and encrypted if
+/// there's a passphrase
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    write(name, &serde_json::to_vec(value)?)
+}
+
+/// Whether files are encrypted with a passphrase, which has to be given with `unlock`
+/// before they can be read
+pub fn has_passphrase() -> Result<bool> {
+    Ok(data_dir()?.join(PASSPHRASE_FILE).exists())
+}
+
+/// Get the key for reading and writing encrypted files, using the passphrase
+pub fn unlock(passphrase: &str) -> Result<()> {
+    let wrapped_key: WrappedKey = load_plain(PASSPHRASE_FILE)?;
+    let key = wrapped_key.unwrap(passphrase)?;
+    *KEY.write().unwrap() = Some(key);
+    Ok(())
+}
+
+/// Whether there's a passphrase in use, and it's been given
+pub fn is_unlocked() -> bool {
+    KEY.read().unwrap().is_some()
+}
+
+/// Check a passphrase without changing anything, as for unlocking the screen
+pub fn check_passphrase(passphrase: &str) -> Result<()> {
+    let wrapped_key: WrappedKey = load_plain(PASSPHRASE_FILE)?;
+    wrapped_key.unwrap(passphrase)?;
+    Ok(())
+}
+
+/// Start encrypting files with a passphrase, change it, or with None, stop encrypting
+/// them. Only the wrapped key changes when the passphrase does; otherwise every file is
+/// rewritten. Until that's done, a mix of plain and encrypted files can still be read.
+pub fn set_passphrase(passphrase: Option<&str>) -> Result<()> {
+    match (passphrase, is_unlocked()) {
+        (Some(passphrase), true) => {
+            if let Some(key) = KEY.read().unwrap().as_ref() {
+                write_plain(PASSPHRASE_FILE, &WrappedKey::new(key, passphrase)?)?;
+            }
+        }
+        (Some(passphrase), false) => {
+            let key = encryption::new_key();
+            write_plain(PASSPHRASE_FILE, &WrappedKey::new(&key, passphrase)?)?;
+            *KEY.write().unwrap() = Some(key);
+            rewrite_files()?;
+        }
+        (None, true) => {
+            let contents = data_files()?
+                .into_iter()
+                .map(|name| Ok((read(&name)?, name)))
+                .collect::<Result<Vec<_>>>()?;
+            *KEY.write().unwrap() = None;
+            for (contents, name) in contents {
+                if let Some(contents) = contents {
+                    write(&name, &contents)?;
+                }
+            }
+            let path = data_dir()?.join(PASSPHRASE_FILE);
+            fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+        (None, false) => return Err(anyhow::anyhow!("There's no passphrase to remove")),
+    }
+    Ok(())
+}
+
+/// Delete every file we've saved, and the passphrase file with them
+pub fn delete_files() -> Result<()> {
+    let dir = data_dir()?;
+    let mut names = data_files()?;
+    names.push(PASSPHRASE_FILE.to_string());
+    for name in names {
+        let path = dir.join(name);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write every file again, so they're encrypted or not to match the key
+fn rewrite_files() -> Result<()> {
+    for name in data_files()? {
+        if let Some(contents) = read(&name)? {
+            write(&name, &contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// The names of the files that hold our data, which are the ones that get encrypted
+fn data_files() -> Result<Vec<String>> {
+    let dir = data_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_file()
+            && name.ends_with(".json")
+            && !name.starts_with('.')
+            && name != PASSPHRASE_FILE
+        {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Read a file in the data directory, decrypting it if it's encrypted. A missing file
+/// gives None.
+fn read(name: &str) -> Result<Option<Vec<u8>>> {
+    let path = data_dir()?.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+    if !encryption::is_encrypted(&contents) {
+        return Ok(Some(contents));
+    }
+    match KEY.read().unwrap().as_ref() {
+        Some(key) => encryption::decrypt(key, &contents)
+            .map(Some)
+            .with_context(|| format!("decrypting {}", path.display())),
+        None => Err(anyhow::anyhow!(
+            "{} is encrypted, and no passphrase was given",
+            path.display()
+        )),
+    }
+}
+
+/// Write a file in the data directory, encrypting it if there's a key
+fn write(name: &str, contents: &[u8]) -> Result<()> {
+    match KEY.read().unwrap().as_ref() {
+        Some(key) => write_file(name, &encryption::encrypt(key, contents)?),
+        None => write_file(name, contents),
+    }
+}
+
+fn load_plain<T: DeserializeOwned>(name: &str) -> Result<T> {
+    let path = data_dir()?.join(name);
+    let contents = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice(&contents).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn write_plain<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    write_file(name, &serde_json::to_vec(value)?)
+}
+
+/// Write a file in the data directory, readable only by us. The file is written under
+/// a temporary name and renamed, so it's never left half-written.
+fn write_file(name: &str, contents: &[u8]) -> Result<()> {
+    let dir = data_dir()?;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("creating {}", dir.display()))?;
+
+    let path = dir.join(name);
+    let temp_path = dir.join(format!(".{}.tmp", name));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .with_context(|| format!("creating {}", temp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
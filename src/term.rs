@@ -5,8 +5,12 @@ use std::{
 
 use anyhow::{Context, Result};
 use crossterm::{
+    cursor::MoveTo,
     event::{Event, EventStream},
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{
+        disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+        LeaveAlternateScreen,
+    },
     ExecutableCommand,
 };
 use futures::{stream::Stream, task::Poll};
@@ -43,6 +47,22 @@ impl Term {
             .context("leave alternate screen")?;
         Ok(())
     }
+
+    /// Clear the screen, both inside and outside the alternate screen, along with the
+    /// scrollback, so nothing that was shown is left behind
+    pub fn wipe() -> Result<()> {
+        let mut stdout = stdout();
+        stdout
+            .execute(Clear(ClearType::All))
+            .context("clear screen")?;
+        Term::stop()?;
+        stdout
+            .execute(Clear(ClearType::All))?
+            .execute(Clear(ClearType::Purge))?
+            .execute(MoveTo(0, 0))
+            .context("clear scrollback")?;
+        Ok(())
+    }
 }
 
 impl Stream for Term {
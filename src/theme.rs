@@ -10,22 +10,35 @@ pub struct SystemMessage {
 
 pub struct ChatMessage {
     pub date: Style,
+    pub state: Style,
+    pub read_state: Style,
+    pub progress: Style,
+    pub code: Style,
+    pub quote: Style,
+    pub link: Style,
+    pub selected: Style,
+    pub search_match: Style,
     pub message_id_colors: Vec<Color>,
+    #[allow(dead_code)]
     pub message: Style,
 }
 
 pub struct ChatTabs {
     pub style: Style,
     pub highlight_style: Style,
+    pub disconnected_style: Style,
+    pub verified_style: Style,
 }
 
 pub struct InputPanel {
     pub style: Style,
     pub title: Style,
     pub border: Style,
+    pub error: Style,
 }
 
 pub struct Theme {
+    #[allow(dead_code)]
     pub root: Style,
     pub title_bar: Style,
     pub system_messages_panel: Style,
@@ -57,15 +70,26 @@ lazy_static::lazy_static! {
             style: Style::new().fg(Color::White),
             title: Style::new().fg(Color::Blue),
             border: Style::new().fg(Color::Green),
+            error: Style::new().fg(Color::Red),
         },
         chat_message: ChatMessage {
             date: Style::new().fg(LIGHT_GRAY),
+            state: Style::new().fg(LIGHT_GRAY),
+            read_state: Style::new().fg(Color::LightCyan),
+            progress: Style::new().fg(Color::LightCyan),
+            code: Style::new().fg(Color::LightYellow).bg(CODE_BACKGROUND),
+            quote: Style::new().fg(LIGHT_GRAY).add_modifier(Modifier::ITALIC),
+            link: Style::new().fg(Color::LightBlue).add_modifier(Modifier::UNDERLINED),
+            selected: Style::new().fg(Color::Black).bg(Color::Yellow),
+            search_match: Style::new().fg(Color::Black).bg(Color::LightGreen),
             message_id_colors: vec![Color::LightRed, Color::Red, Color::LightYellow, Color::Yellow, Color::LightGreen, Color::Green, Color::Magenta],
             message: Style::new().fg(Color::White),
         },
         chat_tabs: ChatTabs {
             style: Style::new().fg(Color::White),
             highlight_style: Style::new().fg(Color::Yellow),
+            disconnected_style: Style::new().fg(LIGHT_GRAY),
+            verified_style: Style::new().fg(Color::LightGreen),
         },
         status_bar: Style::new().bg(Color::Blue),
     };
@@ -82,6 +106,7 @@ lazy_static::lazy_static! {
 
 const DARK_BLUE: Color = Color::Rgb(16, 24, 48);
 const ORANGE: Color = Color::Rgb(255, 127, 0);
+const CODE_BACKGROUND: Color = Color::Rgb(32, 40, 72);
 // const LIGHT_BLUE: Color = Color::Rgb(64, 96, 192);
 // const LIGHT_YELLOW: Color = Color::Rgb(192, 192, 96);
 // const LIGHT_GREEN: Color = Color::Rgb(64, 192, 96);
@@ -0,0 +1,730 @@
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::{TcpStream, UnixStream},
+};
+use tor_client_lib::{
+    control_connection::{OnionAddress, OnionServiceListener, OnionServiceMapping, TorSocketAddr},
+    OnionService as TorClientOnionService, TorEd25519SigningKey, TorServiceId,
+};
+use voynich::{
+    config::TorAuthConfig,
+    onion_service::{OnionService, OnionType},
+    util::{get_onion_address, get_onion_service_key, DATA_DIR},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{client_auth::AuthorizedClients, storage};
+
+/// Tor's reply code for a command that worked
+const OK_STATUS: u16 = 250;
+
+/// The keys for the HMACs in SAFECOOKIE authentication, from Tor's control spec
+const SERVER_HASH_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const CONTROLLER_HASH_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// How ADD_ONION takes an ed25519 key, and asks for a new one
+const KEY_PREFIX: &str = "ED25519-V3:";
+const NEW_KEY: &str = "NEW:ED25519-V3";
+
+/// How many random bytes we send in AUTHCHALLENGE
+const CLIENT_NONCE_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A reply from Tor: the status code, and each line with the code taken off. The lines
+/// of data in a "250+" reply come after the line that introduces them.
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    lines: Vec<String>,
+}
+
+/// Replies can hold keys, so they're zeroed when they're dropped
+impl Drop for Reply {
+    fn drop(&mut self) {
+        self.lines.zeroize();
+    }
+}
+
+/// A connection to Tor's control port. tor_client_lib only sends the commands it has
+/// methods for, and we need some it doesn't have, like the ones for client
+/// authorization, so this speaks the control protocol itself.
+pub struct TorControl {
+    reader: Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl TorControl {
+    pub async fn connect(address: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(address)
+            .await
+            .with_context(|| format!("connecting to {}", address))?;
+        Ok(Self::with_stream(stream))
+    }
+
+    /// Connect to Tor's control socket, for a Tor set up with ControlSocket
+    pub async fn connect_unix(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("connecting to {}", path.display()))?;
+        Ok(Self::with_stream(stream))
+    }
+
+    fn with_stream<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(stream: S) -> Self {
+        let (reader, writer) = split(stream);
+        let reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(reader);
+        Self {
+            reader: BufReader::new(reader).lines(),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Authenticate the way the config says. With no password given, it's asked for;
+    /// with no cookie given, it's read from the file Tor names.
+    pub async fn authenticate(
+        &mut self,
+        authentication: Option<TorAuthConfig>,
+        hashed_password: Option<String>,
+        cookie: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let (methods, cookie_file) = self.protocol_info().await?;
+        let method = match authentication {
+            None => "NULL",
+            Some(TorAuthConfig::HashedPassword) => "HASHEDPASSWORD",
+            Some(TorAuthConfig::SafeCookie) => "SAFECOOKIE",
+        };
+        if !methods.iter().any(|allowed| allowed == method) {
+            return Err(anyhow!(
+                "Tor doesn't allow {} authentication; it allows {}",
+                method,
+                methods.join(", ")
+            ));
+        }
+
+        match authentication {
+            None => {
+                self.command("AUTHENTICATE", None).await?;
+            }
+            Some(TorAuthConfig::HashedPassword) => {
+                let password = Zeroizing::new(match hashed_password {
+                    Some(password) => password,
+                    None => rpassword::prompt_password("Type a password: ")?,
+                });
+                self.command("AUTHENTICATE", Some(&quote(&password)))
+                    .await?;
+            }
+            Some(TorAuthConfig::SafeCookie) => {
+                let cookie = Zeroizing::new(match cookie {
+                    Some(cookie) => cookie,
+                    None => {
+                        let cookie_file = cookie_file
+                            .ok_or_else(|| anyhow!("Tor didn't say where its cookie file is"))?;
+                        std::fs::read(&cookie_file)
+                            .with_context(|| format!("reading cookie file {}", cookie_file))?
+                    }
+                });
+                self.safe_cookie_authentication(&cookie).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The authentication methods Tor allows, and the cookie file, if it has one
+    async fn protocol_info(&mut self) -> Result<(Vec<String>, Option<String>)> {
+        let reply = self.command("PROTOCOLINFO", Some("1")).await?;
+        let auth_line = reply
+            .lines
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or_else(|| anyhow!("Tor didn't say how to authenticate"))?;
+        let methods = field(auth_line, "METHODS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|method| method.to_string())
+            .collect();
+        let cookie_file = auth_line
+            .split_once("COOKIEFILE=")
+            .and_then(|(_, rest)| unquote(rest));
+        Ok((methods, cookie_file))
+    }
+
+    async fn safe_cookie_authentication(&mut self, cookie: &[u8]) -> Result<()> {
+        let mut client_nonce = [0u8; CLIENT_NONCE_LENGTH];
+        OsRng.fill_bytes(&mut client_nonce);
+        let reply = self
+            .command(
+                "AUTHCHALLENGE",
+                Some(&format!("SAFECOOKIE {}", hex::encode(client_nonce))),
+            )
+            .await?;
+        let challenge = reply.lines.first().map_or("", |line| line.as_str());
+        let server_hash = field(challenge, "SERVERHASH").and_then(|hash| hex::decode(hash).ok());
+        let server_nonce =
+            field(challenge, "SERVERNONCE").and_then(|nonce| hex::decode(nonce).ok());
+        let (server_hash, server_nonce) = match (server_hash, server_nonce) {
+            (Some(server_hash), Some(server_nonce)) => (server_hash, server_nonce),
+            _ => return Err(anyhow!("Unexpected reply to AUTHCHALLENGE: {}", challenge)),
+        };
+
+        let mut message = Zeroizing::new(Vec::with_capacity(
+            cookie.len() + client_nonce.len() + server_nonce.len(),
+        ));
+        message.extend_from_slice(cookie);
+        message.extend_from_slice(&client_nonce);
+        message.extend_from_slice(&server_nonce);
+
+        // Make sure it's the Tor that wrote the cookie we're talking to, before proving
+        // we have the cookie too
+        let mut mac = HmacSha256::new_from_slice(SERVER_HASH_KEY).unwrap();
+        mac.update(&message);
+        mac.verify_slice(&server_hash)
+            .map_err(|_| anyhow!("Tor's answer to our cookie challenge is wrong"))?;
+
+        let mut mac = HmacSha256::new_from_slice(CONTROLLER_HASH_KEY).unwrap();
+        mac.update(&message);
+        let client_hash = hex::encode(mac.finalize().into_bytes());
+        self.command("AUTHENTICATE", Some(&client_hash)).await?;
+        Ok(())
+    }
+
+    /// Add an onion service, with a new key if `key` is None. A detached service stays
+    /// up when this connection closes; otherwise Tor takes it down then. If
+    /// `client_keys`, x25519 public keys in base32, are given, only those clients can
+    /// connect.
+    pub async fn add_onion(
+        &mut self,
+        key: Option<&TorEd25519SigningKey>,
+        ports: &[OnionServiceMapping],
+        detach: bool,
+        client_keys: &[&str],
+    ) -> Result<TorClientOnionService> {
+        let mut options = String::new();
+        let mut flags = Vec::new();
+        if detach {
+            flags.push("Detach");
+        }
+        if !client_keys.is_empty() {
+            flags.push("V3Auth");
+        }
+        if !flags.is_empty() {
+            options.push_str(&format!(" Flags={}", flags.join(",")));
+        }
+        for port in ports {
+            options.push_str(&format!(
+                " Port={},{}",
+                port.virt_port(),
+                port.listen_address()
+            ));
+        }
+        for client_key in client_keys {
+            options.push_str(&format!(" ClientAuthV3={}", client_key));
+        }
+
+        // Made at its full size, so no copies of the key are left behind as it grows
+        let key_blob = key.map(|key| Zeroizing::new(key.to_blob()));
+        let key_length = key_blob.as_ref().map_or(0, |key_blob| key_blob.len());
+        let mut arguments = Zeroizing::new(String::with_capacity(
+            NEW_KEY.len() + key_length + options.len(),
+        ));
+        match &key_blob {
+            Some(key_blob) => {
+                arguments.push_str(KEY_PREFIX);
+                arguments.push_str(key_blob);
+            }
+            None => arguments.push_str(NEW_KEY),
+        }
+        arguments.push_str(&options);
+
+        let reply = self.command("ADD_ONION", Some(&arguments)).await?;
+        let service_id = reply
+            .lines
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| anyhow!("Tor didn't say what the new onion service is"))?;
+        let service_id = TorServiceId::from_str(service_id)
+            .map_err(|error| anyhow!("Tor gave a bad onion service ID: {}", error))?;
+        let key = match key {
+            Some(key) => key.clone(),
+            None => reply
+                .lines
+                .iter()
+                .find_map(|line| line.strip_prefix("PrivateKey=")?.strip_prefix(KEY_PREFIX))
+                .map(TorEd25519SigningKey::from_blob)
+                .ok_or_else(|| anyhow!("Tor didn't give the key of the new onion service"))?,
+        };
+        Ok(TorClientOnionService::new(service_id, key, ports))
+    }
+
+    pub async fn delete_onion(&mut self, service_id: &str) -> Result<()> {
+        self.command("DEL_ONION", Some(service_id)).await?;
+        Ok(())
+    }
+
+    /// Give Tor the key for connecting to a protected onion service. It lasts until Tor
+    /// restarts.
+    pub async fn add_client_auth(&mut self, service_id: &str, private_key: &str) -> Result<()> {
+        let mut arguments = Zeroizing::new(String::with_capacity(
+            service_id.len() + private_key.len() + 8,
+        ));
+        arguments.push_str(service_id);
+        arguments.push_str(" x25519:");
+        arguments.push_str(private_key);
+        self.command("ONION_CLIENT_AUTH_ADD", Some(&arguments))
+            .await?;
+        Ok(())
+    }
+
+    /// Where Tor's SOCKS proxy is listening on TCP, for a tor that picked its own port
+    pub async fn socks_address(&mut self) -> Result<SocketAddr> {
+        self.get_info("net/listeners/socks")
+            .await?
+            .iter()
+            .flat_map(|line| line.split(' '))
+            .find_map(|listener| SocketAddr::from_str(listener.trim_matches('"')).ok())
+            .ok_or_else(|| anyhow!("Tor isn't listening for SOCKS connections on TCP"))
+    }
+
+    /// The values Tor has for `key`, which may be one or a list
+    pub async fn get_info(&mut self, key: &str) -> Result<Vec<String>> {
+        let reply = self.command("GETINFO", Some(key)).await?;
+        let prefix = format!("{}=", key);
+        let mut lines = reply.lines.iter();
+        let first = lines
+            .next()
+            .and_then(|line| line.strip_prefix(&prefix))
+            .ok_or_else(|| anyhow!("Tor didn't give a value for {}", key))?;
+        // The reply ends with "OK", after the values
+        let rest = lines.take(reply.lines.len().saturating_sub(2));
+        Ok(std::iter::once(first)
+            .chain(rest.map(|line| line.as_str()))
+            .filter(|value| !value.is_empty())
+            .map(|value| value.to_string())
+            .collect())
+    }
+
+    /// Send a command and read the reply, which is an error unless Tor says it worked.
+    /// The arguments are left out of errors, since they can hold keys.
+    async fn command(&mut self, command: &str, arguments: Option<&str>) -> Result<Reply> {
+        let arguments = arguments.unwrap_or_default();
+        let mut line = Zeroizing::new(String::with_capacity(command.len() + arguments.len() + 3));
+        line.push_str(command);
+        if !arguments.is_empty() {
+            line.push(' ');
+            line.push_str(arguments);
+        }
+        line.push_str("\r\n");
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .context("writing to the Tor control connection")?;
+        self.writer.flush().await?;
+
+        let reply = self.read_reply().await?;
+        if reply.status != OK_STATUS {
+            return Err(anyhow!(
+                "Tor refused {}: {} {}",
+                command,
+                reply.status,
+                reply.lines.last().map_or("", |line| line.as_str())
+            ));
+        }
+        Ok(reply)
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let status = line
+                .get(..3)
+                .and_then(|status| status.parse::<u16>().ok())
+                .ok_or_else(|| anyhow!("Unexpected reply from Tor: {}", line))?;
+            let text = line.get(4..).unwrap_or_default().to_string();
+            match line.as_bytes().get(3) {
+                Some(b'-') => lines.push(text),
+                Some(b'+') => {
+                    lines.push(text);
+                    loop {
+                        let data = self.read_line().await?;
+                        if data == "." {
+                            break;
+                        }
+                        // A leading dot is doubled, so data can't be taken for the end
+                        lines.push(data.strip_prefix('.').unwrap_or(&data).to_string());
+                    }
+                }
+                Some(b' ') | None => {
+                    lines.push(text);
+                    return Ok(Reply { status, lines });
+                }
+                Some(_) => return Err(anyhow!("Unexpected reply from Tor: {}", line)),
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        self.reader
+            .next_line()
+            .await
+            .context("reading from the Tor control connection")?
+            .ok_or_else(|| anyhow!("Tor closed the control connection"))
+    }
+}
+
+/// Set up our onion service, as voynich's `create_onion_service` does, but using our
+/// control connection. A persistent service is detached, so it stays up in Tor between
+/// runs, and is added again each time with the authorized clients as they are now.
+pub async fn create_onion_service(
+    control: &mut TorControl,
+    onion_type: OnionType,
+    service_port: Option<u16>,
+    listen_address: Option<TorSocketAddr>,
+    clients: &AuthorizedClients,
+) -> Result<(OnionService, OnionAddress, OnionServiceListener)> {
+    let client_keys = clients
+        .iter()
+        .map(|(_, public_key)| public_key.as_str())
+        .collect::<Vec<_>>();
+    let (onion_service, onion_address) = match onion_type {
+        OnionType::Transient => {
+            let service_port = service_port
+                .ok_or_else(|| anyhow!("No service port given for the transient onion service"))?;
+            let ports = [port_mapping(service_port, listen_address)?];
+            let service = control.add_onion(None, &ports, false, &[]).await?;
+            let onion_address = OnionAddress::new(service.service_id().clone(), service_port);
+            (OnionService::from(service), onion_address)
+        }
+        OnionType::Persistent { name, create: true } => {
+            let service_port = service_port
+                .ok_or_else(|| anyhow!("No service port given for the persistent onion service"))?;
+            let ports = [port_mapping(service_port, listen_address)?];
+            let service = control.add_onion(None, &ports, true, &client_keys).await?;
+            let onion_service = OnionService::new(&name, service);
+            let onion_address = OnionAddress::new(onion_service.service_id().clone(), service_port);
+            save_persistent_service(&name, &onion_service, &onion_address)?;
+            (onion_service, onion_address)
+        }
+        OnionType::Persistent {
+            name,
+            create: false,
+        } => {
+            let (onion_address, signing_key) = load_persistent_service(&name)?;
+            let ports = [port_mapping(onion_address.service_port(), listen_address)?];
+            let onion_service = OnionService::new(
+                &name,
+                TorClientOnionService::new(onion_address.service_id().clone(), signing_key, &ports),
+            );
+            let service_id = onion_service.service_id().to_string();
+            // Tor only takes client keys when a service is added, so if it's still up
+            // from last time, it's taken down and added again
+            if control
+                .get_info("onions/detached")
+                .await?
+                .contains(&service_id)
+            {
+                control.delete_onion(&service_id).await?;
+            }
+            control
+                .add_onion(
+                    Some(onion_service.signing_key()),
+                    onion_service.ports(),
+                    true,
+                    &client_keys,
+                )
+                .await?;
+            (onion_service, onion_address)
+        }
+    };
+    let listen_address = onion_service
+        .listen_addresses_for_port(onion_address.service_port())
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("The onion service has no address to listen on"))?;
+    let listener = OnionServiceListener::bind(listen_address).await?;
+    Ok((onion_service, onion_address, listener))
+}
+
+/// The file persistent onion services are saved in. It's one of our own, so their keys
+/// are encrypted along with everything else when there's a passphrase, and a panic that
+/// deletes our files deletes them too.
+const ONION_SERVICES_FILE: &str = "onion_services.json";
+
+/// A persistent onion service as it's saved, with its key as the blob Tor gives
+#[derive(Deserialize, Serialize)]
+struct SavedOnionService {
+    onion_address: String,
+    signing_key: String,
+}
+
+impl Drop for SavedOnionService {
+    fn drop(&mut self) {
+        self.signing_key.zeroize();
+    }
+}
+
+fn save_persistent_service(
+    name: &str,
+    onion_service: &OnionService,
+    onion_address: &OnionAddress,
+) -> Result<()> {
+    let mut services: HashMap<String, SavedOnionService> = storage::load(ONION_SERVICES_FILE)?;
+    services.insert(
+        name.to_string(),
+        SavedOnionService {
+            onion_address: onion_address.to_string(),
+            signing_key: onion_service.signing_key().to_blob(),
+        },
+    );
+    storage::save(ONION_SERVICES_FILE, &services)
+}
+
+/// The address and key of a persistent onion service. One that voynich saved, in plain
+/// files of its own, is moved into our storage the first time it's used with a
+/// passphrase set, and read from voynich's files until then.
+fn load_persistent_service(name: &str) -> Result<(OnionAddress, TorEd25519SigningKey)> {
+    let services: HashMap<String, SavedOnionService> = storage::load(ONION_SERVICES_FILE)?;
+    let Some(saved) = services.get(name) else {
+        return import_persistent_service(name);
+    };
+    let onion_address =
+        OnionAddress::from_str(&saved.onion_address).map_err(|error| anyhow!("{}", error))?;
+    let key = Zeroizing::new(
+        BASE64
+            .decode(&saved.signing_key)
+            .with_context(|| format!("decoding the key of onion service {}", name))?,
+    );
+    let key: [u8; 64] = key
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("The key of onion service {} isn't an Ed25519 key", name))?;
+    Ok((onion_address, TorEd25519SigningKey::from_bytes(key)))
+}
+
+fn import_persistent_service(name: &str) -> Result<(OnionAddress, TorEd25519SigningKey)> {
+    let onion_address = get_onion_address(name)
+        .with_context(|| format!("There's no persistent onion service called {}", name))?;
+    let signing_key = get_onion_service_key(name)?;
+    let dir = Path::new(DATA_DIR.as_str()).join(name);
+    move_voynich_files(&dir, storage::has_passphrase()?, || {
+        let onion_service = OnionService::new(
+            name,
+            TorClientOnionService::new(
+                onion_address.service_id().clone(),
+                signing_key.clone(),
+                &[],
+            ),
+        );
+        save_persistent_service(name, &onion_service, &onion_address)
+    })?;
+    Ok((onion_address, signing_key))
+}
+
+/// Delete the files voynich saved an onion service in, in `dir`, once `save` has put it
+/// in our storage. That's only done with a passphrase, since our files are as plain as
+/// voynich's without one, and voynich can still use its own.
+fn move_voynich_files(
+    dir: &Path,
+    passphrase: bool,
+    save: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    if !passphrase {
+        return Ok(());
+    }
+    save()?;
+    for file in ["ed25519_secret_key", "onion_address"] {
+        let path = dir.join(file);
+        fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+    }
+    // Only goes if it's empty, in case voynich keeps anything else there
+    let _ = fs::remove_dir(dir);
+    Ok(())
+}
+
+/// Map the onion service port to where we listen, which is the same port on localhost
+/// unless it's given
+fn port_mapping(
+    service_port: u16,
+    listen_address: Option<TorSocketAddr>,
+) -> Result<OnionServiceMapping> {
+    let listen_address = match listen_address {
+        Some(listen_address) => listen_address,
+        None => TorSocketAddr::from_str(&format!("127.0.0.1:{}", service_port))
+            .map_err(|error| anyhow!("{}", error))?,
+    };
+    Ok(OnionServiceMapping::new(service_port, Some(listen_address)))
+}
+
+/// The value of `name=value` among the space-separated fields of a reply line
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    line.split(' ')
+        .find_map(|part| part.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// A string in double quotes, with backslashes before quotes and backslashes in it
+fn quote(string: &str) -> Zeroizing<String> {
+    let mut quoted = Zeroizing::new(String::with_capacity(string.len() * 2 + 2));
+    quoted.push('"');
+    for character in string.chars() {
+        if character == '"' || character == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The string at the start of `text` in double quotes, with its escapes undone
+fn unquote(text: &str) -> Option<String> {
+    let mut characters = text.strip_prefix('"')?.chars();
+    let mut string = String::new();
+    loop {
+        match characters.next()? {
+            '"' => return Some(string),
+            '\\' => string.push(characters.next()?),
+            character => string.push(character),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    /// A control connection to a fake Tor that reads one command and sends `reply`.
+    /// Returns what it read.
+    async fn exchange(reply: &'static str, send: impl AsyncFnOnce(&mut TorControl)) -> String {
+        let (client, mut server) = duplex(4096);
+        let mut control = TorControl::with_stream(client);
+        let fake_tor = tokio::spawn(async move {
+            let mut command = vec![0; 4096];
+            let length = server.read(&mut command).await.unwrap();
+            server.write_all(reply.as_bytes()).await.unwrap();
+            String::from_utf8(command[..length].to_vec()).unwrap()
+        });
+        send(&mut control).await;
+        fake_tor.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_info_reads_data_replies() {
+        let command = exchange(
+            "250+onions/detached=\r\nfirst\r\n..second\r\n.\r\n250 OK\r\n",
+            async |control| {
+                let values = control.get_info("onions/detached").await.unwrap();
+                assert_eq!(values, vec!["first", ".second"]);
+            },
+        )
+        .await;
+        assert_eq!(command, "GETINFO onions/detached\r\n");
+    }
+
+    #[tokio::test]
+    async fn get_info_reads_single_line_replies() {
+        exchange("250-version=0.4.8.12\r\n250 OK\r\n", async |control| {
+            assert_eq!(control.get_info("version").await.unwrap(), vec!["0.4.8.12"]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn socks_address_skips_unix_sockets() {
+        let command = exchange(
+            "250-net/listeners/socks=\"unix:/run/tor/socks\" \"127.0.0.1:41263\"\r\n250 OK\r\n",
+            async |control| {
+                let address = control.socks_address().await.unwrap();
+                assert_eq!(address, SocketAddr::from_str("127.0.0.1:41263").unwrap());
+            },
+        )
+        .await;
+        assert_eq!(command, "GETINFO net/listeners/socks\r\n");
+    }
+
+    #[tokio::test]
+    async fn refusals_leave_out_the_arguments() {
+        exchange("552 Unrecognized private key\r\n", async |control| {
+            let error = control
+                .add_client_auth("service", "secret")
+                .await
+                .unwrap_err()
+                .to_string();
+            assert_eq!(
+                error,
+                "Tor refused ONION_CLIENT_AUTH_ADD: 552 Unrecognized private key"
+            );
+        })
+        .await;
+    }
+
+    /// A directory like the one voynich saves an onion service in
+    fn voynich_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["ed25519_secret_key", "onion_address"] {
+            fs::write(dir.path().join(file), "saved").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn voynich_files_are_moved_with_a_passphrase() {
+        let dir = voynich_dir();
+        let mut saved = false;
+        move_voynich_files(dir.path(), true, || {
+            saved = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(saved);
+        assert!(!dir.path().exists());
+    }
+
+    #[test]
+    fn voynich_files_are_left_without_a_passphrase() {
+        let dir = voynich_dir();
+        let mut saved = false;
+        move_voynich_files(dir.path(), false, || {
+            saved = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(!saved);
+        assert!(dir.path().join("ed25519_secret_key").exists());
+        assert!(dir.path().join("onion_address").exists());
+    }
+
+    #[test]
+    fn voynich_files_are_kept_if_saving_fails() {
+        let dir = voynich_dir();
+        assert!(move_voynich_files(dir.path(), true, || Err(anyhow!("full disk"))).is_err());
+        assert!(dir.path().join("ed25519_secret_key").exists());
+    }
+
+    #[test]
+    fn quoting_round_trips() {
+        let quoted = quote(r#"pass "word" \ here"#);
+        assert_eq!(quoted.as_str(), r#""pass \"word\" \\ here""#);
+        assert_eq!(
+            unquote(&format!("{} rest", quoted.as_str())).unwrap(),
+            r#"pass "word" \ here"#
+        );
+    }
+
+    #[test]
+    fn fields_are_found_by_name() {
+        let line = "METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/run/tor/control.authcookie\"";
+        assert_eq!(field(line, "METHODS"), Some("COOKIE,SAFECOOKIE"));
+        assert_eq!(field(line, "COOKIE"), None);
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, ChildStdout, Command},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::timeout,
+};
+
+/// How long we give tor to exit once it's been killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The file in the data directory tor writes its control port to
+const CONTROL_PORT_FILE: &str = "control_port";
+
+/// How far tor has got connecting to the network, as it reports in its log
+#[derive(Clone, Debug)]
+pub struct BootstrapStatus {
+    pub percent: u8,
+    pub summary: String,
+}
+
+impl Default for BootstrapStatus {
+    fn default() -> Self {
+        Self {
+            percent: 0,
+            summary: "Starting".to_string(),
+        }
+    }
+}
+
+impl BootstrapStatus {
+    pub fn is_done(&self) -> bool {
+        self.percent >= 100
+    }
+
+    /// Pick the status out of a log line like "Jun 01 12:00:00.000 [notice]
+    /// Bootstrapped 45% (requesting_descriptors): Asking for relay descriptors"
+    fn parse(line: &str) -> Option<Self> {
+        let (_, rest) = line.split_once("Bootstrapped ")?;
+        let (percent, rest) = rest.split_once('%')?;
+        let summary = rest.split_once("): ").map_or("", |(_, summary)| summary);
+        Some(Self {
+            percent: percent.parse().ok()?,
+            summary: summary.trim().to_string(),
+        })
+    }
+}
+
+/// A tor of our own, for when there's no system Tor to use. It gets a torrc and data
+/// directory of its own in a temporary directory and uses cookie authentication. It
+/// picks its own localhost ports, so there's no race for them with anything else; the
+/// control port is read from the file tor writes it to, and the SOCKS port is asked
+/// for over the control connection. It's killed when this is dropped, and exits by
+/// itself if we do.
+///
+/// All that's asked of the binary is that it takes `-f <torrc>`, writes the control
+/// port file and logs to stdout the way tor does, so a script that does that and
+/// prints "Bootstrapped 100% (done): Done" can stand in for it.
+#[derive(Debug)]
+pub struct TorProcess {
+    child: Child,
+    status: UnboundedReceiver<Result<BootstrapStatus>>,
+    /// Removed along with everything in it when this is dropped
+    data_dir: TempDir,
+}
+
+impl TorProcess {
+    pub fn spawn(binary: &Path) -> Result<Self> {
+        let data_dir = tempfile::Builder::new()
+            .prefix("voynich-tor-")
+            .tempdir()
+            .context("creating tor data directory")?;
+        let torrc = data_dir.path().join("torrc");
+        fs::write(
+            &torrc,
+            format!(
+                "DataDirectory {dir}\n\
+                 ControlPort auto\n\
+                 ControlPortWriteToFile {dir}/{control_port_file}\n\
+                 SocksPort auto\n\
+                 CookieAuthentication 1\n\
+                 CookieAuthFile {dir}/control_auth_cookie\n\
+                 __OwningControllerProcess {pid}\n\
+                 Log notice stdout\n",
+                dir = data_dir.path().display(),
+                control_port_file = CONTROL_PORT_FILE,
+                pid = std::process::id(),
+            ),
+        )
+        .with_context(|| format!("writing {}", torrc.display()))?;
+
+        let mut child = Command::new(binary)
+            .arg("-f")
+            .arg(&torrc)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("running {}", binary.display()))?;
+        let (sender, status) = unbounded_channel();
+        tokio::spawn(read_output(child.stdout.take().unwrap(), sender));
+
+        Ok(Self {
+            child,
+            status,
+            data_dir,
+        })
+    }
+
+    /// Where tor's control port is, from the file it writes once it's listening, which
+    /// it's done by the time it reports any progress. The file holds a line like
+    /// "PORT=127.0.0.1:36021".
+    pub fn control_address(&self) -> Result<SocketAddr> {
+        let path = self.data_dir.path().join(CONTROL_PORT_FILE);
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix("PORT="))
+            .and_then(|address| SocketAddr::from_str(address.trim()).ok())
+            .ok_or_else(|| anyhow!("tor didn't write its control port to {}", path.display()))
+    }
+
+    /// Wait for tor to report how it's getting on. It's an error if tor exits first.
+    pub async fn next_status(&mut self) -> Result<BootstrapStatus> {
+        self.status
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(anyhow!("tor stopped")))
+    }
+
+    /// Kill tor and wait for it to go
+    pub async fn stop(mut self) -> Result<()> {
+        self.child.start_kill().context("killing tor")?;
+        timeout(STOP_TIMEOUT, self.child.wait())
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for tor to exit"))?
+            .context("waiting for tor to exit")?;
+        Ok(())
+    }
+}
+
+/// Pass on the bootstrap status from tor's log, until it exits. The rest of the log is
+/// read too, so tor never blocks writing it, and the last warning or error is kept to
+/// say why, if it stops.
+async fn read_output(stdout: ChildStdout, sender: UnboundedSender<Result<BootstrapStatus>>) {
+    let mut lines = BufReader::new(stdout).lines();
+    let mut last_problem = None;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(status) = BootstrapStatus::parse(&line) {
+            let _ = sender.send(Ok(status));
+        } else if line.contains("[warn]") || line.contains("[err]") {
+            last_problem = Some(line);
+        }
+    }
+    let _ = sender.send(Err(match last_problem {
+        Some(line) => anyhow!("tor exited: {}", line),
+        None => anyhow!("tor exited"),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    /// Write a shell script to stand in for tor. It's given `-f <torrc>`, and the torrc
+    /// is in the data directory, so `$DIR` is where it should write the control port.
+    fn fake_tor(dir: &TempDir, script: &str) -> PathBuf {
+        let path = dir.path().join("tor");
+        fs::write(
+            &path,
+            format!("#!/bin/sh\nDIR=$(dirname \"$2\")\n{}", script),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_bootstrap_lines() {
+        let status = BootstrapStatus::parse(
+            "Jun 01 12:00:00.000 [notice] Bootstrapped 45% (requesting_descriptors): Asking for relay descriptors",
+        )
+        .unwrap();
+        assert_eq!(status.percent, 45);
+        assert_eq!(status.summary, "Asking for relay descriptors");
+        assert!(!status.is_done());
+        assert!(
+            BootstrapStatus::parse("Jun 01 12:00:00.000 [notice] Opening Socks listener").is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_progress_and_stops() {
+        let dir = TempDir::new().unwrap();
+        let binary = fake_tor(
+            &dir,
+            "echo PORT=127.0.0.1:36021 > \"$DIR/control_port\"\n\
+             echo 'Jun 01 12:00:00.000 [notice] Bootstrapped 5% (conn): Connecting to a relay'\n\
+             echo 'Jun 01 12:00:01.000 [notice] Bootstrapped 100% (done): Done'\n\
+             exec sleep 60\n",
+        );
+        let mut tor = TorProcess::spawn(&binary).unwrap();
+        assert_eq!(tor.next_status().await.unwrap().percent, 5);
+        let status = tor.next_status().await.unwrap();
+        assert!(status.is_done());
+        assert_eq!(status.summary, "Done");
+        assert_eq!(
+            tor.control_address().unwrap(),
+            SocketAddr::from_str("127.0.0.1:36021").unwrap()
+        );
+        let data_dir = tor.data_dir.path().to_path_buf();
+        tor.stop().await.unwrap();
+        assert!(!data_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn says_why_tor_exited() {
+        let dir = TempDir::new().unwrap();
+        let binary = fake_tor(
+            &dir,
+            "echo 'Jun 01 12:00:00.000 [notice] Bootstrapped 0% (starting): Starting'\n\
+             echo 'Jun 01 12:00:00.000 [warn] Could not bind to 127.0.0.1:9050'\n\
+             exit 1\n",
+        );
+        let mut tor = TorProcess::spawn(&binary).unwrap();
+        assert_eq!(tor.next_status().await.unwrap().percent, 0);
+        let error = tor.next_status().await.unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "tor exited: Jun 01 12:00:00.000 [warn] Could not bind to 127.0.0.1:9050"
+        );
+        assert!(tor.next_status().await.is_err());
+        assert!(tor.control_address().is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Context, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream, UnixStream},
+};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where Tor's control port or SOCKS proxy is: a TCP address, or a Unix domain socket
+/// written as `unix:<path>`, the way Tor's ControlSocket and SocksPort options take it
+#[derive(Clone, Debug)]
+pub enum TorAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for TorAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(anyhow!("'{}' is missing the path to the socket", s)),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => SocketAddr::from_str(s).map(Self::Tcp).map_err(|_| {
+                anyhow!(
+                    "'{}' isn't an address; it should be like 127.0.0.1:9051 or unix:/run/tor/control",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// voynich makes the connections to peers itself, and only over TCP, so a SOCKS proxy
+/// on a Unix socket is reached through a bridge: a listener on a random localhost port,
+/// whose connections are each passed through to a new connection to the socket.
+/// Returns the address to give voynich in place of the socket.
+///
+/// The bridge is open to anyone on this machine for as long as we run, the same as a
+/// SocksPort on localhost would be.
+pub async fn bridge(path: &Path) -> Result<SocketAddr> {
+    // Connect now, so a wrong path is reported before anything else happens
+    let first = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("connecting to {}", path.display()))?;
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("listening for the bridge to the Tor proxy socket")?;
+    let address = listener.local_addr()?;
+
+    let path = path.to_path_buf();
+    tokio::spawn(async move {
+        let mut first = Some(first);
+        while let Ok((stream, _)) = listener.accept().await {
+            let socket = match first.take() {
+                Some(socket) => socket,
+                None => match UnixStream::connect(&path).await {
+                    Ok(socket) => socket,
+                    Err(_) => continue,
+                },
+            };
+            tokio::spawn(pass_through(stream, socket));
+        }
+    });
+    Ok(address)
+}
+
+async fn pass_through(mut stream: TcpStream, mut socket: UnixStream) {
+    let _ = copy_bidirectional(&mut stream, &mut socket).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    #[test]
+    fn addresses_parse() {
+        assert!(matches!(
+            TorAddress::from_str("127.0.0.1:9050").unwrap(),
+            TorAddress::Tcp(_)
+        ));
+        assert!(matches!(
+            TorAddress::from_str("unix:/run/tor/socks").unwrap(),
+            TorAddress::Unix(path) if path == Path::new("/run/tor/socks")
+        ));
+        assert!(TorAddress::from_str("unix:").is_err());
+        assert!(TorAddress::from_str("localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn bridge_passes_each_connection_through() {
+        let path = std::env::temp_dir().join(format!("voynich-term-bridge-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixListener::bind(&path).unwrap();
+        let echo = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = socket.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buffer = [0; 16];
+                    let length = stream.read(&mut buffer).await.unwrap();
+                    stream.write_all(&buffer[..length]).await.unwrap();
+                });
+            }
+        });
+
+        let address = bridge(&path).await.unwrap();
+        for message in [b"first", b"again"] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(message).await.unwrap();
+            let mut reply = [0; 5];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, message);
+        }
+
+        echo.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
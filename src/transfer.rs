@@ -0,0 +1,513 @@
+use anyhow::{anyhow, Context, Result};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use tor_client_lib::TorServiceId;
+
+use crate::config::TermConfig;
+
+pub type TransferId = u64;
+
+/// How much of a file goes in each chunk
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Length of a SHA3-256 hash, hex-encoded
+const HASH_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferStatus {
+    /// Waiting for the receiver to accept or reject the file
+    Offered,
+    InProgress,
+    /// All sent; waiting for the receiver to check it
+    Verifying,
+    Complete,
+    Rejected,
+    /// The connection dropped part way through. Sending the same file again picks up
+    /// where it left off.
+    Interrupted,
+    Failed,
+}
+
+#[derive(Debug)]
+pub enum TransferDirection {
+    Outgoing {
+        path: PathBuf,
+    },
+    Incoming {
+        /// Where the file is written as it arrives. It's named after the hash of the
+        /// file and the transfer, so a later offer of the same file can find it and
+        /// resume from it, without two transfers ever writing to the same file.
+        part_path: PathBuf,
+        /// Where the file ended up, once it's all in
+        saved_path: Option<PathBuf>,
+    },
+}
+
+/// A file being sent to, or received from, a peer
+#[derive(Debug)]
+pub struct Transfer {
+    pub peer: TorServiceId,
+    pub direction: TransferDirection,
+    pub name: String,
+    pub size: u64,
+    /// SHA3-256 of the file, hex-encoded
+    pub hash: String,
+    /// How much has been sent or received, including anything from an earlier attempt
+    pub transferred: u64,
+    pub status: TransferStatus,
+}
+
+impl Transfer {
+    pub fn is_outgoing(&self) -> bool {
+        matches!(self.direction, TransferDirection::Outgoing { .. })
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Offered | TransferStatus::InProgress | TransferStatus::Verifying
+        )
+    }
+
+    pub fn saved_path(&self) -> Option<&Path> {
+        match &self.direction {
+            TransferDirection::Incoming { saved_path, .. } => saved_path.as_deref(),
+            TransferDirection::Outgoing { .. } => None,
+        }
+    }
+}
+
+/// Every file transfer in this session, in either direction. This only deals with the
+/// files; the payloads that go with each step are sent by the caller.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    transfers: HashMap<TransferId, Transfer>,
+}
+
+impl Transfers {
+    pub fn get(&self, id: TransferId) -> Option<&Transfer> {
+        self.transfers.get(&id)
+    }
+
+    /// Look up a transfer with a peer. Peers can only refer to their own transfers.
+    fn get_mut(&mut self, peer: &TorServiceId, id: TransferId) -> Result<&mut Transfer> {
+        self.transfers
+            .get_mut(&id)
+            .filter(|transfer| transfer.peer == *peer)
+            .ok_or_else(|| anyhow!("Unknown file transfer {}", id))
+    }
+
+    /// Get ready to send a file, returning the ID to offer it to the peer with
+    pub fn send_file(&mut self, peer: &TorServiceId, path: &Path) -> Result<TransferId> {
+        let metadata = fs::metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        if !metadata.is_file() {
+            return Err(anyhow!("{} isn't a file", path.display()));
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Can't send {}: bad file name", path.display()))?
+            .to_string();
+        let hash = hash_file(path)?;
+
+        let id = rand::random();
+        self.transfers.insert(
+            id,
+            Transfer {
+                peer: peer.clone(),
+                direction: TransferDirection::Outgoing {
+                    path: path.to_path_buf(),
+                },
+                name,
+                size: metadata.len(),
+                hash,
+                transferred: 0,
+                status: TransferStatus::Offered,
+            },
+        );
+        Ok(id)
+    }
+
+    /// A peer has offered us a file. Fails if it's not one we're willing to take.
+    pub fn receive_offer(
+        &mut self,
+        peer: &TorServiceId,
+        id: TransferId,
+        name: &str,
+        size: u64,
+        hash: &str,
+        config: &TermConfig,
+    ) -> Result<()> {
+        if self.transfers.contains_key(&id) {
+            return Err(anyhow!("Duplicate file transfer {}", id));
+        }
+        let name = sanitize_file_name(name)?;
+        if size > config.max_file_size {
+            return Err(anyhow!(
+                "File is {}, over the {} limit",
+                format_size(size),
+                format_size(config.max_file_size)
+            ));
+        }
+        if hash.len() != HASH_LENGTH || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Bad file hash '{}'", hash));
+        }
+        let hash = hash.to_ascii_lowercase();
+        let part_path = config
+            .download_dir()?
+            .join(format!(".{}-{:016x}.part", &hash[..16], id));
+
+        self.transfers.insert(
+            id,
+            Transfer {
+                peer: peer.clone(),
+                direction: TransferDirection::Incoming {
+                    part_path,
+                    saved_path: None,
+                },
+                name,
+                size,
+                hash,
+                transferred: 0,
+                status: TransferStatus::Offered,
+            },
+        );
+        Ok(())
+    }
+
+    /// Accept a file we've been offered. Returns the offset to ask for it from, which
+    /// is past whatever we already have from an earlier attempt.
+    pub fn accept(&mut self, id: TransferId) -> Result<u64> {
+        let in_use = self.part_paths_in_use();
+        let transfer = self
+            .transfers
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("Unknown file transfer {}", id))?;
+        let part_path = match &transfer.direction {
+            TransferDirection::Incoming { part_path, .. } => part_path,
+            TransferDirection::Outgoing { .. } => {
+                return Err(anyhow!("Can't accept a file we're sending"))
+            }
+        };
+        if let Some(dir) = part_path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .with_context(|| format!("creating {}", dir.display()))?;
+        }
+        if !part_path.exists() {
+            if let Some(earlier) = earlier_part_file(part_path, &transfer.hash, &in_use) {
+                fs::rename(&earlier, part_path)
+                    .with_context(|| format!("renaming {}", earlier.display()))?;
+            }
+        }
+        let offset = match fs::metadata(part_path) {
+            Ok(metadata) if metadata.len() <= transfer.size => metadata.len(),
+            Ok(_) => {
+                fs::remove_file(part_path)
+                    .with_context(|| format!("removing {}", part_path.display()))?;
+                0
+            }
+            Err(_) => 0,
+        };
+        transfer.transferred = offset;
+        transfer.status = TransferStatus::InProgress;
+        Ok(offset)
+    }
+
+    /// The part files of incoming transfers that are still going
+    fn part_paths_in_use(&self) -> Vec<PathBuf> {
+        self.transfers
+            .values()
+            .filter(|transfer| transfer.is_active())
+            .filter_map(|transfer| match &transfer.direction {
+                TransferDirection::Incoming { part_path, .. } => Some(part_path.clone()),
+                TransferDirection::Outgoing { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The receiver has accepted a file we offered, and wants it from `offset` on
+    pub fn start_sending(
+        &mut self,
+        peer: &TorServiceId,
+        id: TransferId,
+        offset: u64,
+    ) -> Result<()> {
+        let transfer = self.get_mut(peer, id)?;
+        if !transfer.is_outgoing() || transfer.status != TransferStatus::Offered {
+            return Err(anyhow!(
+                "File transfer {} wasn't waiting to be accepted",
+                id
+            ));
+        }
+        if offset > transfer.size {
+            return Err(anyhow!(
+                "Asked for {} from offset {}, past the end",
+                transfer.name,
+                offset
+            ));
+        }
+        transfer.transferred = offset;
+        transfer.status = TransferStatus::InProgress;
+        Ok(())
+    }
+
+    /// Outgoing transfers that have data waiting to be sent
+    pub fn sending(&self) -> Vec<TransferId> {
+        self.transfers
+            .iter()
+            .filter(|(_, transfer)| {
+                transfer.is_outgoing() && transfer.status == TransferStatus::InProgress
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.transfers
+            .values()
+            .any(|transfer| transfer.is_outgoing() && transfer.status == TransferStatus::InProgress)
+    }
+
+    /// Read the next chunk of a file we're sending, and move the transfer past it.
+    /// Gives the peer, the chunk's offset and its data, or None if there's nothing to send.
+    /// The last chunk may be empty, if the receiver already had the whole file.
+    pub fn next_chunk(&mut self, id: TransferId) -> Result<Option<(TorServiceId, u64, Vec<u8>)>> {
+        let transfer = match self.transfers.get_mut(&id) {
+            Some(transfer) if transfer.status == TransferStatus::InProgress => transfer,
+            _ => return Ok(None),
+        };
+        let path = match &transfer.direction {
+            TransferDirection::Outgoing { path } => path,
+            TransferDirection::Incoming { .. } => return Ok(None),
+        };
+
+        let offset = transfer.transferred;
+        let length = (transfer.size - offset).min(CHUNK_SIZE as u64);
+        let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(length as usize);
+        file.take(length).read_to_end(&mut data)?;
+        if (data.len() as u64) < length {
+            return Err(anyhow!("{} got shorter while sending it", path.display()));
+        }
+
+        transfer.transferred += length;
+        if transfer.transferred == transfer.size {
+            transfer.status = TransferStatus::Verifying;
+        }
+        Ok(Some((transfer.peer.clone(), offset, data)))
+    }
+
+    /// Write a chunk of a file we're receiving. Returns true once the whole file is in.
+    pub fn receive_chunk(
+        &mut self,
+        peer: &TorServiceId,
+        id: TransferId,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<bool> {
+        let transfer = self.get_mut(peer, id)?;
+        let part_path = match &transfer.direction {
+            TransferDirection::Incoming { part_path, .. }
+                if transfer.status == TransferStatus::InProgress =>
+            {
+                part_path
+            }
+            _ => return Err(anyhow!("File transfer {} isn't in progress", id)),
+        };
+        if offset != transfer.transferred {
+            return Err(anyhow!(
+                "Expected data for {} at offset {}, got {}",
+                transfer.name,
+                transfer.transferred,
+                offset
+            ));
+        }
+        if offset + data.len() as u64 > transfer.size {
+            return Err(anyhow!("Got more data than expected for {}", transfer.name));
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(part_path)
+            .with_context(|| format!("opening {}", part_path.display()))?;
+        file.write_all(data)
+            .with_context(|| format!("writing {}", part_path.display()))?;
+        transfer.transferred += data.len() as u64;
+        Ok(transfer.transferred == transfer.size)
+    }
+
+    /// Check a file we've received in full against its hash, and move it into the
+    /// download directory under its own name. Returns where it was saved.
+    pub fn finish(&mut self, id: TransferId) -> Result<PathBuf> {
+        let transfer = self
+            .transfers
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("Unknown file transfer {}", id))?;
+        let (part_path, saved_path) = match &mut transfer.direction {
+            TransferDirection::Incoming {
+                part_path,
+                saved_path,
+            } => (part_path, saved_path),
+            TransferDirection::Outgoing { .. } => {
+                return Err(anyhow!("Can't finish a file we're sending"))
+            }
+        };
+
+        if hash_file(part_path)? != transfer.hash {
+            let _ = fs::remove_file(&part_path);
+            return Err(anyhow!("{} doesn't match its hash", transfer.name));
+        }
+        let dir = part_path
+            .parent()
+            .ok_or_else(|| anyhow!("Bad download path {}", part_path.display()))?;
+        let path = unique_path(dir, &transfer.name);
+        fs::rename(&part_path, &path).with_context(|| format!("saving {}", path.display()))?;
+
+        *saved_path = Some(path.clone());
+        transfer.status = TransferStatus::Complete;
+        Ok(path)
+    }
+
+    pub fn set_status(&mut self, peer: &TorServiceId, id: TransferId, status: TransferStatus) {
+        if let Ok(transfer) = self.get_mut(peer, id) {
+            transfer.status = status;
+        }
+    }
+
+    pub fn fail(&mut self, id: TransferId) {
+        if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.status = TransferStatus::Failed;
+        }
+    }
+
+    /// Forget every transfer, deleting the part files of any that didn't finish, so
+    /// nothing's left to show what was being downloaded
+    pub fn discard(&mut self) {
+        for (_, transfer) in self.transfers.drain() {
+            if let TransferDirection::Incoming {
+                part_path,
+                saved_path: None,
+            } = &transfer.direction
+            {
+                let _ = fs::remove_file(part_path);
+            }
+        }
+    }
+
+    /// The connection to a peer has dropped, so stop any transfers with them. Returns
+    /// how many there were.
+    pub fn interrupt(&mut self, peer: &TorServiceId) -> usize {
+        let mut count = 0;
+        for transfer in self
+            .transfers
+            .values_mut()
+            .filter(|transfer| transfer.peer == *peer && transfer.is_active())
+        {
+            transfer.status = TransferStatus::Interrupted;
+            count += 1;
+        }
+        count
+    }
+}
+
+/// SHA3-256 of a file, hex-encoded
+/// Find the biggest part file left by an earlier transfer of the file with this hash,
+/// that no transfer is still writing to
+fn earlier_part_file(part_path: &Path, hash: &str, in_use: &[PathBuf]) -> Option<PathBuf> {
+    let prefix = format!(".{}-", &hash[..16]);
+    fs::read_dir(part_path.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".part"))
+        })
+        .map(|entry| entry.path())
+        .filter(|path| !in_use.contains(path))
+        .max_by_key(|path| {
+            fs::metadata(path)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0)
+        })
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hasher = Sha3_256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let count = file
+            .read(&mut buffer)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Make a file name from a peer safe to save: no directories, no hidden files and
+/// no control characters
+fn sanitize_file_name(name: &str) -> Result<String> {
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect::<String>();
+    if name.trim().is_empty() {
+        Err(anyhow!("Bad file name"))
+    } else {
+        Ok(name)
+    }
+}
+
+/// A path for `name` in `dir` that isn't taken, adding " (1)", " (2)" and so on if needed
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|count| dir.join(format!("{} ({}){}", stem, count, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// A size in bytes, for people to read
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -1,8 +1,14 @@
 pub mod allow_connection_popup;
+pub mod bootstrap_screen;
 pub mod chat_input;
 pub mod chat_panel;
 pub mod chat_tabs;
+pub mod client_key_popup;
 pub mod command_popup;
+pub mod file_offer_popup;
+pub mod lock_screen;
+pub mod members_panel;
+pub mod room_invite_popup;
 pub mod status_bar;
 pub mod system_messages_panel;
 pub mod title_bar;
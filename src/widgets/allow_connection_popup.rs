@@ -1,4 +1,5 @@
 use crate::{
+    contacts::Contact,
     root::centered_rect,
     theme::{LIGHT_GRAY, THEME},
 };
@@ -6,16 +7,58 @@ use ratatui::{prelude::*, widgets::block::*, widgets::*};
 
 pub struct AllowConnectionPopup {
     onion_address: String,
+    /// What we know about the address, to help decide whether to accept
+    details: Vec<Line<'static>>,
     accept_selected: bool,
 }
 
 impl AllowConnectionPopup {
-    pub fn new(onion_address: &str, accept_selected: bool) -> Self {
+    pub fn new(onion_address: &str, contact: Option<&Contact>, accept_selected: bool) -> Self {
         Self {
             onion_address: onion_address.to_string(),
+            details: Self::details(contact),
             accept_selected,
         }
     }
+
+    fn details(contact: Option<&Contact>) -> Vec<Line<'static>> {
+        let contact = match contact {
+            Some(contact) => contact,
+            None => return vec![Line::raw("  Never seen before")],
+        };
+        let mut details = Vec::new();
+        if contact.blocked {
+            details.push(Line::styled(
+                "  On your block list",
+                Style::default()
+                    .fg(Color::LightRed)
+                    .add_modifier(Modifier::BOLD),
+            ));
+        }
+        if contact.is_saved() {
+            let mut line = vec![Span::raw(format!(
+                "  Saved contact: {}",
+                contact.nickname.as_deref().unwrap_or("(no nickname)")
+            ))];
+            if contact.verified {
+                line.push(Span::styled(" ✓ verified", THEME.chat_tabs.verified_style));
+            } else {
+                line.push(Span::raw(" (not verified)"));
+            }
+            details.push(Line::from(line));
+        } else {
+            details.push(Line::raw("  Not a saved contact"));
+        }
+        details.push(Line::raw(match contact.last_chatted {
+            Some(date) => format!("  Last chatted: {}", date.format("%Y-%m-%d %H:%M")),
+            None => "  Never chatted".to_string(),
+        }));
+        details.push(Line::raw(format!(
+            "  Connected {} time(s), rejected {} time(s)",
+            contact.connections, contact.rejections
+        )));
+        details
+    }
 }
 
 impl Widget for AllowConnectionPopup {
@@ -26,7 +69,13 @@ impl Widget for AllowConnectionPopup {
         let accept_text = "<Accept>";
         let reject_text = "<Reject>";
 
-        let message_width = std::cmp::max(intro.len(), address.len());
+        let message_width = self
+            .details
+            .iter()
+            .map(|line| line.width())
+            .chain([intro.len(), address.len()])
+            .max()
+            .unwrap_or_default();
         let num_spaces = message_width - accept_text.len() - reject_text.len();
         let mut spacer = String::new();
         for _ in 0..num_spaces {
@@ -49,7 +98,7 @@ impl Widget for AllowConnectionPopup {
         };
         buttons.spans = vec![accept_button, Span::raw(spacer), reject_button];
 
-        let message_text = vec![
+        let mut message_text = vec![
             Line::styled(title, Style::default().add_modifier(Modifier::BOLD))
                 .alignment(Alignment::Center),
             Line::raw(""),
@@ -58,8 +107,10 @@ impl Widget for AllowConnectionPopup {
             Line::styled(address, Style::default().add_modifier(Modifier::BOLD))
                 .alignment(Alignment::Center),
             Line::raw(""),
-            buttons.alignment(Alignment::Center),
         ];
+        message_text.extend(self.details);
+        message_text.push(Line::raw(""));
+        message_text.push(buttons.alignment(Alignment::Center));
 
         let area = centered_rect(
             Constraint::Length((message_width + 6) as u16),
@@ -0,0 +1,48 @@
+use crate::{root::centered_rect, theme::THEME, tor_process::BootstrapStatus};
+use ratatui::{prelude::*, widgets::block::*, widgets::*};
+
+/// Shows how far the tor we started has got connecting to the network
+pub struct BootstrapScreen<'a> {
+    status: &'a BootstrapStatus,
+}
+
+impl<'a> BootstrapScreen<'a> {
+    pub fn new(status: &'a BootstrapStatus) -> Self {
+        Self { status }
+    }
+}
+
+impl Widget for BootstrapScreen<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        Clear.render(area, buf);
+        Block::default().style(THEME.chat_panel).render(area, buf);
+
+        let area = centered_rect(Constraint::Percentage(50), Constraint::Length(7), area);
+        let block = Block::default()
+            .title(Line::styled(
+                "Starting Tor",
+                Style::default().add_modifier(Modifier::BOLD),
+            ))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Double)
+            .border_style(THEME.input_panel.border);
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .split(inner);
+        Gauge::default()
+            .gauge_style(THEME.input_panel.border)
+            .percent(self.status.percent.min(100).into())
+            .render(chunks[0], buf);
+        Paragraph::new(self.status.summary.as_str()).render(chunks[2], buf);
+        Paragraph::new("Press Esc to give up").render(chunks[3], buf);
+    }
+}
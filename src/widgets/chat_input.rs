@@ -29,12 +29,13 @@ impl<'a> Widget for ChatInputWidget<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let inner_width = (area.width - 2) as usize;
 
-        let input = split_each(self.input.get_input(), inner_width)
+        let input = self.input.get_input();
+        let lines = split_each(&input, inner_width)
             .into_iter()
             .map(|line| Line::from(vec![Span::raw(line)]))
             .collect::<Vec<_>>();
 
-        Paragraph::new(input)
+        Paragraph::new(lines)
             .block(Block::default().borders(Borders::NONE))
             .style(THEME.chat_input)
             .alignment(Alignment::Left)
@@ -1,52 +1,313 @@
-use crate::{app_context::AppContext, theme::THEME};
+use crate::{
+    app_context::{AppContext, ConnectionStatus},
+    chat::{format_duration, Chat, ChatId, MessageState},
+    markup,
+    protocol::MessageId,
+    search::ChatSearch,
+    theme::THEME,
+    transfer::{format_size, Transfer, TransferStatus},
+};
 use ratatui::{prelude::*, widgets::block::*, widgets::*};
-use tor_client_lib::TorServiceId;
+use std::time::Duration;
 
 pub struct ChatPanel<'a> {
     messages: Vec<Line<'a>>,
-    id: TorServiceId,
+    title: String,
+    status: Option<ConnectionStatus>,
+    ttl: Option<Duration>,
+    /// The peer hasn't yet said they've changed their timer to `ttl`
+    ttl_requested: bool,
+    verified: bool,
+    /// The line the message cursor is on, which has to be scrolled into view
+    selected_line: Option<usize>,
 }
 
 impl<'a> ChatPanel<'a> {
-    pub fn new(id: &TorServiceId, context: &'a AppContext) -> Self {
-        let chat = context.chats.get(id).unwrap();
+    /// `selected` is the message under the message cursor, if it's up. Matches for
+    /// `search` are highlighted if it's a search of this chat.
+    pub fn new(
+        id: &ChatId,
+        context: &'a AppContext,
+        selected: Option<MessageId>,
+        search: Option<&ChatSearch>,
+    ) -> Self {
+        let chat = context.chat(id).unwrap();
+        let (title, status) = match id {
+            ChatId::Peer(id) => match context.contacts.nickname(id) {
+                Some(nickname) => (format!("{} ({})", nickname, id), context.chat_status(id)),
+                None => (id.to_string(), context.chat_status(id)),
+            },
+            ChatId::Room(room_id) => {
+                let room = context.rooms.get(room_id).unwrap();
+                (
+                    format!("{} ({} members)", room.name, room.members.len()),
+                    None,
+                )
+            }
+        };
+        let search = search.filter(|search| search.chat_id == *id);
+        let mut line_count = 0;
+        let mut selected_line = None;
         let messages = chat
             .iter()
-            .map(|message| {
+            .flat_map(|entry| {
+                let message = &entry.message;
                 let date = message.date.format("%H:%M:%S ").to_string();
-                let color = match message.sender.clone() {
-                    sender_id if sender_id == *id => *context.get_color(id).unwrap(),
-                    _ => Color::Blue,
+                let color = match &message.sender {
+                    sender if *sender == context.id => Color::Blue,
+                    sender => context.get_color(sender).copied().unwrap_or(Color::White),
+                };
+                let state = match entry.state {
+                    MessageState::Received => Span::raw("   "),
+                    MessageState::Queued => Span::styled("⧗  ", THEME.chat_message.state),
+                    MessageState::Sent => Span::styled("✓  ", THEME.chat_message.state),
+                    MessageState::Delivered => Span::styled("✓✓ ", THEME.chat_message.state),
+                    MessageState::Read => Span::styled("✓✓ ", THEME.chat_message.read_state),
                 };
-                let ui_message = vec![
-                    Span::styled(date, THEME.chat_message.date),
+                let is_selected = (selected.is_some() && entry.id == selected)
+                    || search.is_some_and(|search| {
+                        search.current.is_some() && search.current == entry.id
+                    });
+                let mut ui_message = vec![
+                    Span::styled(
+                        date,
+                        if is_selected {
+                            THEME.chat_message.selected
+                        } else {
+                            THEME.chat_message.date
+                        },
+                    ),
+                    state,
                     Span::styled(message.sender.as_str(), Style::new().fg(color)),
                     Span::styled(": ", Style::new().fg(color)),
-                    Span::raw(message.message.clone()),
                 ];
-                Line::from(ui_message)
+                let mut text = if entry.deleted {
+                    vec![vec![Span::styled(
+                        "message deleted",
+                        THEME.chat_message.state.add_modifier(Modifier::ITALIC),
+                    )]]
+                } else if context.show_raw_text {
+                    vec![vec![Span::raw(message.message.as_str())]]
+                } else {
+                    markup::render(&message.message)
+                };
+                if let (Some(search), false) = (search, entry.deleted) {
+                    text = text
+                        .into_iter()
+                        .map(|spans| {
+                            search
+                                .search
+                                .highlight(spans, THEME.chat_message.search_match)
+                        })
+                        .collect();
+                }
+                // Markers go at the end of the message's last line
+                if let Some(last) = text.last_mut() {
+                    if entry.edited && !entry.deleted {
+                        last.push(Span::styled(" (edited)", THEME.chat_message.state));
+                    }
+                    if let Some(remaining) = entry.expires_in() {
+                        last.push(Span::styled(
+                            format!(" ⏱ {}", countdown(remaining)),
+                            THEME.chat_message.state,
+                        ));
+                    }
+                }
+                // The first line follows the sender; the rest are indented under it
+                let mut text = text.into_iter();
+                ui_message.extend(text.next().unwrap_or_default());
+                let mut lines = entry
+                    .reply_to
+                    .map(|reply_to| reply_line(chat, reply_to, context))
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                if is_selected {
+                    selected_line = Some(line_count + lines.len());
+                }
+                lines.push(Line::from(ui_message));
+                lines.extend(text.map(|spans| {
+                    let mut line = vec![Span::raw(" ".repeat(INDENT))];
+                    line.extend(spans);
+                    Line::from(line)
+                }));
+                if let Some(transfer) = entry
+                    .transfer
+                    .and_then(|transfer| context.transfers.get(transfer))
+                {
+                    lines.push(transfer_line(transfer));
+                }
+                line_count += lines.len();
+                lines
             })
             .collect::<Vec<_>>();
         Self {
             messages,
-            id: id.clone(),
+            title,
+            status,
+            ttl: chat.ttl(),
+            ttl_requested: chat.ttl_requested(),
+            verified: matches!(id, ChatId::Peer(id) if context.contacts.is_verified(id)),
+            selected_line,
         }
     }
 }
 
+/// How much of a message to show above the replies to it
+const REPLY_PREVIEW_LENGTH: usize = 60;
+
+/// How much of the sender's service ID to show above a reply
+const REPLY_SENDER_LENGTH: usize = 12;
+
+/// A one-line preview of the message a reply is to, shown above the reply
+fn reply_line<'a>(chat: &'a Chat, reply_to: MessageId, context: &AppContext) -> Line<'a> {
+    let style = THEME.chat_message.quote;
+    let mut line = vec![Span::raw(" ".repeat(INDENT))];
+    match chat.get(reply_to) {
+        Some(entry) => {
+            let sender = match &entry.message.sender {
+                sender if *sender == context.id => "you".to_string(),
+                sender => format!("{}…", &sender.as_str()[..REPLY_SENDER_LENGTH]),
+            };
+            line.push(Span::styled(format!("↱ {}: ", sender), style));
+            if entry.deleted {
+                line.push(Span::styled("message deleted", style));
+            } else {
+                let first_line = entry.message.message.lines().next().unwrap_or_default();
+                match first_line.char_indices().nth(REPLY_PREVIEW_LENGTH) {
+                    Some((index, _)) => {
+                        line.push(Span::styled(&first_line[..index], style));
+                        line.push(Span::styled("…", style));
+                    }
+                    None => line.push(Span::styled(first_line, style)),
+                }
+            }
+        }
+        None => line.push(Span::styled("↱ a message that's no longer here", style)),
+    }
+    Line::from(line)
+}
+
+/// Indent for the lines under an entry, to line up past its date and state
+const INDENT: usize = 12;
+
+/// How long a disappearing message has left, like "4:32" or "1:04:32"
+fn countdown(remaining: Duration) -> String {
+    let seconds = remaining.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Width of a file transfer's progress bar, in characters
+const PROGRESS_BAR_WIDTH: u64 = 20;
+
+/// How a file transfer is getting on, shown under its entry in the chat
+fn transfer_line<'a>(transfer: &Transfer) -> Line<'a> {
+    let indent = Span::raw(" ".repeat(INDENT));
+    let status: String = match transfer.status {
+        TransferStatus::Offered if transfer.is_outgoing() => "waiting for them to accept".into(),
+        TransferStatus::Offered => "waiting for you to accept".into(),
+        TransferStatus::InProgress | TransferStatus::Verifying => {
+            let filled = match transfer.size {
+                0 => PROGRESS_BAR_WIDTH,
+                size => transfer.transferred * PROGRESS_BAR_WIDTH / size,
+            };
+            let percent = match transfer.size {
+                0 => 100,
+                size => transfer.transferred * 100 / size,
+            };
+            return Line::from(vec![
+                indent,
+                Span::styled(
+                    format!(
+                        "{}{}",
+                        "█".repeat(filled as usize),
+                        "░".repeat((PROGRESS_BAR_WIDTH - filled) as usize)
+                    ),
+                    THEME.chat_message.progress,
+                ),
+                Span::styled(
+                    format!(
+                        " {}% {} of {}",
+                        percent,
+                        format_size(transfer.transferred),
+                        format_size(transfer.size)
+                    ),
+                    THEME.chat_message.state,
+                ),
+            ]);
+        }
+        TransferStatus::Complete => match transfer.saved_path() {
+            Some(path) => format!("saved to {}", path.display()),
+            None => "sent".into(),
+        },
+        TransferStatus::Rejected => "declined".into(),
+        TransferStatus::Interrupted if transfer.is_outgoing() => {
+            "interrupted; send it again to carry on".into()
+        }
+        TransferStatus::Interrupted => "interrupted".into(),
+        TransferStatus::Failed => "failed".into(),
+    };
+    Line::from(vec![indent, Span::styled(status, THEME.chat_message.state)])
+}
+
 impl<'a> Widget for ChatPanel<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let inner_height = area.height - 2;
-        let scroll = if self.messages.len() as u16 > inner_height {
+        let mut scroll = if self.messages.len() as u16 > inner_height {
             self.messages.len() as u16 - inner_height
         } else {
             0
         };
+        // Keep the message cursor on the screen
+        if let Some(line) = self.selected_line {
+            scroll = scroll.min(line as u16);
+        }
+        let mut title = vec![Span::styled(
+            self.title,
+            Style::default().add_modifier(Modifier::BOLD),
+        )];
+        if self.verified {
+            title.push(Span::styled(" ✓ verified", THEME.chat_tabs.verified_style));
+        }
+        match self.status {
+            Some(ConnectionStatus::Disconnected) => title.push(Span::styled(
+                " (disconnected)",
+                THEME.chat_tabs.disconnected_style,
+            )),
+            Some(ConnectionStatus::Reconnecting) => title.push(Span::styled(
+                " (reconnecting)",
+                THEME.chat_tabs.disconnected_style,
+            )),
+            _ => {}
+        }
+        match (self.ttl, self.ttl_requested) {
+            (Some(ttl), false) => title.push(Span::styled(
+                format!(" ⏱ {}", format_duration(ttl)),
+                THEME.chat_message.state,
+            )),
+            (Some(ttl), true) => title.push(Span::styled(
+                format!(" ⏱ {} (requested)", format_duration(ttl)),
+                THEME.chat_message.state,
+            )),
+            (None, true) => {
+                title.push(Span::styled(" ⏱ off (requested)", THEME.chat_message.state))
+            }
+            (None, false) => {}
+        }
         Paragraph::new(self.messages)
-            .block(Block::default().borders(Borders::ALL).title(Span::styled(
-                self.id.to_string(),
-                Style::default().add_modifier(Modifier::BOLD),
-            )))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(Line::from(title)),
+            )
             .style(THEME.chat_panel)
             .alignment(Alignment::Left)
             .scroll((scroll, 0))
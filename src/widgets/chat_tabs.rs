@@ -1,33 +1,88 @@
-use crate::theme::THEME;
+use crate::{
+    app_context::{AppContext, ConnectionStatus},
+    chat::ChatId,
+    theme::THEME,
+};
 use ratatui::{prelude::*, widgets::block::*, widgets::*};
-use voynich::chat::ChatList;
+use tor_client_lib::TorServiceId;
 
 pub struct ChatTabs<'a> {
     lines: Vec<Line<'a>>,
-    current_index: usize,
+    current_index: Option<usize>,
 }
 
 impl<'a> ChatTabs<'a> {
-    pub fn new(chat_list: &ChatList) -> Self {
-        let lines = chat_list
-            .names()
+    pub fn new(context: &AppContext) -> Self {
+        let mut lines = context
+            .chat_list
+            .ids()
             .iter()
-            .map(|s| Line::from(s.as_str().to_string()))
-            .collect();
+            .map(|id| match id {
+                ChatId::Peer(id) => Self::peer_tab(id, context),
+                ChatId::Room(room_id) => Line::from(format!(
+                    "#{}",
+                    context
+                        .rooms
+                        .get(room_id)
+                        .map_or("", |room| room.name.as_str())
+                )),
+            })
+            .collect::<Vec<_>>();
+
+        // Placeholders for outgoing connections that haven't been made yet
+        lines.extend(
+            context
+                .pending_connects
+                .iter()
+                .filter(|pending| pending.reconnect.is_none())
+                .map(|pending| {
+                    let text = match pending.started {
+                        Some(started) => format!(
+                            "connecting to {}… {}s",
+                            pending.address,
+                            started.elapsed().as_secs()
+                        ),
+                        None => format!("queued: {}", pending.address),
+                    };
+                    Line::styled(text, THEME.chat_tabs.disconnected_style)
+                }),
+        );
+
         Self {
             lines,
-            current_index: chat_list.current_index().unwrap(),
+            current_index: context.chat_list.current_index(),
         }
     }
+
+    fn peer_tab(id: &TorServiceId, context: &AppContext) -> Line<'a> {
+        let mut spans = vec![Span::raw(context.contacts.display_name(id))];
+        if context.contacts.is_verified(id) {
+            spans.push(Span::styled(" ✓", THEME.chat_tabs.verified_style));
+        }
+        match context.chat_status(id) {
+            Some(ConnectionStatus::Disconnected) => spans.push(Span::styled(
+                " (disconnected)",
+                THEME.chat_tabs.disconnected_style,
+            )),
+            Some(ConnectionStatus::Reconnecting) => spans.push(Span::styled(
+                " (reconnecting)",
+                THEME.chat_tabs.disconnected_style,
+            )),
+            _ => {}
+        }
+        Line::from(spans)
+    }
 }
 
 impl<'a> Widget for ChatTabs<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        Tabs::new(self.lines)
+        let mut tabs = Tabs::new(self.lines)
             .block(Block::default().title("Chats").borders(Borders::ALL))
             .style(THEME.chat_tabs.style)
-            .highlight_style(THEME.chat_tabs.highlight_style)
-            .select(self.current_index)
-            .render(area, buf);
+            .highlight_style(THEME.chat_tabs.highlight_style);
+        if let Some(current_index) = self.current_index {
+            tabs = tabs.select(current_index);
+        }
+        tabs.render(area, buf);
     }
 }
@@ -0,0 +1,47 @@
+use crate::{client_auth::ClientKeypair, root::centered_rect, theme::THEME};
+use ratatui::{prelude::*, widgets::block::*, widgets::*};
+
+/// Shows a key pair made with 'auth keygen'. This is the only place the private key
+/// is shown; it's borrowed, so no copy of it is made to draw it.
+pub struct ClientKeyPopup<'a> {
+    keypair: &'a ClientKeypair,
+}
+
+impl<'a> ClientKeyPopup<'a> {
+    pub fn new(keypair: &'a ClientKeypair) -> Self {
+        Self { keypair }
+    }
+}
+
+impl Widget for ClientKeyPopup<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let message_text = vec![
+            Line::styled("Client Authorization Keys", bold).alignment(Alignment::Center),
+            Line::raw(""),
+            Line::raw("Public key, for the owner of the service to add with 'auth add':"),
+            Line::styled(self.keypair.public_key.as_str(), bold).alignment(Alignment::Center),
+            Line::raw(""),
+            Line::raw("Private key, to connect with 'connect <address> --client-auth-key <key>'."),
+            Line::raw("Keep it secret. It isn't saved or logged, so copy it now:"),
+            Line::styled(self.keypair.private_key.as_str(), bold).alignment(Alignment::Center),
+            Line::raw(""),
+            Line::raw("Press Enter or Esc when you're done").alignment(Alignment::Center),
+        ];
+        let message_width = message_text.iter().map(|line| line.width()).max().unwrap();
+
+        let area = centered_rect(
+            Constraint::Length((message_width + 6) as u16),
+            Constraint::Length(message_text.len() as u16 + 2),
+            area,
+        );
+        let message = Paragraph::new(message_text).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Double)
+                .border_style(THEME.input_panel.border),
+        );
+        Clear.render(area, buf); //this clears out the background
+        message.render(area, buf);
+    }
+}
@@ -26,10 +26,15 @@ impl<'a> CommandPopup<'a> {
         // Calculate where the cursor is relative to the popup
         let input_cursor = self.command_input.cursor_location(inner_width);
 
-        // Calculate the rendered popup area
+        // Calculate the rendered popup area, leaving room for any error
+        let error_height = if self.command_input.get_error().is_some() {
+            1
+        } else {
+            0
+        };
         let popup_area = centered_rect(
             Constraint::Percentage(70),
-            Constraint::Length(input_cursor.1 + 3),
+            Constraint::Length(input_cursor.1 + error_height + 3),
             area,
         );
         // Save it
@@ -51,16 +56,24 @@ impl<'a> Widget for CommandPopup<'a> {
         let inner_width = (self.render_area.unwrap().width - 2) as usize;
 
         // Split the string according to width
-        let split_input = split_each(input_string, inner_width)
+        let mut split_input = split_each(&input_string, inner_width)
             .into_iter()
             .map(|line| Line::from(vec![Span::raw(line)]))
             .collect::<Vec<_>>();
 
+        // Show any error from the last command under the input
+        if let Some(error) = self.command_input.get_error() {
+            split_input.push(Line::styled(error.to_string(), THEME.input_panel.error));
+        }
+
         // Generate the input panel
         let input_panel = Paragraph::new(split_input)
             .block(
                 Block::default()
-                    .title(Line::styled("Command Input", THEME.input_panel.title))
+                    .title(Line::styled(
+                        self.command_input.title(),
+                        THEME.input_panel.title,
+                    ))
                     .borders(Borders::ALL)
                     .border_type(BorderType::Double)
                     .border_style(THEME.input_panel.border),
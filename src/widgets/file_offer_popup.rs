@@ -0,0 +1,84 @@
+use crate::{
+    root::centered_rect,
+    theme::{LIGHT_GRAY, THEME},
+    transfer::format_size,
+};
+use ratatui::{prelude::*, widgets::block::*, widgets::*};
+
+pub struct FileOfferPopup {
+    onion_address: String,
+    name: String,
+    size: u64,
+    accept_selected: bool,
+}
+
+impl FileOfferPopup {
+    pub fn new(onion_address: &str, name: &str, size: u64, accept_selected: bool) -> Self {
+        Self {
+            onion_address: onion_address.to_string(),
+            name: name.to_string(),
+            size,
+            accept_selected,
+        }
+    }
+}
+
+impl Widget for FileOfferPopup {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let title = "Incoming File";
+        let intro = "  Incoming file from:";
+        let address = format!("{}.onion", self.onion_address);
+        let file = format!("{} ({})", self.name, format_size(self.size));
+        let accept_text = "<Accept>";
+        let reject_text = "<Reject>";
+
+        let message_width = intro.len().max(address.len()).max(file.chars().count());
+        let num_spaces = message_width - accept_text.len() - reject_text.len();
+        let spacer = " ".repeat(num_spaces);
+
+        let mut buttons = Line::default();
+        let selected_style = Style::default().add_modifier(Modifier::BOLD).bg(LIGHT_GRAY);
+        let unselected_style = Style::default();
+        let (accept_button, reject_button) = if self.accept_selected {
+            (
+                Span::styled(accept_text, selected_style),
+                Span::styled(reject_text, unselected_style),
+            )
+        } else {
+            (
+                Span::styled(accept_text, unselected_style),
+                Span::styled(reject_text, selected_style),
+            )
+        };
+        buttons.spans = vec![accept_button, Span::raw(spacer), reject_button];
+
+        let message_text = vec![
+            Line::styled(title, Style::default().add_modifier(Modifier::BOLD))
+                .alignment(Alignment::Center),
+            Line::raw(""),
+            Line::raw(intro),
+            Line::raw(""),
+            Line::styled(address, Style::default().add_modifier(Modifier::BOLD))
+                .alignment(Alignment::Center),
+            Line::raw(""),
+            Line::styled(file, Style::default().add_modifier(Modifier::BOLD))
+                .alignment(Alignment::Center),
+            Line::raw(""),
+            buttons.alignment(Alignment::Center),
+        ];
+
+        let area = centered_rect(
+            Constraint::Length((message_width + 6) as u16),
+            Constraint::Length(message_text.len() as u16 + 2),
+            area,
+        );
+        let message = Paragraph::new(message_text).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Double)
+                .border_style(THEME.input_panel.border),
+        );
+        Clear.render(area, buf); //this clears out the background
+        message.render(area, buf);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{input::lock_input::LockInput, root::centered_rect, theme::THEME};
+use ratatui::{prelude::*, widgets::block::*, widgets::*};
+
+/// Covers the whole screen while it's locked, so nothing about our chats or our onion
+/// address shows
+pub struct LockScreen<'a> {
+    lock_input: &'a LockInput,
+    /// How many things happened while locked; what they were waits until it's unlocked
+    notifications: usize,
+}
+
+impl<'a> LockScreen<'a> {
+    pub fn new(lock_input: &'a LockInput, notifications: usize) -> Self {
+        Self {
+            lock_input,
+            notifications,
+        }
+    }
+}
+
+impl Widget for LockScreen<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        Clear.render(area, buf);
+        Block::default().style(THEME.chat_panel).render(area, buf);
+
+        let mut text = vec![
+            Line::styled("Locked", Style::default().add_modifier(Modifier::BOLD))
+                .alignment(Alignment::Center),
+            Line::raw(""),
+            Line::raw("Type your passphrase and press Enter to unlock"),
+            Line::raw(""),
+            Line::raw(format!("> {}", self.lock_input.get_masked_input())),
+        ];
+        if let Some(error) = self.lock_input.get_error() {
+            text.push(Line::styled(error.to_string(), THEME.input_panel.error));
+        }
+        if self.notifications > 0 {
+            text.push(Line::raw(""));
+            text.push(Line::raw(format!(
+                "{} notification(s) waiting",
+                self.notifications
+            )));
+        }
+
+        let area = centered_rect(
+            Constraint::Percentage(50),
+            Constraint::Length(text.len() as u16 + 2),
+            area,
+        );
+        Paragraph::new(text)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_type(BorderType::Double)
+                    .border_style(THEME.input_panel.border),
+            )
+            .render(area, buf);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{app_context::AppContext, room::Room, theme::THEME};
+use ratatui::{prelude::*, widgets::block::*, widgets::*};
+
+/// Width of the members panel, including its borders
+pub const MEMBERS_PANEL_WIDTH: u16 = 24;
+
+/// How much of each member's service ID to show
+const ID_WIDTH: usize = 12;
+
+/// The members of a room, marked with whether we're connected to them
+pub struct MembersPanel<'a> {
+    lines: Vec<Line<'a>>,
+}
+
+impl<'a> MembersPanel<'a> {
+    pub fn new(room: &Room, context: &AppContext) -> Self {
+        let lines = room
+            .members
+            .iter()
+            .map(|member| {
+                let id = format!("{}…", &member.id.as_str()[..ID_WIDTH]);
+                let color = context
+                    .get_color(&member.id)
+                    .copied()
+                    .unwrap_or(Color::White);
+                if member.id == context.id {
+                    Line::from(vec![
+                        Span::raw("  "),
+                        Span::styled(id, Style::new().fg(Color::Blue)),
+                        Span::styled(" (you)", THEME.chat_tabs.disconnected_style),
+                    ])
+                } else if context.is_connected(&member.id) {
+                    Line::from(vec![
+                        Span::raw("● "),
+                        Span::styled(id, Style::new().fg(color)),
+                    ])
+                } else {
+                    Line::from(vec![
+                        Span::styled("○ ", THEME.chat_tabs.disconnected_style),
+                        Span::styled(id, THEME.chat_tabs.disconnected_style),
+                    ])
+                }
+            })
+            .collect();
+        Self { lines }
+    }
+}
+
+impl<'a> Widget for MembersPanel<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        Paragraph::new(self.lines)
+            .block(Block::default().title("Members").borders(Borders::ALL))
+            .style(THEME.chat_panel)
+            .render(area, buf);
+    }
+}
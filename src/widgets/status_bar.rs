@@ -1,17 +1,55 @@
-use crate::theme::THEME;
+use crate::{
+    app_context::AppContext, input::chat_input::InputMode, search::ChatSearch, theme::THEME,
+};
 use ratatui::{prelude::*, widgets::block::*, widgets::*};
 
-pub struct StatusBar {}
+pub struct StatusBar {
+    text: String,
+}
 
 impl StatusBar {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(context: &AppContext, mode: InputMode, search: Option<&ChatSearch>) -> Self {
+        let text = match mode {
+            InputMode::Editing(_) => "Editing message; Enter to save, Esc to cancel".to_string(),
+            InputMode::Selecting(_) => {
+                "Pick a message with up/down; r to reply to it, e to edit it, d to delete it, Esc to stop"
+                    .to_string()
+            }
+            InputMode::Replying(_) => {
+                "Replying to the marked message; Esc to cancel the reply".to_string()
+            }
+            InputMode::Deleting(_) => {
+                "Delete the marked message: d for everyone, l from your copy only, Esc to cancel"
+                    .to_string()
+            }
+            InputMode::Searching => {
+                match search.and_then(|search| Some((search, context.chat(&search.chat_id)?))) {
+                    Some((search, chat)) => {
+                        let (position, count) = search.position(chat);
+                        format!(
+                            "Match {} of {} for '{}'; n for older, N for newer, Esc to stop",
+                            position.unwrap_or(0),
+                            count,
+                            search.search.query
+                        )
+                    }
+                    None => "Searching".to_string(),
+                }
+            }
+            InputMode::Message => match context.chat_list.current_peer() {
+                Some(id) if context.config.typing_indicators && context.is_typing(id) => {
+                    format!("{} is typing…", id)
+                }
+                _ => "Input".to_string(),
+            },
+        };
+        Self { text }
     }
 }
 
 impl Widget for StatusBar {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        Paragraph::new("Input")
+        Paragraph::new(self.text)
             .block(Block::default().borders(Borders::NONE))
             .style(THEME.status_bar)
             .alignment(Alignment::Left)
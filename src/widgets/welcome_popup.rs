@@ -37,8 +37,17 @@ impl Widget for WelcomePopup {
             Line::raw("To connect to someone, press ctrl-k to bring up a command window, and type 'connect <onion-address>'"),
             Line::raw("Once connected, type your messages in the input box at the bottom"),
             Line::raw("To quit a chat, type '/quit' in the chat input box"),
+            Line::raw("To reconnect a dropped chat, type '/reconnect' in the chat input box"),
+            Line::raw("To send a file, type '/send <path>' in the chat input box"),
+            Line::raw("To edit your last message, press up-arrow; '/delete' deletes it"),
+            Line::raw("To reply to a message, press ctrl-up-arrow, pick the message and press 'r'; 'e' edits it, 'd' deletes it"),
+            Line::raw("To search a chat, press ctrl-f or type '/search <text>'; n and N move between matches"),
+            Line::raw("To check who you're chatting with, type '/verify' and compare the safety number with them"),
+            Line::raw("To make messages disappear after a while, type '/ttl 5m' in the chat; '/ttl off' stops it"),
+            Line::raw("To add someone to a room, type '/invite <onion-address>' in the room; '/leave' leaves it"),
             Line::raw("To run a command (listed below) type ctrl-k and type the command"),
             Line::raw("Type ctrl-c anywhere, or 'quit' in the command window, to exit"),
+            Line::raw("Type ctrl-alt-p anywhere, or 'panic' in the command window, to wipe everything and exit"),
             Line::raw("Type ctrl-h to show/hide this window again"),
             Line::raw("Type ctrl-k to show/hide the command window"),
             Line::raw(""),
@@ -50,6 +59,16 @@ impl Widget for WelcomePopup {
             ),
             Line::raw(""),
             Line::raw("connect <address>  - to connect to another chat user"),
+            Line::raw("chat <address>     - to write to a chat user you're not connected to"),
+            Line::raw("cancel [<address>] - to cancel connecting to another chat user"),
+            Line::raw("room <name>        - to start a room for chatting with several users"),
+            Line::raw("export <path>      - to write the system messages to a file (unencrypted)"),
+            Line::raw("block [<address>]  - to block connections from a chat user, or list blocked users"),
+            Line::raw("unblock <address>  - to unblock a chat user"),
+            Line::raw("auth add|remove|list|keygen - to manage who can connect to your persistent onion service"),
+            Line::raw("passphrase change|remove - to encrypt your saved files with a passphrase, or stop"),
+            Line::raw("lock               - to hide everything until your passphrase is typed"),
+            Line::raw("panic              - to wipe everything and exit at once"),
             Line::raw("quit               - to exit the application"),
             Line::raw(""),
         ];